@@ -0,0 +1,128 @@
+use rand::Rng;
+use crate::models::message::escape_tag_value;
+use crate::utils::to_hex;
+
+/// A `BATCH +id type` ... `BATCH -id` block.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub server: String,
+    pub id: String,
+    pub batch_type: String,
+    pub params: Vec<String>,
+}
+
+impl Batch {
+    pub fn new(server: &str, batch_type: &str) -> Self {
+        let bytes: [u8; 6] = rand::thread_rng().gen();
+        Batch {
+            server: server.to_string(),
+            id: to_hex(&bytes),
+            batch_type: batch_type.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with_param(mut self, param: &str) -> Self {
+        self.params.push(param.to_string());
+        self
+    }
+
+    pub fn start(&self) -> String {
+        let mut line = format!(":{} BATCH +{} {}", self.server, self.id, self.batch_type);
+        for param in &self.params {
+            line.push(' ');
+            line.push_str(param);
+        }
+        line
+    }
+
+    pub fn end(&self) -> String {
+        format!(":{} BATCH -{}", self.server, self.id)
+    }
+
+    /// Tags a line as belonging to this batch.
+    pub fn wrap(&self, line: &str) -> String {
+        add_tag(line, "batch", &self.id)
+    }
+
+    /// Returns the start line, every line tagged with the batch id, and the end line.
+    pub fn frame(&self, lines: Vec<String>) -> Vec<String> {
+        let mut framed = Vec::with_capacity(lines.len() + 2);
+        framed.push(self.start());
+        framed.extend(lines.iter().map(|line| self.wrap(line)));
+        framed.push(self.end());
+        framed
+    }
+}
+
+/// Adds a tag to a protocol line, merging it into an existing `@tags` section.
+pub fn add_tag(line: &str, key: &str, value: &str) -> String {
+    let tag = if value.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}={}", key, escape_tag_value(value))
+    };
+    match line.strip_prefix('@') {
+        Some(rest) => format!("@{};{}", tag, rest),
+        None => format!("@{} {}", tag, line),
+    }
+}
+
+/// What the requesting client negotiated that affects how its replies are framed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramingCaps {
+    pub batch: bool,
+    pub labeled_response: bool,
+}
+
+/// Everything one command produced: the replies to its sender, which are framed and
+/// queued as a unit so a batch is never cut short, and lines for other clients.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandReplies {
+    pub own: Vec<String>,
+    pub others: Vec<(usize, String)>,
+}
+
+impl CommandReplies {
+    /// Splits a handler's responses into the replies to `client_id` and the rest.
+    pub fn new(client_id: usize, responses: Vec<(usize, String)>) -> Self {
+        let (own, others): (Vec<_>, Vec<_>) = responses.into_iter().partition(|(id, _)| *id == client_id);
+        CommandReplies {
+            own: own.into_iter().map(|(_, line)| line).collect(),
+            others,
+        }
+    }
+}
+
+/// Frames the replies one command produced for its sender.
+///
+/// With `labeled-response`, the label is echoed on a single reply, on a `labeled-response`
+/// batch wrapping several replies, or on an `ACK` when there are none. Otherwise clients with
+/// `batch` get multi-line replies wrapped in a batch of `batch_type`. Lines addressed to other
+/// clients are passed through unchanged.
+pub fn frame_replies(
+    server: &str,
+    replies: CommandReplies,
+    label: Option<&str>,
+    batch_type: Option<&str>,
+    caps: FramingCaps,
+) -> CommandReplies {
+    let CommandReplies { own, others } = replies;
+    let own = match label {
+        Some(label) if caps.labeled_response => match own.len() {
+            0 => vec![add_tag(&format!(":{} ACK", server), "label", label)],
+            1 => vec![add_tag(&own[0], "label", label)],
+            _ if caps.batch => {
+                let mut lines = Batch::new(server, "labeled-response").frame(own);
+                lines[0] = add_tag(&lines[0], "label", label);
+                lines
+            }
+            _ => own,
+        },
+        _ => match batch_type {
+            Some(batch_type) if caps.batch && own.len() > 1 => Batch::new(server, batch_type).frame(own),
+            _ => own,
+        },
+    };
+    CommandReplies { own, others }
+}
@@ -1,15 +1,76 @@
 
-use crate::commands::parser::Command;
-use crate::models::user::User;
+use crate::commands::parser::{Command, Tags};
+use crate::config::{ClassConfig, Config, ConfigHandle, DEFAULT_CLASS, VERSION};
+use crate::commands::batch::{frame_replies, Batch, CommandReplies, FramingCaps};
+use crate::models::user::{User, UserStatus, SNOMASK_LETTERS};
 use crate::models::channel::Channel;
-use std::collections::HashMap;
+use crate::models::message::{format_server_time, Message, MessageKind, Recipient};
+use crate::models::history::{
+    channel_buffer, private_buffer, private_participants, select_history, FileHistoryStore, HistoryEntry,
+    HistoryQuery, HistoryRef, HistoryStore, Retention,
+};
+use crate::models::stats::ServerStats;
+use crate::models::ban::{Ban, BanKind, BanList};
+use crate::server::shutdown::{Shutdown, ShutdownKind};
+use crate::models::account::{normalize_certfp, parse_plain_payload, AccountStore, FileAccountStore, SaslSession};
+use crate::utils::{casefold, check_password};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
 
 pub struct SharedState {
     pub users: Arc<Mutex<HashMap<usize, User>>>,
     pub channels: Arc<Mutex<HashMap<String, Channel>>>,
+    pub accounts: Arc<dyn AccountStore>,
+    pub history: Arc<dyn HistoryStore>,
+    pub config: Arc<ConfigHandle>,
+    pub stats: Arc<ServerStats>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub shutdown: Arc<Shutdown>,
 }
 
+impl Default for SharedState {
+    fn default() -> Self {
+        SharedState {
+            users: Arc::new(Mutex::new(HashMap::new())),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            accounts: Arc::new(FileAccountStore::new()),
+            history: Arc::new(FileHistoryStore::new(Retention::default())),
+            config: Arc::new(ConfigHandle::new(Config::default())),
+            stats: Arc::new(ServerStats::new()),
+            bans: Arc::new(Mutex::new(BanList::new())),
+            shutdown: Arc::new(Shutdown::new()),
+        }
+    }
+}
+
+impl SharedState {
+    pub fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
+}
+
+/// Capabilities offered in `CAP LS`.
+const SUPPORTED_CAPS: &[&str] = &[
+    "account-tag",
+    "batch",
+    "draft/chathistory",
+    "echo-message",
+    "extended-join",
+    "labeled-response",
+    "message-tags",
+    "multi-prefix",
+    "sasl",
+    "server-time",
+    "userhost-in-names",
+];
+const SASL_MECHANISMS: &[&str] = &["PLAIN", "EXTERNAL"];
+/// Most messages returned by a single CHATHISTORY request.
+const CHATHISTORY_MAX: usize = 100;
+/// Most ISUPPORT tokens sent in a single 005 reply.
+const ISUPPORT_PER_LINE: usize = 13;
+
 pub async fn handle_command(command: Command, client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
     match command {
         Command::Nick(nickname) => handle_nick(client_id, nickname, shared_state),
@@ -17,17 +78,18 @@ pub async fn handle_command(command: Command, client_id: usize, shared_state: &S
         Command::Join(channel) => handle_join(client_id, channel, shared_state),
         Command::Part(channel) => handle_part(client_id, channel, shared_state),
         Command::PrivMsg(target, message) => handle_privmsg(client_id, target, message, shared_state),
+        Command::Notice(target, message) => handle_notice(client_id, target, message, shared_state),
         Command::Quit(message) => handle_quit(client_id, message, shared_state),
         Command::Ping(server) => handle_ping(client_id, server),
         Command::Pong(_) => handle_pong(client_id, shared_state),
-        Command::Mode(_, _, _) => Ok(vec![(client_id, "MODE command not implemented yet".to_string())]),
+        Command::Mode(target, modes, param) => handle_mode(client_id, target, modes, param, shared_state),
         Command::Topic(channel, topic) => handle_topic(client_id, channel, topic, shared_state),
         Command::Names(channel) => handle_names(client_id, channel, shared_state),
         Command::List(channel) => handle_list(client_id, channel, shared_state),
         Command::Invite(_, _) => Ok(vec![(client_id, "INVITE command not implemented yet".to_string())]),
         Command::Kick(_, _, _) => Ok(vec![(client_id, "KICK command not implemented yet".to_string())]),
-        Command::Who(_) => Ok(vec![(client_id, "WHO command not implemented yet".to_string())]),
-        Command::WhoisUser(_) => Ok(vec![(client_id, "WHOIS command not implemented yet".to_string())]),
+        Command::Who(mask) => handle_who(client_id, mask, shared_state),
+        Command::WhoisUser(target) => handle_whois(client_id, target, shared_state),
         Command::WhoisServer(_) => Ok(vec![(client_id, "WHOIS command not implemented yet".to_string())]),
         Command::WhoisOperator(_) => Ok(vec![(client_id, "WHOIS command not implemented yet".to_string())]),
         Command::WhoisIdle(_) => Ok(vec![(client_id, "WHOIS command not implemented yet".to_string())]),
@@ -35,78 +97,372 @@ pub async fn handle_command(command: Command, client_id: usize, shared_state: &S
         Command::WhoisAuth(_) => Ok(vec![(client_id, "WHOIS command not implemented yet".to_string())]),
         Command::Whowas(_, _, _) => Ok(vec![(client_id, "WHOWAS command not implemented yet".to_string())]),
         Command::Cap(subcommand, param) => handle_cap(client_id, subcommand, param, shared_state),
+        Command::Authenticate(data) => handle_authenticate(client_id, data, shared_state),
+        Command::ChatHistory(subcommand, params) => handle_chathistory(client_id, subcommand, params, shared_state),
+        Command::Rehash => handle_rehash(client_id, shared_state),
+        Command::Motd => handle_motd(client_id, shared_state),
+        Command::Lusers => handle_lusers(client_id, shared_state),
+        Command::Version => handle_version(client_id, shared_state),
+        Command::Time => handle_time(client_id, shared_state),
+        Command::Admin => handle_admin(client_id, shared_state),
+        Command::Info => handle_info(client_id, shared_state),
+        Command::Stats(query) => handle_stats(client_id, query, shared_state),
+        Command::Help(topic) => handle_help(client_id, topic, shared_state),
+        Command::Oper(name, password) => handle_oper(client_id, name, password, shared_state),
+        Command::Kill(target, reason) => handle_kill(client_id, target, reason, shared_state),
+        Command::Pass(password) => handle_pass(client_id, password, shared_state),
+        Command::Wallops(text) => handle_wallops(client_id, text, shared_state),
+        Command::Die => handle_die(client_id, ShutdownKind::Stop, shared_state),
+        Command::Restart => handle_die(client_id, ShutdownKind::Restart, shared_state),
+        Command::AddBan(kind, minutes, mask, reason) => handle_add_ban(client_id, kind, minutes, mask, reason, shared_state).await,
+        Command::RemoveBan(kind, mask) => handle_remove_ban(client_id, kind, mask, shared_state).await,
+    }
+}
+
+/// Handles a command sent with message tags, grouping the replies for the sender
+/// according to the `batch` and `labeled-response` capabilities.
+pub async fn handle_tagged_command(command: Command, tags: &Tags, client_id: usize, shared_state: &SharedState) -> Result<CommandReplies, String> {
+    let batch_type = reply_batch_type(&command);
+    let label = tags.get("label").filter(|label| !label.is_empty()).cloned();
+    let result = handle_command(command, client_id, shared_state).await;
+
+    let caps = shared_state.users.lock().unwrap().get(&client_id)
+        .map(|user| FramingCaps {
+            batch: user.has_cap("batch"),
+            labeled_response: user.has_cap("labeled-response"),
+        })
+        .unwrap_or_default();
+
+    let responses = match result {
+        Ok(responses) => responses,
+        Err(e) if label.is_some() && caps.labeled_response => {
+            log::error!("Error handling command for client {}: {}", client_id, e);
+            vec![(client_id, format!("ERROR :{}", e))]
+        }
+        Err(e) => return Err(e),
+    };
+    let replies = CommandReplies::new(client_id, responses);
+    Ok(frame_replies(&shared_state.config().server.name, replies, label.as_deref(), batch_type, caps))
+}
+
+/// Batch type used to group multi-line replies for clients with the `batch` capability.
+fn reply_batch_type(command: &Command) -> Option<&'static str> {
+    match command {
+        Command::Names(_) => Some("rustirc2/names"),
+        Command::List(_) => Some("rustirc2/list"),
+        Command::Who(_) => Some("rustirc2/who"),
+        Command::Stats(_) => Some("rustirc2/stats"),
+        _ => None,
     }
 }
 
 fn handle_nick(client_id: usize, nickname: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let channel_count = shared_state.channels.lock().unwrap().len();
     let mut users = shared_state.users.lock().unwrap();
     let user = users.entry(client_id).or_insert_with(|| User::new(client_id, "0.0.0.0".parse().unwrap()));
     let old_nick = user.nickname.clone().unwrap_or_else(|| "<unknown>".to_string());
-    user.set_nickname(nickname.clone())?;
-    Ok(vec![(client_id, format!(":{} NICK :{}", old_nick, nickname))])
+    if find_nick(&users, &nickname).map(|other| other.id != client_id).unwrap_or(false) {
+        let nick = users[&client_id].reply_nick();
+        return Ok(vec![(client_id, format!(":{} 433 {} {} :Nickname is already in use", config.server.name, nick, nickname))]);
+    }
+    let user = users.get_mut(&client_id).expect("user was just inserted");
+    let qline = shared_state.bans.lock().unwrap().find_nick(&nickname);
+    if let Some(ban) = qline {
+        return Ok(vec![(client_id, format!(":{} 432 {} {} :Nickname is reserved: {}", config.server.name, user.reply_nick(), nickname, ban.reason))]);
+    }
+    user.set_nickname_with_limit(nickname.clone(), config.limits.nick_length)?;
+    let mut responses = vec![(client_id, format!(":{} NICK :{}", old_nick, nickname))];
+    responses.extend(complete_registration(client_id, &mut users, channel_count, shared_state));
+    Ok(responses)
 }
 
 fn handle_user(client_id: usize, username: String, realname: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let channel_count = shared_state.channels.lock().unwrap().len();
     let mut users = shared_state.users.lock().unwrap();
     if let Some(user) = users.get_mut(&client_id) {
+        if user.registered {
+            return Ok(vec![(client_id, format!(":{} 462 {} :You may not reregister", config.server.name, user.reply_nick()))]);
+        }
         user.username = Some(username);
         user.realname = Some(realname);
-        let nickname = user.nickname.clone().unwrap_or_else(|| format!("User{}", client_id));
-        Ok(vec![
-            (client_id, format!(":{} 001 {} :Welcome to the IRC server!", "server", nickname)),
-            (client_id, format!(":{} 002 {} :Your host is rustirc2, running version 1.0", "server", nickname)),
-            (client_id, format!(":{} 003 {} :This server was created {}", "server", nickname, chrono::Utc::now().format("%Y-%m-%d"))),
-            (client_id, format!(":{} 004 {} rustirc2 1.0 o o", "server", nickname)),
-            (client_id, format!(":{} 005 {} CHANTYPES=# CHARSET=utf-8 :are supported by this server", "server", nickname)),
-            (client_id, format!(":{} 251 {} :There are {} users and 0 services on 1 server", "server", nickname, users.len())),
-        ])
+        Ok(complete_registration(client_id, &mut users, channel_count, shared_state))
     } else {
         Err("User not found".to_string())
     }
 }
 
+fn handle_pass(client_id: usize, password: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let mut users = shared_state.users.lock().unwrap();
+    let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
+    if user.registered {
+        return Ok(vec![(client_id, format!(":{} 462 {} :You may not reregister", config.server.name, user.reply_nick()))]);
+    }
+    // Checked once registration completes, when the auth block is known
+    user.password = Some(password);
+    Ok(Vec::new())
+}
+
+/// Sends the welcome burst once NICK and USER have both been received and capability
+/// negotiation (if any) has ended. `channel_count` is taken by the caller, which must
+/// not lock the channels while holding the users.
+fn complete_registration(client_id: usize, users: &mut HashMap<usize, User>, channel_count: usize, shared_state: &SharedState) -> Vec<(usize, String)> {
+    let config = shared_state.config();
+    let user = match users.get_mut(&client_id) {
+        Some(user) => user,
+        None => return Vec::new(),
+    };
+    if user.registered || user.cap_negotiating || user.nickname.is_none() || user.username.is_none() {
+        return Vec::new();
+    }
+    let auth = config.auth_block(user.username.as_deref().unwrap_or("*"), user.host, user.hostname.as_deref());
+    // The auth block's password takes the place of the listener's server password
+    let required = auth.and_then(|auth| auth.password())
+        .or_else(|| user.listener.as_deref().and_then(|address| config.listener(address)).and_then(|listener| listener.password()));
+    let password = user.password.take();
+    if let Some(password_hash) = required {
+        if !password.map(|password| check_password(&password, password_hash)).unwrap_or(false) {
+            let user = users.remove(&client_id).expect("registering user is present");
+            log::info!("Refusing client {}: bad password", user.reply_nick());
+            let mut responses = vec![
+                (client_id, format!(":{} 464 {} :Password incorrect", config.server.name, user.reply_nick())),
+                (client_id, format!("ERROR :Closing Link: {} (Bad Password)", user.host)),
+            ];
+            responses.extend(server_notices(&config, users, 'c', &format!("Rejecting user {} ({}): bad password", user.reply_nick(), user.user_host())));
+            return responses;
+        }
+    }
+    if let Some(auth) = auth {
+        user.class = auth.class.clone();
+        user.vhost = auth.spoof.clone();
+        user.flood_exempt = auth.exempts("flood");
+        user.kline_exempt = auth.exempts("kline");
+    }
+    let kline = match user.kline_exempt {
+        true => None,
+        false => shared_state.bans.lock().unwrap().find_user(user.username.as_deref().unwrap_or("*"), user.host, user.hostname.as_deref()),
+    };
+    if let Some(ban) = kline {
+        let (nickname, user_host, host) = (user.reply_nick(), user.user_host(), user.host);
+        log::info!("Refusing K-lined client {} ({})", nickname, ban.mask);
+        // Not registered yet, so the user is in no channels
+        users.remove(&client_id);
+        let mut responses = vec![
+            (client_id, format!(":{} 465 {} :You are banned from this server- {}", config.server.name, nickname, ban.reason)),
+            (client_id, format!("ERROR :Closing Link: {} ({}: {})", host, ban.kind.action(), ban.reason)),
+        ];
+        responses.extend(server_notices(&config, users, 'x', &format!("Rejecting K-lined user {} ({}) [{}]", nickname, user_host, ban.mask)));
+        return responses;
+    }
+
+    let class = config.user_class(&user.class, None);
+    let class_name = user.class.clone();
+    if users.values().filter(|other| other.registered && other.class == class_name).count() >= class.max_clients {
+        let user = users.remove(&client_id).expect("registering user is present");
+        log::info!("Refusing client {}: class {} is full", user.reply_nick(), class_name);
+        let mut responses = vec![(client_id, format!("ERROR :Closing Link: {} (No more connections allowed in your connection class)", user.host))];
+        let notice = format!("Rejecting user {} ({}): class {} is full", user.reply_nick(), user.user_host(), class_name);
+        responses.extend(server_notices(&config, users, 'c', &notice));
+        return responses;
+    }
+    let user = users.get_mut(&client_id).expect("registering user is present");
+    user.registered = true;
+    user.sasl = None;
+
+    let nickname = user.reply_nick();
+    let connecting = format!("Client connecting: {} ({}) [{}]", nickname, user.user_host(), user.realname.as_deref().unwrap_or_default());
+    let displayed_host = Some(user.display_host()).filter(|host| *host != user.real_host());
+    let server = &config.server.name;
+    let mut burst = vec![
+        format!(":{} 001 {} :Welcome to the IRC server!", server, nickname),
+        format!(":{} 002 {} :Your host is {}, running version {}", server, nickname, server, VERSION),
+        format!(":{} 003 {} :This server was created {}", server, nickname, shared_state.stats.started.format("%Y-%m-%d")),
+        format!(":{} 004 {} {} {} oswx o", server, nickname, server, VERSION),
+    ];
+    burst.extend(isupport_lines(&config, &nickname));
+    burst.extend(lusers_lines(&config, &nickname, users, channel_count, &shared_state.stats));
+    burst.extend(motd_lines(&config, &nickname));
+    if let Some(host) = displayed_host {
+        burst.push(format!(":{} 396 {} {} :is now your displayed host", server, nickname, host));
+    }
+    let mut responses: Vec<(usize, String)> = burst.into_iter().map(|line| (client_id, line)).collect();
+    responses.extend(server_notices(&config, users, 'c', &connecting));
+    responses
+}
+
+/// ISUPPORT tokens describing this server to clients.
+fn isupport_tokens(config: &Config) -> Vec<String> {
+    let mut tokens = vec![
+        "CASEMAPPING=ascii".to_string(),
+        format!("CHANNELLEN={}", config.limits.channel_length),
+        "CHANTYPES=#".to_string(),
+        "CHARSET=utf-8".to_string(),
+        format!("CHATHISTORY={}", CHATHISTORY_MAX),
+        "MSGREFTYPES=msgid,timestamp".to_string(),
+        format!("NETWORK={}", config.server.network),
+        format!("NICKLEN={}", config.limits.nick_length),
+        "PREFIX=(ov)@+".to_string(),
+        "TARGMAX=NAMES:1,LIST:1,WHOIS:1,PRIVMSG:1,NOTICE:1".to_string(),
+        format!("TOPICLEN={}", config.limits.topic_length),
+        "UTF8ONLY".to_string(),
+    ];
+    tokens.sort();
+    tokens
+}
+
+/// 005 replies, with at most `ISUPPORT_PER_LINE` tokens each.
+fn isupport_lines(config: &Config, nick: &str) -> Vec<String> {
+    isupport_tokens(config)
+        .chunks(ISUPPORT_PER_LINE)
+        .map(|tokens| format!(":{} 005 {} {} :are supported by this server", config.server.name, nick, tokens.join(" ")))
+        .collect()
+}
+
+/// 251-255 and 265/266 replies. Only registered users are counted as users; 252-254 are
+/// left out when there is nothing to report.
+fn lusers_lines(config: &Config, nick: &str, users: &HashMap<usize, User>, channel_count: usize, stats: &ServerStats) -> Vec<String> {
+    let server = &config.server.name;
+    let registered = users.values().filter(|user| user.registered).count();
+    let unknown = users.len() - registered;
+    let opers = users.values().filter(|user| user.registered && user.oper.is_some()).count();
+    // Every user is local until servers can be linked
+    stats.record_users(registered, registered);
+    let (max_local, max_global) = (stats.max_local_users(), stats.max_global_users());
+
+    let mut lines = vec![format!(":{} 251 {} :There are {} users and 0 services on 1 server", server, nick, registered)];
+    if opers > 0 {
+        lines.push(format!(":{} 252 {} {} :operator(s) online", server, nick, opers));
+    }
+    if unknown > 0 {
+        lines.push(format!(":{} 253 {} {} :unknown connection(s)", server, nick, unknown));
+    }
+    if channel_count > 0 {
+        lines.push(format!(":{} 254 {} {} :channels formed", server, nick, channel_count));
+    }
+    lines.push(format!(":{} 255 {} :I have {} clients and 0 servers", server, nick, registered));
+    lines.push(format!(":{} 265 {} {} {} :Current local users {}, max {}", server, nick, registered, max_local, registered, max_local));
+    lines.push(format!(":{} 266 {} {} {} :Current global users {}, max {}", server, nick, registered, max_global, registered, max_global));
+    lines
+}
+
+fn handle_lusers(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let channel_count = shared_state.channels.lock().unwrap().len();
+    let users = shared_state.users.lock().unwrap();
+    let nick = users.get(&client_id).map(|user| user.reply_nick()).ok_or_else(|| "User not found".to_string())?;
+    let lines = lusers_lines(&shared_state.config(), &nick, &users, channel_count, &shared_state.stats);
+    Ok(lines.into_iter().map(|line| (client_id, line)).collect())
+}
+
+/// 375/372/376 replies with the MOTD, or 422 when none is configured.
+fn motd_lines(config: &Config, nick: &str) -> Vec<String> {
+    let server = &config.server.name;
+    match &config.motd {
+        Some(motd) => {
+            let mut lines = vec![format!(":{} 375 {} :- {} Message of the day - ", server, nick, server)];
+            lines.extend(motd.lines.iter().map(|line| format!(":{} 372 {} :- {}", server, nick, line)));
+            lines.push(format!(":{} 376 {} :End of /MOTD command.", server, nick));
+            lines
+        }
+        None => vec![format!(":{} 422 {} :MOTD File is missing", server, nick)],
+    }
+}
+
+fn handle_motd(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let nick = shared_state.users.lock().unwrap().get(&client_id)
+        .map(|user| user.reply_nick())
+        .ok_or_else(|| "User not found".to_string())?;
+    Ok(motd_lines(&shared_state.config(), &nick).into_iter().map(|line| (client_id, line)).collect())
+}
+
 fn handle_join(client_id: usize, channel_name: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    if channel_name.len() > config.limits.channel_length {
+        return Err(format!("Channel name {} is too long", channel_name));
+    }
     let mut channels = shared_state.channels.lock().unwrap();
     let mut users = shared_state.users.lock().unwrap();
 
-    let channel = channels.entry(channel_name.clone()).or_insert_with(|| Channel::new(channel_name.clone()));
+    let key = casefold(&channel_name);
+    let joined = channels.get(&key).map(|channel| channel.members.contains(&client_id)).unwrap_or(false);
+    if let Some(user) = users.get(&client_id).filter(|_| !joined) {
+        if user.channels.len() >= config.user_class(&user.class, user.oper.as_deref()).max_channels {
+            return Ok(vec![(client_id, format!(":{} 405 {} {} :You have joined too many channels", config.server.name, user.reply_nick(), channel_name))]);
+        }
+    }
+
+    let is_new_channel = !channels.contains_key(&key);
+    let channel = channels.entry(key).or_insert_with(|| Channel::new(channel_name));
+    // Replies use the name the channel was created with
+    let channel_name = channel.name.clone();
     channel.add_member(client_id);
+    if is_new_channel {
+        // Whoever creates a channel becomes its operator
+        channel.add_operator(client_id);
+    }
 
     if let Some(user) = users.get_mut(&client_id) {
         user.join_channel(channel_name.clone());
+        let user = &users[&client_id];
         let nick = user.nickname.clone().unwrap_or_else(|| client_id.to_string());
-        let user_list = channel.members.iter()
-            .map(|&id| users.get(&id).and_then(|u| u.nickname.clone()).unwrap_or_else(|| id.to_string()))
-            .collect::<Vec<_>>()
-            .join(" ");
-        
-        let join_message = format!(":{} JOIN :{}", nick, channel_name);
-        let names_message = format!(":{} 353 {} = {} :{}", "server", nick, channel_name, user_list);
-        let end_of_names = format!(":{} 366 {} {} :End of /NAMES list", "server", nick, channel_name);
-        
-        let mut messages = vec![
-            (client_id, join_message.clone()),
-            (client_id, names_message.clone()),
-            (client_id, end_of_names.clone())
-        ];
-        
+
+        let mut messages = vec![(client_id, join_line(user, &channel_name, user))];
+        messages.extend(names_lines(&config.server.name, channel, &users, user, &nick).into_iter().map(|line| (client_id, line)));
+
         // Notify other channel members about the new user
         for &member_id in &channel.members {
             if member_id != client_id {
                 if let Some(member) = users.get(&member_id) {
-                    let member_nick = member.nickname.clone().unwrap_or_else(|| member_id.to_string());
-                    messages.push((member_id, format!(":{} 353 {} = {} :{}", "server", member_nick, channel_name, user_list)));
-                    messages.push((member_id, format!(":{} 366 {} {} :End of /NAMES list", "server", member_nick, channel_name)));
+                    messages.push((member_id, join_line(user, &channel_name, member)));
                 }
             }
         }
-        
+
         Ok(messages)
     } else {
         Err("User not found".to_string())
     }
 }
 
+/// JOIN line as seen by `recipient`, including account and realname for `extended-join`.
+fn join_line(joiner: &User, channel_name: &str, recipient: &User) -> String {
+    let nick = joiner.nickname.clone().unwrap_or_else(|| joiner.id.to_string());
+    if recipient.has_cap("extended-join") {
+        format!(
+            ":{} JOIN {} {} :{}",
+            nick,
+            channel_name,
+            joiner.account.as_deref().unwrap_or("*"),
+            joiner.realname.as_deref().unwrap_or("")
+        )
+    } else {
+        format!(":{} JOIN :{}", nick, channel_name)
+    }
+}
+
+/// 353/366 replies for `channel` as seen by `viewer`, addressed to `target`.
+fn names_lines(server: &str, channel: &Channel, users: &HashMap<usize, User>, viewer: &User, target: &str) -> Vec<String> {
+    let multi_prefix = viewer.has_cap("multi-prefix");
+    let userhost = viewer.has_cap("userhost-in-names");
+    let user_list = channel.members.iter()
+        .map(|&id| {
+            let name = match users.get(&id) {
+                Some(member) if userhost => member.mask(),
+                Some(member) => member.nickname.clone().unwrap_or_else(|| id.to_string()),
+                None => id.to_string(),
+            };
+            format!("{}{}", channel.member_prefixes(id, multi_prefix), name)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    vec![
+        format!(":{} 353 {} = {} :{}", server, target, channel.name, user_list),
+        format!(":{} 366 {} {} :End of /NAMES list", server, target, channel.name),
+    ]
+}
+
 fn handle_part(client_id: usize, channel_name: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
     let mut channels = shared_state.channels.lock().unwrap();
     let mut users = shared_state.users.lock().unwrap();
@@ -114,13 +470,16 @@ fn handle_part(client_id: usize, channel_name: String, shared_state: &SharedStat
     let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
     let nick = user.nickname.clone().unwrap_or_else(|| client_id.to_string());
 
-    if let Some(channel) = channels.get_mut(&channel_name) {
-        channel.remove_member(&client_id);
-        if channel.members.is_empty() {
-            channels.remove(&channel_name);
+    let key = casefold(&channel_name);
+    let channel_name = match channels.get_mut(&key) {
+        Some(channel) => {
+            channel.remove_member(&client_id);
+            channel.name.clone()
         }
-    } else {
-        return Err(format!("Channel {} not found", channel_name));
+        None => return Err(format!("Channel {} not found", channel_name)),
+    };
+    if channels[&key].members.is_empty() {
+        channels.remove(&key);
     }
 
     user.leave_channel(&channel_name);
@@ -128,92 +487,182 @@ fn handle_part(client_id: usize, channel_name: String, shared_state: &SharedStat
 }
 
 fn handle_privmsg(client_id: usize, target: String, message: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
-    let users = shared_state.users.lock().unwrap();
+    relay_message(client_id, MessageKind::PrivMsg, target, message, shared_state)
+}
+
+fn handle_notice(client_id: usize, target: String, message: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    // NOTICE must never trigger an error reply
+    Ok(relay_message(client_id, MessageKind::Notice, target, message, shared_state).unwrap_or_default())
+}
+
+fn relay_message(client_id: usize, kind: MessageKind, target: String, content: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
     let channels = shared_state.channels.lock().unwrap();
+    let users = shared_state.users.lock().unwrap();
 
     let sender = users.get(&client_id).ok_or_else(|| "User not found".to_string())?;
     let sender_nick = sender.nickname.clone().unwrap_or_else(|| client_id.to_string());
 
     if target.starts_with('#') {
         // Channel message
-        let channel = channels.get(&target).ok_or_else(|| format!("Channel {} not found", target))?;
+        let channel = channels.get(&casefold(&target)).ok_or_else(|| format!("Channel {} not found", target))?;
+        let target = channel.name.clone();
         if !channel.members.contains(&client_id) {
             return Err("You're not on that channel".to_string());
         }
 
-        let message_to_send = format!(":{} PRIVMSG {} :{}", sender_nick, target, message);
-        Ok(channel.members.iter()
+        let message = Message::new(client_id, Recipient::Channel(target.clone()), content).with_kind(kind);
+        shared_state.history.append(
+            &channel_buffer(&target),
+            HistoryEntry::from_message(&message, &sender_nick, sender.account.as_deref(), &target),
+        );
+        let mut responses: Vec<(usize, String)> = channel.members.iter()
             .filter(|&&member_id| member_id != client_id)
-            .map(|&member_id| (member_id, message_to_send.clone()))
-            .collect())
+            .filter_map(|member_id| users.get(member_id))
+            .map(|member| (member.id, message.to_line(&sender_nick, &target, &message_tags(&message, sender, member))))
+            .collect();
+        if sender.has_cap("echo-message") {
+            responses.push((client_id, message.to_line(&sender_nick, &target, &message_tags(&message, sender, sender))));
+        }
+        Ok(responses)
     } else {
         // Private message
-        let target_user = users.values().find(|u| u.nickname.as_ref() == Some(&target))
-            .ok_or_else(|| format!("User {} not found", target))?;
-        
-        Ok(vec![(target_user.id, format!(":{} PRIVMSG {} :{}", sender_nick, target, message))])
+        let target_user = find_nick(&users, &target).ok_or_else(|| format!("User {} not found", target))?;
+        let target = target_user.reply_nick();
+
+        let message = Message::new(client_id, Recipient::User(target_user.id), content).with_kind(kind);
+        // Private history is only kept between logged-in users, who can read it back
+        if let (Some(from), Some(to)) = (sender.account.as_deref(), target_user.account.as_deref()) {
+            shared_state.history.append(
+                &private_buffer(from, to),
+                HistoryEntry::from_message(&message, &sender_nick, sender.account.as_deref(), &target),
+            );
+        }
+        let mut responses = vec![(target_user.id, message.to_line(&sender_nick, &target, &message_tags(&message, sender, target_user)))];
+        if sender.has_cap("echo-message") && target_user.id != client_id {
+            responses.push((client_id, message.to_line(&sender_nick, &target, &message_tags(&message, sender, sender))));
+        }
+        Ok(responses)
     }
 }
 
+/// Tags a relayed message carries for a recipient, depending on the capabilities it negotiated.
+fn relay_tags(time: String, msgid: &str, account: Option<&str>, recipient: &User) -> Vec<(&'static str, String)> {
+    let mut tags = Vec::new();
+    if recipient.has_cap("server-time") {
+        tags.push(("time", time));
+    }
+    if recipient.has_cap("message-tags") {
+        tags.push(("msgid", msgid.to_string()));
+    }
+    if recipient.has_cap("account-tag") {
+        if let Some(account) = account {
+            tags.push(("account", account.to_string()));
+        }
+    }
+    tags
+}
+
+fn message_tags(message: &Message, sender: &User, recipient: &User) -> Vec<(&'static str, String)> {
+    relay_tags(message.server_time(), &message.msgid, sender.account.as_deref(), recipient)
+}
+
 fn handle_quit(client_id: usize, message: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
-    let mut users = shared_state.users.lock().unwrap();
     let mut channels = shared_state.channels.lock().unwrap();
+    let mut users = shared_state.users.lock().unwrap();
+    let quit_message = message.unwrap_or_else(|| "Client Quit".to_string());
 
-    if let Some(user) = users.remove(&client_id) {
+    if let Some((user, mut responses)) = remove_user(client_id, &quit_message, &shared_state.config(), &mut users, &mut channels) {
         let nick = user.nickname.unwrap_or_else(|| client_id.to_string());
-        let quit_message = message.unwrap_or_else(|| "Client Quit".to_string());
-
-        let mut responses = Vec::new();
-
-        for channel_name in &user.channels {
-            if let Some(channel) = channels.get_mut(channel_name) {
-                channel.remove_member(&client_id);
-                for &member_id in &channel.members {
-                    responses.push((member_id, format!(":{} QUIT :{}", nick, quit_message)));
-                }
-            }
-        }
-
         // Add a response for the client who is quitting
         responses.push((client_id, format!(":{} QUIT :{}", nick, quit_message)));
-
         Ok(responses)
     } else {
         Err("User not found".to_string())
     }
 }
 
+/// Removes a user from the server and their channels, telling the other members and
+/// subscribed opers they quit. Replies to the user themselves are left to the caller.
+fn remove_user(client_id: usize, quit_message: &str, config: &Config, users: &mut HashMap<usize, User>, channels: &mut HashMap<String, Channel>) -> Option<(User, Vec<(usize, String)>)> {
+    let user = users.remove(&client_id)?;
+    let nick = user.nickname.clone().unwrap_or_else(|| client_id.to_string());
+    let mut responses = Vec::new();
+    for channel_name in &user.channels {
+        if let Some(channel) = channels.get_mut(&casefold(channel_name)) {
+            channel.remove_member(&client_id);
+            for &member_id in &channel.members {
+                responses.push((member_id, format!(":{} QUIT :{}", nick, quit_message)));
+            }
+        }
+    }
+    if user.registered {
+        responses.extend(server_notices(config, users, 'c', &format!("Client exiting: {} ({}) [{}]", nick, user.user_host(), quit_message)));
+    }
+    Some((user, responses))
+}
+
+/// Disconnects a client for `reason`, such as Excess Flood, and reports it to opers
+/// subscribed to `letter` besides the usual exit notice.
+pub fn disconnect_client(client_id: usize, reason: &str, letter: Option<char>, shared_state: &SharedState) -> Vec<(usize, String)> {
+    let config = shared_state.config();
+    let mut channels = shared_state.channels.lock().unwrap();
+    let mut users = shared_state.users.lock().unwrap();
+    let who = match users.get(&client_id) {
+        Some(user) => format!("{} ({})", user.reply_nick(), user.user_host()),
+        None => return Vec::new(),
+    };
+    let mut responses = disconnect_user(client_id, reason, &config, &mut users, &mut channels);
+    if let Some(letter) = letter {
+        responses.extend(server_notices(&config, &users, letter, &format!("{}: {}", reason, who)));
+    }
+    responses
+}
+
+/// Closes another user's connection: their channels see a QUIT with `reason` and the
+/// user gets an ERROR, after which their connection ends.
+fn disconnect_user(target_id: usize, reason: &str, config: &Config, users: &mut HashMap<usize, User>, channels: &mut HashMap<String, Channel>) -> Vec<(usize, String)> {
+    match remove_user(target_id, reason, config, users, channels) {
+        Some((user, mut responses)) => {
+            responses.push((target_id, format!("ERROR :Closing Link: {} ({})", user.host, reason)));
+            responses
+        }
+        None => Vec::new(),
+    }
+}
+
 fn handle_ping(client_id: usize, server: String) -> Result<Vec<(usize, String)>, String> {
     Ok(vec![(client_id, format!("PONG {}", server))])
 }
 
 fn handle_pong(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
     let mut users = shared_state.users.lock().unwrap();
-    if let Some(_user) = users.get_mut(&client_id) {
-        // Update last activity timestamp
-        // For now, we'll just return an OK message
-        Ok(vec![(client_id, "PONG received".to_string())])
+    if users.get_mut(&client_id).is_some() {
+        // The connection's read loop counts any line, PONG included, as activity
+        Ok(Vec::new())
     } else {
         Err("User not found".to_string())
     }
 }
 
 fn handle_topic(client_id: usize, channel_name: String, topic: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
     let mut channels = shared_state.channels.lock().unwrap();
     let users = shared_state.users.lock().unwrap();
 
-    if let Some(channel) = channels.get_mut(&channel_name) {
+    if let Some(channel) = channels.get_mut(&casefold(&channel_name)) {
+        let channel_name = channel.name.clone();
         if let Some(user) = users.get(&client_id) {
             let nick = user.nickname.clone().unwrap_or_else(|| client_id.to_string());
             match topic {
-                Some(new_topic) => {
+                Some(mut new_topic) => {
+                    truncate_to(&mut new_topic, shared_state.config().limits.topic_length);
                     channel.set_topic(new_topic.clone());
                     Ok(vec![(client_id, format!(":{} TOPIC {} :{}", nick, channel_name, new_topic))])
                 }
                 None => {
                     match &channel.topic {
-                        Some(current_topic) => Ok(vec![(client_id, format!(":{} 332 {} {} :{}", "server", nick, channel_name, current_topic))]),
-                        None => Ok(vec![(client_id, format!(":{} 331 {} {} :No topic is set", "server", nick, channel_name))]),
+                        Some(current_topic) => Ok(vec![(client_id, format!(":{} 332 {} {} :{}", server, nick, channel_name, current_topic))]),
+                        None => Ok(vec![(client_id, format!(":{} 331 {} {} :No topic is set", server, nick, channel_name))]),
                     }
                 }
             }
@@ -225,35 +674,155 @@ fn handle_topic(client_id: usize, channel_name: String, topic: Option<String>, s
     }
 }
 
+/// User whose nickname is `nick` under the server's casemapping.
+fn find_nick<'a>(users: &'a HashMap<usize, User>, nick: &str) -> Option<&'a User> {
+    let nick = casefold(nick);
+    users.values().find(|user| user.nickname.as_deref().map(|n| casefold(n) == nick).unwrap_or(false))
+}
+
+/// Shortens `text` to at most `max_len` bytes without splitting a character.
+fn truncate_to(text: &mut String, max_len: usize) {
+    if text.len() > max_len {
+        let mut end = max_len;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
 fn handle_names(client_id: usize, channel_name: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
     let channels = shared_state.channels.lock().unwrap();
     let users = shared_state.users.lock().unwrap();
 
-    if let Some(channel) = channels.get(&channel_name) {
-        let user_list = channel.members.iter()
-            .filter_map(|&id| users.get(&id))
-            .map(|user| user.nickname.clone().unwrap_or_else(|| user.id.to_string()))
-            .collect::<Vec<_>>()
-            .join(" ");
-
-        Ok(vec![
-            (client_id, format!(":{} 353 * = {} :{}", "server", channel_name, user_list)),
-            (client_id, format!(":{} 366 * {} :End of /NAMES list", "server", channel_name)),
-        ])
+    if let Some(channel) = channels.get(&casefold(&channel_name)) {
+        let viewer = users.get(&client_id).cloned().unwrap_or_else(|| User::new(client_id, "0.0.0.0".parse().unwrap()));
+        Ok(names_lines(&shared_state.config().server.name, channel, &users, &viewer, "*").into_iter().map(|line| (client_id, line)).collect())
     } else {
         Err(format!("Channel {} not found", channel_name))
     }
 }
 
+fn handle_who(client_id: usize, mask: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let channels = shared_state.channels.lock().unwrap();
+    let users = shared_state.users.lock().unwrap();
+
+    let viewer = users.get(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let me = viewer.reply_nick();
+    let mask = mask.split_whitespace().next().unwrap_or("*").to_string();
+    let multi_prefix = viewer.has_cap("multi-prefix");
+
+    let mut responses = Vec::new();
+    if mask.starts_with('#') {
+        if let Some(channel) = channels.get(&casefold(&mask)) {
+            let mut members: Vec<&User> = channel.members.iter().filter_map(|id| users.get(id)).collect();
+            members.sort_by_key(|member| member.reply_nick());
+            for member in members {
+                let prefixes = channel.member_prefixes(member.id, multi_prefix);
+                responses.push((client_id, who_line(&server, &me, &mask, member, &prefixes)));
+            }
+        }
+    } else if let Some(user) = find_nick(&users, &mask) {
+        responses.push((client_id, who_line(&server, &me, "*", user, "")));
+    }
+    responses.push((client_id, format!(":{} 315 {} {} :End of WHO list", server, me, mask)));
+    Ok(responses)
+}
+
+fn handle_whois(client_id: usize, target: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let channels = shared_state.channels.lock().unwrap();
+    let users = shared_state.users.lock().unwrap();
+
+    let me = users.get(&client_id).map(|u| u.reply_nick()).unwrap_or_else(|| "*".to_string());
+    let requester_is_oper = users.get(&client_id).map(|u| u.oper.is_some()).unwrap_or(false);
+    // "WHOIS server nick" asks a specific server; there is only this one
+    let target = target.split_whitespace().last().unwrap_or("").to_string();
+
+    let user = match find_nick(&users, &target) {
+        Some(user) => user,
+        None => {
+            return Ok(vec![
+                (client_id, format!(":{} 401 {} {} :No such nick/channel", server, me, target)),
+                (client_id, format!(":{} 318 {} {} :End of /WHOIS list", server, me, target)),
+            ]);
+        }
+    };
+    let nick = user.reply_nick();
+
+    let mut responses = vec![(client_id, format!(
+        ":{} 311 {} {} {} {} * :{}",
+        server,
+        me,
+        nick,
+        user.username.as_deref().unwrap_or("*"),
+        user.display_host(),
+        user.realname.as_deref().unwrap_or("")
+    ))];
+
+    let mut user_channels: Vec<String> = user.channels.iter()
+        .filter_map(|name| channels.get(&casefold(name)))
+        .map(|channel| format!("{}{}", channel.member_prefixes(user.id, false), channel.name))
+        .collect();
+    user_channels.sort();
+    if !user_channels.is_empty() {
+        responses.push((client_id, format!(":{} 319 {} {} :{}", server, me, nick, user_channels.join(" "))));
+    }
+
+    responses.push((client_id, format!(":{} 312 {} {} {} :{}", server, me, nick, server, config.server.description)));
+    if let UserStatus::Away(Some(message)) = &user.status {
+        responses.push((client_id, format!(":{} 301 {} {} :{}", server, me, nick, message)));
+    }
+    if user.oper.is_some() {
+        responses.push((client_id, format!(":{} 313 {} {} :is an IRC operator", server, me, nick)));
+    }
+    // The real host behind a cloak or spoof is only shown to its owner and opers
+    if user.id == client_id || requester_is_oper {
+        responses.push((client_id, format!(":{} 378 {} {} :is connecting from {} {}", server, me, nick, user.user_host(), user.host)));
+    }
+    if user.secure {
+        responses.push((client_id, format!(":{} 671 {} {} :is using a secure connection", server, me, nick)));
+    }
+    // Certificate fingerprints are only shown to their owner
+    if let Some(certfp) = user.certfp.as_ref().filter(|_| user.id == client_id) {
+        responses.push((client_id, format!(":{} 276 {} {} :has client certificate fingerprint {}", server, me, nick, certfp)));
+    }
+    if let Some(account) = &user.account {
+        responses.push((client_id, format!(":{} 330 {} {} {} :is logged in as", server, me, nick, account)));
+    }
+    responses.push((client_id, format!(":{} 318 {} {} :End of /WHOIS list", server, me, nick)));
+    Ok(responses)
+}
+
+fn who_line(server: &str, me: &str, channel: &str, user: &User, prefixes: &str) -> String {
+    let away = if matches!(user.status, UserStatus::Away(_)) { 'G' } else { 'H' };
+    format!(
+        ":{} 352 {} {} {} {} {} {} {}{} :0 {}",
+        server,
+        me,
+        channel,
+        user.username.as_deref().unwrap_or("*"),
+        user.display_host(),
+        server,
+        user.reply_nick(),
+        away,
+        prefixes,
+        user.realname.as_deref().unwrap_or("")
+    )
+}
+
 fn handle_list(client_id: usize, channel: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
     let channels = shared_state.channels.lock().unwrap();
 
-    let mut response = vec![(client_id, format!(":{} 321 Channel :Users Name", "server"))];
+    let mut response = vec![(client_id, format!(":{} 321 Channel :Users Name", server))];
 
     match channel {
         Some(channel_name) => {
-            if let Some(channel) = channels.get(&channel_name) {
-                response.push((client_id, format!(":{} 322 {} {} :{}", "server", channel_name, channel.members.len(), channel.topic.clone().unwrap_or_default())));
+            if let Some(channel) = channels.get(&casefold(&channel_name)) {
+                response.push((client_id, format!(":{} 322 {} {} :{}", server, channel.name, channel.members.len(), channel.topic.clone().unwrap_or_default())));
             } else {
                 return Err(format!("Channel {} not found", channel_name));
             }
@@ -261,20 +830,809 @@ fn handle_list(client_id: usize, channel: Option<String>, shared_state: &SharedS
         None => {
             let mut channel_list: Vec<_> = channels.iter().collect();
             channel_list.sort_by(|a, b| a.0.cmp(b.0));
-            for (name, channel) in channel_list {
-                response.push((client_id, format!(":{} 322 {} {} :{}", "server", name, channel.members.len(), channel.topic.clone().unwrap_or_default())));
+            for (_, channel) in channel_list {
+                response.push((client_id, format!(":{} 322 {} {} :{}", server, channel.name, channel.members.len(), channel.topic.clone().unwrap_or_default())));
             }
         }
     }
 
-    response.push((client_id, format!(":{} 323 :End of /LIST", "server")));
+    response.push((client_id, format!(":{} 323 :End of /LIST", server)));
     Ok(response)
 }
-fn handle_cap(client_id: usize, subcommand: String, _param: Option<String>, _shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
-    match subcommand.as_str() {
-        "LS" => Ok(vec![(client_id, "CAP * LS :".to_string())]),
-        "REQ" => Ok(vec![(client_id, "CAP * ACK :".to_string())]),
-        "END" => Ok(vec![]),
-        _ => Ok(vec![(client_id, format!("Unknown CAP subcommand: {}", subcommand))]),
+fn handle_cap(client_id: usize, subcommand: String, param: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let channel_count = shared_state.channels.lock().unwrap().len();
+    let mut users = shared_state.users.lock().unwrap();
+    let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let nick = user.reply_nick();
+    let param = param.map(|p| p.trim().trim_start_matches(':').to_string()).unwrap_or_default();
+
+    match subcommand.to_uppercase().as_str() {
+        "LS" => {
+            if !user.registered {
+                user.cap_negotiating = true;
+            }
+            let version_302 = param.parse::<u32>().map(|v| v >= 302).unwrap_or(false);
+            let caps = SUPPORTED_CAPS.iter()
+                .map(|cap| cap_ls_entry(cap, version_302))
+                .collect::<Vec<_>>()
+                .join(" ");
+            Ok(vec![(client_id, format!(":{} CAP {} LS :{}", server, nick, caps))])
+        }
+        "LIST" => {
+            let mut caps: Vec<_> = user.capabilities.iter().cloned().collect();
+            caps.sort();
+            Ok(vec![(client_id, format!(":{} CAP {} LIST :{}", server, nick, caps.join(" ")))])
+        }
+        "REQ" => {
+            if !user.registered {
+                user.cap_negotiating = true;
+            }
+            let requested: Vec<&str> = param.split_whitespace().collect();
+            let all_supported = requested.iter()
+                .all(|cap| SUPPORTED_CAPS.contains(&cap.trim_start_matches('-')));
+            if requested.is_empty() || !all_supported {
+                return Ok(vec![(client_id, format!(":{} CAP {} NAK :{}", server, nick, param))]);
+            }
+            for cap in &requested {
+                match cap.strip_prefix('-') {
+                    Some(removed) => {
+                        user.capabilities.remove(removed);
+                    }
+                    None => {
+                        user.capabilities.insert(cap.to_string());
+                    }
+                }
+            }
+            Ok(vec![(client_id, format!(":{} CAP {} ACK :{}", server, nick, param))])
+        }
+        "END" => {
+            user.cap_negotiating = false;
+            Ok(complete_registration(client_id, &mut users, channel_count, shared_state))
+        }
+        _ => Ok(vec![(client_id, format!(":{} 410 {} {} :Invalid CAP command", server, nick, subcommand))]),
+    }
+}
+
+fn cap_ls_entry(cap: &str, version_302: bool) -> String {
+    match cap {
+        "sasl" if version_302 => format!("sasl={}", SASL_MECHANISMS.join(",")),
+        _ => cap.to_string(),
+    }
+}
+
+fn handle_authenticate(client_id: usize, data: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let mut users = shared_state.users.lock().unwrap();
+    let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let nick = user.reply_nick();
+
+    if !user.has_cap("sasl") {
+        return Ok(vec![(client_id, format!(":{} 904 {} :SASL authentication failed", server, nick))]);
+    }
+    if user.account.is_some() {
+        return Ok(vec![(client_id, format!(":{} 907 {} :You have already authenticated using SASL", server, nick))]);
+    }
+    if data == "*" {
+        user.sasl = None;
+        return Ok(vec![(client_id, format!(":{} 906 {} :SASL authentication aborted", server, nick))]);
+    }
+
+    let mut session = match user.sasl.take() {
+        Some(session) => session,
+        None => {
+            let mechanism = data.to_uppercase();
+            if !SASL_MECHANISMS.contains(&mechanism.as_str()) {
+                return Ok(vec![
+                    (client_id, format!(":{} 908 {} {} :are available SASL mechanisms", server, nick, SASL_MECHANISMS.join(","))),
+                    (client_id, format!(":{} 904 {} :SASL authentication failed", server, nick)),
+                ]);
+            }
+            user.sasl = Some(SaslSession::new(mechanism));
+            return Ok(vec![(client_id, "AUTHENTICATE +".to_string())]);
+        }
+    };
+
+    let payload = match session.push_chunk(&data) {
+        Ok(Some(payload)) => payload,
+        Ok(None) => {
+            user.sasl = Some(session);
+            return Ok(vec![]);
+        }
+        Err(reason) => return Ok(vec![(client_id, format!(":{} 905 {} :{}", server, nick, reason))]),
+    };
+
+    let account = BASE64.decode(payload.as_bytes()).ok()
+        .and_then(|decoded| sasl_account(&session.mechanism, &decoded, user.certfp.as_deref(), shared_state.accounts.as_ref()));
+
+    match account {
+        Some(account) => {
+            user.account = Some(account.clone());
+            Ok(vec![
+                (client_id, format!(":{} 900 {} {} {} :You are now logged in as {}", server, nick, user.mask(), account, account)),
+                (client_id, format!(":{} 903 {} :SASL authentication successful", server, nick)),
+            ])
+        }
+        None => Ok(vec![(client_id, format!(":{} 904 {} :SASL authentication failed", server, nick))]),
+    }
+}
+
+/// Resolves the account a completed SASL exchange logs into, if the credentials are valid.
+fn sasl_account(mechanism: &str, payload: &[u8], certfp: Option<&str>, accounts: &dyn AccountStore) -> Option<String> {
+    match mechanism {
+        "PLAIN" => {
+            let (authzid, authcid, password) = parse_plain_payload(payload)?;
+            if !authzid.is_empty() && !authzid.eq_ignore_ascii_case(&authcid) {
+                return None;
+            }
+            if accounts.verify_password(&authcid, &password) {
+                Some(authcid)
+            } else {
+                None
+            }
+        }
+        "EXTERNAL" => {
+            let account = accounts.account_for_certfp(certfp?)?;
+            let authzid = std::str::from_utf8(payload).ok()?;
+            if !authzid.is_empty() && !authzid.eq_ignore_ascii_case(&account) {
+                return None;
+            }
+            Some(account)
+        }
+        _ => None,
+    }
+}
+
+fn handle_chathistory(client_id: usize, subcommand: String, params: Vec<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let channels = shared_state.channels.lock().unwrap();
+    let users = shared_state.users.lock().unwrap();
+    let user = users.get(&client_id).ok_or_else(|| "User not found".to_string())?;
+
+    let fail = |code: &str, context: &str, description: &str| {
+        Ok(vec![(client_id, format!(":{} FAIL CHATHISTORY {} {} :{}", server, code, context, description))])
+    };
+
+    let limit = match params.last().and_then(|limit| limit.parse::<usize>().ok()) {
+        Some(limit) => limit.min(CHATHISTORY_MAX),
+        None => return fail("INVALID_PARAMS", &subcommand, "Invalid or missing message limit"),
+    };
+
+    if subcommand == "TARGETS" {
+        let bounds = (
+            params.first().and_then(|p| HistoryRef::parse(p)),
+            params.get(1).and_then(|p| HistoryRef::parse(p)),
+        );
+        let (from, to) = match bounds {
+            (Some(HistoryRef::Timestamp(from)), Some(HistoryRef::Timestamp(to))) if params.len() == 3 => (from.min(to), from.max(to)),
+            _ => return fail("INVALID_PARAMS", &subcommand, "TARGETS takes two timestamps and a limit"),
+        };
+
+        let account = user.account.as_deref().map(casefold);
+        let mut targets: Vec<(String, chrono::DateTime<chrono::Utc>)> = shared_state.history.buffers().into_iter()
+            .filter(|(_, latest)| *latest > from && *latest <= to)
+            .filter_map(|(buffer, latest)| match private_participants(&buffer) {
+                Some((a, b)) if Some(a) == account.as_deref() => Some((b.to_string(), latest)),
+                Some((a, b)) if Some(b) == account.as_deref() => Some((a.to_string(), latest)),
+                Some(_) => None,
+                None => user.channels.iter()
+                    .find(|channel| channel_buffer(channel) == buffer)
+                    .map(|channel| (channel.clone(), latest)),
+            })
+            .collect();
+        targets.sort_by_key(|(_, latest)| *latest);
+        targets.truncate(limit);
+
+        let lines = targets.into_iter()
+            .map(|(target, latest)| format!(":{} CHATHISTORY TARGETS {} {}", server, target, format_server_time(&latest)))
+            .collect();
+        return Ok(history_batch(user, Batch::new(&server, "draft/chathistory-targets"), lines));
+    }
+
+    // A target, one message reference (two for BETWEEN) and the limit
+    let expected = match subcommand.as_str() {
+        "LATEST" | "BEFORE" | "AFTER" | "AROUND" => 3,
+        "BETWEEN" => 4,
+        _ => return fail("INVALID_PARAMS", &subcommand, "Unknown subcommand"),
+    };
+    if params.len() != expected {
+        return fail("INVALID_PARAMS", &subcommand, "Wrong number of parameters");
+    }
+    let mut target = params[0].clone();
+    let refs: Vec<Option<HistoryRef>> = params[1..expected - 1].iter().map(|p| HistoryRef::parse(p)).collect();
+    let query = match (subcommand.as_str(), refs.as_slice()) {
+        ("LATEST", [Some(reference)]) => HistoryQuery::Latest(reference.clone()),
+        ("BEFORE", [Some(reference)]) if *reference != HistoryRef::Any => HistoryQuery::Before(reference.clone()),
+        ("AFTER", [Some(reference)]) if *reference != HistoryRef::Any => HistoryQuery::After(reference.clone()),
+        ("AROUND", [Some(reference)]) if *reference != HistoryRef::Any => HistoryQuery::Around(reference.clone()),
+        ("BETWEEN", [Some(from), Some(to)]) if *from != HistoryRef::Any && *to != HistoryRef::Any => {
+            HistoryQuery::Between(from.clone(), to.clone())
+        }
+        _ => return fail("INVALID_PARAMS", &subcommand, "Invalid message reference"),
+    };
+
+    let buffer = if target.starts_with('#') {
+        match channels.get(&casefold(&target)) {
+            Some(channel) if channel.members.contains(&client_id) => {
+                target = channel.name.clone();
+                channel_buffer(&channel.name)
+            }
+            _ => return fail("INVALID_TARGET", &subcommand, &format!("{} :Messages could not be retrieved", target)),
+        }
+    } else {
+        // Conversations belong to accounts; the other side is named by the nickname of
+        // a user logged in to it, or by the account itself
+        let other = find_nick(&users, &target).and_then(|other| other.account.clone()).unwrap_or_else(|| target.clone());
+        match user.account.as_deref() {
+            Some(account) => private_buffer(account, &other),
+            None => return fail("INVALID_TARGET", &subcommand, &format!("{} :Log in to read private history", target)),
+        }
+    };
+
+    let entries = shared_state.history.entries(&buffer);
+    let lines = select_history(&entries, &query, limit).iter()
+        .map(|entry| entry.to_line(&relay_tags(entry.server_time(), &entry.msgid, entry.account.as_deref(), user)))
+        .collect();
+    Ok(history_batch(user, Batch::new(&server, "chathistory").with_param(&target), lines))
+}
+
+fn history_batch(user: &User, batch: Batch, lines: Vec<String>) -> Vec<(usize, String)> {
+    let lines = if user.has_cap("batch") { batch.frame(lines) } else { lines };
+    lines.into_iter().map(|line| (user.id, line)).collect()
+}
+
+fn handle_rehash(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&shared_state.config(), &nick, oper.as_deref(), "rehash") {
+        return Ok(vec![(client_id, denied)]);
+    }
+
+    let file = shared_state.config.path().map(|path| path.display().to_string()).unwrap_or_else(|| "*".to_string());
+    match shared_state.config.reload() {
+        Ok(config) => {
+            log::info!("{} rehashed the server configuration", nick);
+            Ok(vec![(client_id, format!(":{} 382 {} {} :Rehashing", config.server.name, nick, file))])
+        }
+        Err(e) => {
+            log::error!("Rehash by {} failed: {}", nick, e);
+            Ok(vec![(client_id, format!(":{} NOTICE {} :*** Rehash failed, keeping the running configuration: {}", server, nick, e))])
+        }
+    }
+}
+
+/// Nickname to address replies to and the oper block the user logged in with.
+fn requester(client_id: usize, shared_state: &SharedState) -> Result<(String, Option<String>), String> {
+    let users = shared_state.users.lock().unwrap();
+    let user = users.get(&client_id).ok_or_else(|| "User not found".to_string())?;
+    Ok((user.reply_nick(), user.oper.clone()))
+}
+
+/// Error reply for a user lacking an oper privilege: 481 for non-opers, 723 for opers
+/// whose privilege set does not include it.
+fn privilege_denied(config: &Config, nick: &str, oper: Option<&str>, privilege: &str) -> Option<String> {
+    match oper {
+        None => Some(format!(":{} 481 {} :Permission Denied- You're not an IRC operator", config.server.name, nick)),
+        Some(oper) if !config.oper_has_privilege(oper, privilege) => {
+            Some(format!(":{} 723 {} {} :Insufficient oper privileges.", config.server.name, nick, privilege))
+        }
+        Some(_) => None,
+    }
+}
+
+fn handle_oper(client_id: usize, name: String, password: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let mut users = shared_state.users.lock().unwrap();
+    let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let nick = user.reply_nick();
+    if !user.registered {
+        return Ok(vec![(client_id, format!(":{} 451 {} :You have not registered", server, nick))]);
+    }
+
+    let user_host = user.user_host();
+    let block = config.oper(&name).filter(|oper| {
+        oper.hosts.iter().any(|mask| user.matches_host_mask(mask))
+            && oper.certfp.as_deref()
+                .map(|certfp| user.certfp.as_deref() == Some(normalize_certfp(certfp).as_str()))
+                .unwrap_or(true)
+    });
+    let block = match block {
+        Some(block) => block,
+        None => {
+            let failed = format!("Failed OPER attempt by {} ({}) for {}: no matching block", nick, user_host, name);
+            log::warn!("{}", failed);
+            let mut responses = vec![(client_id, format!(":{} 491 {} :No appropriate operator blocks were found for your host", server, nick))];
+            responses.extend(server_notices(&config, &users, 'o', &failed));
+            return Ok(responses);
+        }
+    };
+    if !check_password(&password, &block.password_hash) {
+        let failed = format!("Failed OPER attempt by {} ({}) for {}: wrong password", nick, user_host, name);
+        log::warn!("{}", failed);
+        let mut responses = vec![(client_id, format!(":{} 464 {} :Password incorrect", server, nick))];
+        responses.extend(server_notices(&config, &users, 'o', &failed));
+        return Ok(responses);
+    }
+
+    let newly_oper = user.oper.is_none();
+    user.oper = Some(block.name.clone());
+    let oper_up = format!("{} ({}) is now an operator using {}", nick, user_host, block.name);
+    log::info!("{}", oper_up);
+    let mut responses = vec![(client_id, format!(":{} 381 {} :You are now an IRC operator", server, nick))];
+    if newly_oper {
+        responses.push((client_id, format!(":{} MODE {} :+o", nick, nick)));
+    }
+    responses.extend(server_notices(&config, &users, 'o', &oper_up));
+    Ok(responses)
+}
+
+fn handle_version(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let (nick, _) = requester(client_id, shared_state)?;
+    let mut lines = vec![format!(":{} 351 {} {} {} :{}", config.server.name, nick, VERSION, config.server.name, config.server.description)];
+    lines.extend(isupport_lines(&config, &nick));
+    Ok(lines.into_iter().map(|line| (client_id, line)).collect())
+}
+
+fn handle_time(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let (nick, _) = requester(client_id, shared_state)?;
+    let now = chrono::Utc::now().format("%A %B %d %Y -- %H:%M:%S +00:00");
+    Ok(vec![(client_id, format!(":{} 391 {} {} :{}", server, nick, server, now))])
+}
+
+fn handle_admin(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, _) = requester(client_id, shared_state)?;
+    let admin = &config.admin;
+    if admin.location.is_empty() && admin.organization.is_empty() && admin.email.is_empty() {
+        return Ok(vec![(client_id, format!(":{} 423 {} {} :No administrative info available", server, nick, server))]);
+    }
+    Ok(vec![
+        (client_id, format!(":{} 256 {} {} :Administrative info", server, nick, server)),
+        (client_id, format!(":{} 257 {} :{}", server, nick, admin.location)),
+        (client_id, format!(":{} 258 {} :{}", server, nick, admin.organization)),
+        (client_id, format!(":{} 259 {} :{}", server, nick, admin.email)),
+    ])
+}
+
+fn handle_info(client_id: usize, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, _) = requester(client_id, shared_state)?;
+    let info = [
+        format!("{} -- {}", VERSION, config.server.description),
+        format!("Network: {}", config.server.network),
+        format!("Online since {}", shared_state.stats.started.format("%Y-%m-%d %H:%M:%S UTC")),
+    ];
+    let mut lines: Vec<String> = info.iter().map(|line| format!(":{} 371 {} :{}", server, nick, line)).collect();
+    lines.push(format!(":{} 374 {} :End of INFO list", server, nick));
+    Ok(lines.into_iter().map(|line| (client_id, line)).collect())
+}
+
+/// Server NOTICEs for the opers subscribed to `letter` of the server notice mask.
+pub fn server_notices(config: &Config, users: &HashMap<usize, User>, letter: char, text: &str) -> Vec<(usize, String)> {
+    users.values()
+        .filter(|user| user.oper.is_some() && user.snomask.contains(&letter))
+        .map(|user| (user.id, format!(":{} NOTICE {} :*** Notice -- {}", config.server.name, user.reply_nick(), text)))
+        .collect()
+}
+
+fn handle_mode(client_id: usize, target: String, modes: String, param: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    if target.starts_with('#') {
+        return handle_channel_mode(client_id, target, modes, param, shared_state);
+    }
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let mut users = shared_state.users.lock().unwrap();
+    let user = users.get_mut(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let nick = user.reply_nick();
+    if !user.nickname.as_deref().map(|n| casefold(n) == casefold(&target)).unwrap_or(false) {
+        return Ok(vec![(client_id, format!(":{} 502 {} :Can't change mode for other users", server, nick))]);
+    }
+    if modes.is_empty() {
+        return Ok(vec![(client_id, format!(":{} 221 {} {}", server, nick, user.modes()))]);
+    }
+
+    let (before, snomask_before) = (user.modes(), user.snomask.clone());
+    let mut adding = true;
+    let mut unknown = false;
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            'w' => user.wallops = adding,
+            // Only opers may see server notices
+            's' if adding => {
+                if user.oper.is_some() {
+                    apply_snomask(&mut user.snomask, param.as_deref());
+                }
+            }
+            's' => user.snomask.clear(),
+            // Operator status comes from OPER and can only be dropped here
+            'o' if !adding => {
+                user.oper = None;
+                user.snomask.clear();
+            }
+            'o' => {}
+            // Cloaking is only offered when a cloak key is configured
+            'x' if user.cloaked_host.is_some() => user.cloaked = adding,
+            _ => unknown = true,
+        }
+    }
+
+    let mut responses = Vec::new();
+    if unknown {
+        responses.push((client_id, format!(":{} 501 {} :Unknown MODE flag", server, nick)));
+    }
+    let change = mode_change(&before, &user.modes());
+    if !change.is_empty() {
+        responses.push((client_id, format!(":{} MODE {} :{}", nick, nick, change)));
+    }
+    if user.snomask != snomask_before && !user.snomask.is_empty() {
+        let letters: String = user.snomask.iter().collect();
+        responses.push((client_id, format!(":{} 008 {} +{} :Server notice mask", server, nick, letters)));
+    }
+    if change.contains('x') {
+        responses.push((client_id, format!(":{} 396 {} {} :is now your displayed host", server, nick, user.display_host())));
+    }
+    Ok(responses)
+}
+
+/// Channel MODE. Only the membership modes exist: `o` and `v` give or take operator
+/// status and voice, one member per command, and are open to channel operators.
+fn handle_channel_mode(client_id: usize, channel_name: String, modes: String, param: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let server = shared_state.config().server.name.clone();
+    let mut channels = shared_state.channels.lock().unwrap();
+    let users = shared_state.users.lock().unwrap();
+    let user = users.get(&client_id).ok_or_else(|| "User not found".to_string())?;
+    let nick = user.reply_nick();
+
+    let channel = match channels.get_mut(&casefold(&channel_name)) {
+        Some(channel) => channel,
+        None => return Ok(vec![(client_id, format!(":{} 403 {} {} :No such channel", server, nick, channel_name))]),
+    };
+    if modes.is_empty() {
+        return Ok(vec![(client_id, format!(":{} 324 {} {} +", server, nick, channel.name))]);
+    }
+    if !channel.operators.contains(&client_id) {
+        return Ok(vec![(client_id, format!(":{} 482 {} {} :You're not channel operator", server, nick, channel.name))]);
+    }
+
+    let target_nick = param.as_deref().and_then(|param| param.split_whitespace().next()).unwrap_or_default();
+    let mut adding = true;
+    let mut applied = String::new();
+    let mut responses = Vec::new();
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            'o' | 'v' if applied.is_empty() => {
+                if target_nick.is_empty() {
+                    responses.push((client_id, format!(":{} 461 {} MODE :Not enough parameters", server, nick)));
+                    break;
+                }
+                let target = match find_nick(&users, target_nick) {
+                    Some(target) => target,
+                    None => {
+                        responses.push((client_id, format!(":{} 401 {} {} :No such nick/channel", server, nick, target_nick)));
+                        break;
+                    }
+                };
+                if !channel.members.contains(&target.id) {
+                    responses.push((client_id, format!(":{} 441 {} {} {} :They aren't on that channel", server, nick, target.reply_nick(), channel.name)));
+                    break;
+                }
+                match (mode, adding) {
+                    ('o', true) => channel.add_operator(target.id),
+                    ('o', false) => channel.remove_operator(&target.id),
+                    ('v', true) => channel.add_voice(target.id),
+                    _ => channel.remove_voice(&target.id),
+                }
+                applied = format!("{}{} {}", if adding { '+' } else { '-' }, mode, target.reply_nick());
+            }
+            // Further membership changes would need parameters of their own
+            'o' | 'v' => {}
+            _ => responses.push((client_id, format!(":{} 472 {} {} :is unknown mode char to me", server, nick, mode))),
+        }
+    }
+
+    if !applied.is_empty() {
+        let line = format!(":{} MODE {} {}", user.mask(), channel.name, applied);
+        responses.extend(channel.members.iter().map(|&member_id| (member_id, line.clone())));
+    }
+    Ok(responses)
+}
+
+/// Applies server notice mask letters such as `ck` or `+c-k`. Without any, every
+/// letter is added.
+fn apply_snomask(snomask: &mut BTreeSet<char>, letters: Option<&str>) {
+    let letters = match letters.filter(|letters| !letters.is_empty()) {
+        Some(letters) => letters,
+        None => {
+            snomask.extend(SNOMASK_LETTERS.chars());
+            return;
+        }
+    };
+    let mut adding = true;
+    for letter in letters.chars() {
+        match letter {
+            '+' => adding = true,
+            '-' => adding = false,
+            letter if SNOMASK_LETTERS.contains(letter) => {
+                if adding {
+                    snomask.insert(letter);
+                } else {
+                    snomask.remove(&letter);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Difference between two mode strings like `+ow` and `+sw`, as `+s-o`.
+fn mode_change(before: &str, after: &str) -> String {
+    let added: String = after.chars().skip(1).filter(|mode| !before.contains(*mode)).collect();
+    let removed: String = before.chars().skip(1).filter(|mode| !after.contains(*mode)).collect();
+    let mut change = String::new();
+    if !added.is_empty() {
+        change.push('+');
+        change.push_str(&added);
+    }
+    if !removed.is_empty() {
+        change.push('-');
+        change.push_str(&removed);
+    }
+    change
+}
+
+fn handle_wallops(client_id: usize, text: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&config, &nick, oper.as_deref(), "wallops") {
+        return Ok(vec![(client_id, denied)]);
+    }
+    let users = shared_state.users.lock().unwrap();
+    let sender = users.get(&client_id).map(|user| user.mask()).unwrap_or(nick);
+    Ok(users.values()
+        .filter(|user| user.registered && user.wallops)
+        .map(|user| (user.id, format!(":{} WALLOPS :{}", sender, text)))
+        .collect())
+}
+
+/// DIE and RESTART. Every connection, including the requester's, is closed with an ERROR.
+fn handle_die(client_id: usize, kind: ShutdownKind, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&config, &nick, oper.as_deref(), "die") {
+        return Ok(vec![(client_id, denied)]);
+    }
+    let command = if kind == ShutdownKind::Restart { "RESTART" } else { "DIE" };
+    if !shared_state.shutdown.request(kind, format!("{} by {}", command, nick)) {
+        return Ok(vec![(client_id, format!(":{} NOTICE {} :*** The server is already shutting down", config.server.name, nick))]);
+    }
+    Ok(Vec::new())
+}
+
+fn handle_kill(client_id: usize, target: String, reason: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&config, &nick, oper.as_deref(), "kill") {
+        return Ok(vec![(client_id, denied)]);
+    }
+    let reason = reason.filter(|reason| !reason.is_empty()).unwrap_or_else(|| nick.clone());
+
+    let mut channels = shared_state.channels.lock().unwrap();
+    let mut users = shared_state.users.lock().unwrap();
+    let target = match find_nick(&users, &target) {
+        Some(user) => user,
+        None => return Ok(vec![(client_id, format!(":{} 401 {} {} :No such nick/channel", config.server.name, nick, target))]),
+    };
+    let (target_id, target_nick) = (target.id, target.reply_nick());
+    log::info!("{} killed {}: {}", nick, target_nick, reason);
+
+    let mut responses = vec![(target_id, format!(":{} KILL {} :{}", nick, target_nick, reason))];
+    responses.extend(disconnect_user(target_id, &format!("Killed ({} ({}))", nick, reason), &config, &mut users, &mut channels));
+    responses.extend(server_notices(&config, &users, 'k', &format!("{} killed {} ({})", nick, target_nick, reason)));
+    Ok(responses)
+}
+
+async fn handle_add_ban(client_id: usize, kind: BanKind, minutes: Option<u64>, mask: String, reason: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&config, &nick, oper.as_deref(), "ban") {
+        return Ok(vec![(client_id, denied)]);
+    }
+    let reason = reason.filter(|reason| !reason.is_empty()).unwrap_or_else(|| "No reason".to_string());
+    // A duration of 0 minutes is permanent
+    let minutes = minutes.filter(|minutes| *minutes > 0);
+    let length = match minutes {
+        Some(minutes) => match i64::try_from(minutes).ok().and_then(chrono::Duration::try_minutes) {
+            Some(length) => Some(length),
+            None => return Ok(vec![(client_id, format!(":{} NOTICE {} :*** {} duration is too long", server, nick, kind.name()))]),
+        },
+        None => None,
+    };
+    let ban = match Ban::new(kind, &mask, &reason, &nick, length) {
+        Ok(ban) => ban,
+        Err(e) => return Ok(vec![(client_id, format!(":{} NOTICE {} :*** {}", server, nick, e))]),
+    };
+
+    let duration = minutes.map(|minutes| format!(" for {} minutes", minutes)).unwrap_or_default();
+    let mut responses = vec![(client_id, format!(":{} NOTICE {} :*** Added {} on {}{} ({})", server, nick, kind.name(), ban.mask, duration, ban.reason))];
+    let added = format!("{} added {} on {}{} ({})", nick, kind.name(), ban.mask, duration, ban.reason);
+    log::info!("{}", added);
+    // The ban applies at once; the file is written off the runtime
+    let saved = shared_state.bans.lock().unwrap().add(ban.clone());
+    if let Err(e) = saved.wait().await {
+        log::error!("Cannot save bans: {}", e);
+        responses.push((client_id, format!(":{} NOTICE {} :*** The {} is active but could not be saved: {}", server, nick, kind.name(), e)));
+    }
+
+    // Remove matching users right away
+    let mut channels = shared_state.channels.lock().unwrap();
+    let mut users = shared_state.users.lock().unwrap();
+    let banned: Vec<usize> = users.values()
+        .filter(|user| match kind {
+            BanKind::UserHost if user.kline_exempt => false,
+            BanKind::UserHost => user.username.as_deref().map(|username| ban.matches_user(username, user.host, user.hostname.as_deref())).unwrap_or(false),
+            BanKind::Ip => ban.matches_ip(user.host),
+            BanKind::Nick => user.nickname.as_deref().map(|nick| ban.matches_nick(nick)).unwrap_or(false),
+        })
+        .map(|user| user.id)
+        .collect();
+    let quit_message = format!("{}: {}", kind.action(), ban.reason);
+    for &target_id in &banned {
+        responses.extend(disconnect_user(target_id, &quit_message, &config, &mut users, &mut channels));
+    }
+    responses.extend(server_notices(&config, &users, 'x', &added));
+    if !banned.is_empty() && users.contains_key(&client_id) {
+        responses.push((client_id, format!(":{} NOTICE {} :*** Disconnected {} matching user(s)", server, nick, banned.len())));
+    }
+    Ok(responses)
+}
+
+async fn handle_remove_ban(client_id: usize, kind: BanKind, mask: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, oper) = requester(client_id, shared_state)?;
+    if let Some(denied) = privilege_denied(&config, &nick, oper.as_deref(), "ban") {
+        return Ok(vec![(client_id, denied)]);
+    }
+    let removed = shared_state.bans.lock().unwrap().remove(kind, &mask);
+    let removed = match removed {
+        Some(saved) => Some(saved.wait().await),
+        None => None,
+    };
+    let mut responses = Vec::new();
+    let notice = match removed {
+        Some(Ok(())) => {
+            let removed = format!("{} removed {} on {}", nick, kind.name(), mask);
+            log::info!("{}", removed);
+            responses.extend(server_notices(&config, &shared_state.users.lock().unwrap(), 'x', &removed));
+            format!("*** Removed {} on {}", kind.name(), mask)
+        }
+        None => format!("*** No {} on {}", kind.name(), mask),
+        Some(Err(e)) => {
+            log::error!("Cannot save bans: {}", e);
+            format!("*** Removed {} on {} but could not save the change: {}", kind.name(), mask, e)
+        }
+    };
+    responses.insert(0, (client_id, format!(":{} NOTICE {} :{}", server, nick, notice)));
+    Ok(responses)
+}
+
+fn handle_stats(client_id: usize, query: String, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, oper) = requester(client_id, shared_state)?;
+    let letter = query.chars().next().unwrap_or('*');
+    let required = match letter {
+        'o' | 'i' | 'y' => Some("stats"),
+        'k' => Some("ban"),
+        _ => None,
+    };
+    if let Some(denied) = required.and_then(|privilege| privilege_denied(&config, &nick, oper.as_deref(), privilege)) {
+        return Ok(vec![(client_id, denied)]);
+    }
+    // Opers with the stats privilege see every connection
+    let see_all = oper.as_deref().map(|oper| config.oper_has_privilege(oper, "stats")).unwrap_or(false);
+
+    let mut lines = Vec::new();
+    match letter {
+        'u' => {
+            let uptime = (chrono::Utc::now() - shared_state.stats.started).num_seconds();
+            lines.push(format!(
+                ":{} 242 {} :Server Up {} days {}:{:02}:{:02}",
+                server, nick, uptime / 86400, uptime % 86400 / 3600, uptime % 3600 / 60, uptime % 60
+            ));
+        }
+        'l' => {
+            let users = shared_state.users.lock().unwrap();
+            let mut links: Vec<&User> = users.values().filter(|user| see_all || user.id == client_id).collect();
+            links.sort_by_key(|user| user.reply_nick());
+            for user in links {
+                let (sent_messages, sent_bytes) = user.traffic.sent();
+                let (received_messages, received_bytes) = user.traffic.received();
+                lines.push(format!(
+                    ":{} 211 {} {}[{}] {} {} {} {} {} {} {}",
+                    server,
+                    nick,
+                    user.reply_nick(),
+                    user.host,
+                    user.traffic.queued(),
+                    sent_messages,
+                    sent_bytes / 1024,
+                    received_messages,
+                    received_bytes / 1024,
+                    (chrono::Utc::now() - user.traffic.opened).num_seconds(),
+                    user.class
+                ));
+            }
+        }
+        'm' => {
+            for (command, usage) in shared_state.stats.command_usage() {
+                lines.push(format!(":{} 212 {} {} {} {} 0", server, nick, command, usage.count, usage.bytes));
+            }
+        }
+        'y' => {
+            let mut classes = config.classes.clone();
+            if !classes.iter().any(|class| class.name == DEFAULT_CLASS) {
+                classes.push(ClassConfig::default());
+            }
+            for class in classes {
+                lines.push(format!(":{} 218 {} Y {} {} 0 {} {}", server, nick, class.name, class.ping_frequency, class.max_clients, class.sendq));
+            }
+        }
+        'i' => {
+            for auth in &config.auth_blocks {
+                let password = if auth.password().is_some() { "<password>" } else { "*" };
+                for mask in &auth.masks {
+                    lines.push(format!(":{} 215 {} I {} {} {} 0 {}", server, nick, mask, password, auth.spoof.as_deref().unwrap_or("*"), auth.class));
+                }
+            }
+        }
+        'o' => {
+            for oper in &config.opers {
+                for host in &oper.hosts {
+                    lines.push(format!(":{} 243 {} O {} * {}", server, nick, host, oper.name));
+                }
+            }
+        }
+        'k' => {
+            let now = chrono::Utc::now();
+            for ban in shared_state.bans.lock().unwrap().active() {
+                let remaining = ban.expires.map(|expires| (expires - now).num_seconds().max(1)).unwrap_or(0);
+                lines.push(format!(":{} 216 {} {} {} {} {} :{}", server, nick, ban.kind.letter(), ban.mask, remaining, ban.set_by, ban.reason));
+            }
+        }
+        _ => {}
+    }
+    lines.push(format!(":{} 219 {} {} :End of /STATS report", server, nick, letter));
+    Ok(lines.into_iter().map(|line| (client_id, line)).collect())
+}
+
+fn handle_help(client_id: usize, topic: Option<String>, shared_state: &SharedState) -> Result<Vec<(usize, String)>, String> {
+    let config = shared_state.config();
+    let server = &config.server.name;
+    let (nick, _) = requester(client_id, shared_state)?;
+    let topic = topic.unwrap_or_else(|| "index".to_string()).to_lowercase();
+
+    let text = match config.help.get(&topic) {
+        Some(text) => text,
+        None => return Ok(vec![(client_id, format!(":{} 524 {} {} :No help available on this topic", server, nick, topic))]),
+    };
+
+    let mut lines: Vec<&str> = text.lines().map(|line| line.trim_end()).collect();
+    if lines.is_empty() {
+        lines.push("");
     }
+    let mut replies = vec![(client_id, format!(":{} 704 {} {} :{}", server, nick, topic, lines[0]))];
+    replies.extend(lines[1..].iter().map(|line| (client_id, format!(":{} 705 {} {} :{}", server, nick, topic, line))));
+    replies.push((client_id, format!(":{} 706 {} {} :End of /HELP", server, nick, topic)));
+    Ok(replies)
 }
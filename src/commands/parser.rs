@@ -1,4 +1,9 @@
 
+use std::collections::HashMap;
+use crate::models::ban::BanKind;
+
+/// IRCv3 message tags sent by the client, with escaped values decoded.
+pub type Tags = HashMap<String, String>;
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
@@ -7,6 +12,7 @@ pub enum Command {
     Join(String),
     Part(String),
     PrivMsg(String, String),
+    Notice(String, String),
     Quit(Option<String>),
     Ping(String),
     Pong(String),
@@ -19,16 +25,96 @@ pub enum Command {
     Who(String),
     WhoisUser(String),
     // TODO: Implement handlers for the following commands
+    #[allow(dead_code)]
     WhoisServer(String),
+    #[allow(dead_code)]
     WhoisOperator(String),
+    #[allow(dead_code)]
     WhoisIdle(String),
+    #[allow(dead_code)]
     WhoisChannels(String),
+    #[allow(dead_code)]
     WhoisAuth(String),
     Whowas(String, Option<String>, Option<String>),
     Cap(String, Option<String>),
+    Authenticate(String),
+    ChatHistory(String, Vec<String>),
+    Rehash,
+    Motd,
+    Lusers,
+    Version,
+    Time,
+    Admin,
+    Info,
+    Stats(String),
+    Help(Option<String>),
+    Oper(String, String),
+    Kill(String, Option<String>),
+    /// Connection password sent before registration.
+    Pass(String),
+    Wallops(String),
+    Die,
+    Restart,
+    /// Adds a server ban: kind, duration in minutes, mask and reason.
+    AddBan(BanKind, Option<u64>, String, Option<String>),
+    RemoveBan(BanKind, String),
+}
+
+/// Parses a line that may start with `@tags`, returning the tags along with the command.
+pub fn parse_message(input: &str) -> Option<(Tags, Command)> {
+    let mut tags = Tags::new();
+    let mut input = input;
+    if let Some(rest) = input.strip_prefix('@') {
+        let (raw_tags, command) = rest.split_once(' ')?;
+        tags = parse_tags(raw_tags);
+        input = command.trim_start();
+    }
+    parse_command(input).map(|command| (tags, command))
+}
+
+/// Uppercased command word of a line, skipping any tags.
+pub fn command_name(input: &str) -> Option<String> {
+    let input = match input.strip_prefix('@') {
+        Some(rest) => rest.split_once(' ')?.1,
+        None => input,
+    };
+    input.split_whitespace().next().map(|name| name.to_uppercase())
+}
+
+pub fn parse_tags(raw: &str) -> Tags {
+    raw.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape_tag_value(value)),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
 }
 
 pub fn parse_command(input: &str) -> Option<Command> {
+    if input.starts_with('@') {
+        return parse_message(input).map(|(_, command)| command);
+    }
     let mut parts = input.splitn(2, ' ');
     let command = parts.next()?;
     let params = parts.next().unwrap_or("");
@@ -51,6 +137,12 @@ pub fn parse_command(input: &str) -> Option<Command> {
             let message = msg_parts.next()?.to_string();
             Some(Command::PrivMsg(target, message))
         }
+        "NOTICE" => {
+            let mut msg_parts = params.splitn(2, ':');
+            let target = msg_parts.next()?.trim().to_string();
+            let message = msg_parts.next()?.to_string();
+            Some(Command::Notice(target, message))
+        }
         "QUIT" => Some(Command::Quit(if params.is_empty() { None } else { Some(params.trim_start_matches(':').to_string()) })),
         "PING" => Some(Command::Ping(params.to_string())),
         "PONG" => Some(Command::Pong(params.to_string())),
@@ -101,6 +193,88 @@ pub fn parse_command(input: &str) -> Option<Command> {
                 cap_parts.next().map(|s| s.to_string()),
             ))
         }
+        "AUTHENTICATE" => {
+            let payload = params.trim();
+            if payload.is_empty() {
+                return None;
+            }
+            Some(Command::Authenticate(payload.to_string()))
+        }
+        "CHATHISTORY" => {
+            let mut history_parts = params.split_whitespace();
+            Some(Command::ChatHistory(
+                history_parts.next()?.to_uppercase(),
+                history_parts.map(|s| s.to_string()).collect(),
+            ))
+        }
+        "REHASH" => Some(Command::Rehash),
+        "MOTD" => Some(Command::Motd),
+        "LUSERS" => Some(Command::Lusers),
+        // The optional target server is always this one
+        "VERSION" => Some(Command::Version),
+        "TIME" => Some(Command::Time),
+        "ADMIN" => Some(Command::Admin),
+        "INFO" => Some(Command::Info),
+        "STATS" => Some(Command::Stats(params.split_whitespace().next().unwrap_or_default().to_string())),
+        "OPER" => {
+            let mut oper_parts = params.split_whitespace();
+            Some(Command::Oper(
+                oper_parts.next()?.to_string(),
+                oper_parts.next()?.trim_start_matches(':').to_string(),
+            ))
+        }
+        "PASS" => {
+            let password = match params.strip_prefix(':') {
+                Some(password) => password,
+                None => params.split(' ').next().unwrap_or_default(),
+            };
+            if password.is_empty() {
+                return None;
+            }
+            Some(Command::Pass(password.to_string()))
+        }
+        "KILL" => {
+            let mut kill_parts = params.splitn(2, ' ');
+            let target = kill_parts.next().filter(|target| !target.is_empty())?.to_string();
+            let reason = kill_parts.next().map(|s| s.trim_start_matches(':').to_string());
+            Some(Command::Kill(target, reason))
+        }
+        "WALLOPS" => {
+            let text = params.trim_start_matches(':');
+            if text.is_empty() {
+                return None;
+            }
+            Some(Command::Wallops(text.to_string()))
+        }
+        "DIE" => Some(Command::Die),
+        "RESTART" => Some(Command::Restart),
+        "KLINE" => parse_add_ban(BanKind::UserHost, params),
+        "DLINE" => parse_add_ban(BanKind::Ip, params),
+        "QLINE" => parse_add_ban(BanKind::Nick, params),
+        "UNKLINE" => parse_remove_ban(BanKind::UserHost, params),
+        "UNDLINE" => parse_remove_ban(BanKind::Ip, params),
+        "UNQLINE" => parse_remove_ban(BanKind::Nick, params),
+        "HELP" => Some(Command::Help(params.split_whitespace().next().map(|topic| topic.to_string()))),
         _ => None,
     }
 }
+
+/// Parses `[minutes] <mask> [:reason]`.
+fn parse_add_ban(kind: BanKind, params: &str) -> Option<Command> {
+    let (params, reason) = match params.split_once(" :") {
+        Some((params, reason)) => (params, Some(reason.to_string())),
+        None => (params, None),
+    };
+    let mut ban_parts = params.split_whitespace();
+    let mut mask = ban_parts.next()?;
+    let mut duration = None;
+    if let Ok(minutes) = mask.parse::<u64>() {
+        duration = Some(minutes);
+        mask = ban_parts.next()?;
+    }
+    Some(Command::AddBan(kind, duration, mask.to_string(), reason))
+}
+
+fn parse_remove_ban(kind: BanKind, params: &str) -> Option<Command> {
+    params.split_whitespace().next().map(|mask| Command::RemoveBan(kind, mask.to_string()))
+}
@@ -0,0 +1,916 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::LevelFilter;
+use serde::Deserialize;
+use tokio::sync::watch;
+use crate::models::history::Retention;
+use crate::models::help::HelpPages;
+use crate::models::motd::Motd;
+use crate::models::user::DEFAULT_NICK_LENGTH;
+use crate::server::codec::EncodingFallback;
+use crate::server::flood;
+use crate::utils::{is_host_mask, is_ip_mask, is_password_hash, is_valid_host, user_host_matches};
+
+/// Software version reported in 002, 004 and VERSION.
+pub const VERSION: &str = concat!("rustirc2-", env!("CARGO_PKG_VERSION"));
+
+/// Privileges a privilege set can grant. Each oper-only command checks one of them.
+pub const PRIVILEGES: &[&str] = &["ban", "die", "kill", "rehash", "sajoin", "see-invisible", "stats", "wallops"];
+
+/// Class used for connections that no other class applies to.
+pub const DEFAULT_CLASS: &str = "default";
+
+/// Exemptions an auth block can grant.
+pub const AUTH_EXEMPTIONS: &[&str] = &["flood", "kline"];
+
+/// Longest history retention, a century.
+pub const MAX_HISTORY_AGE_DAYS: i64 = 36500;
+
+/// End of the problem reported for a password hash `hash_password` didn't make.
+const NOT_A_PASSWORD_HASH: &str = "is not an Argon2 hash; make one with --hash-password";
+
+/// Server configuration, read from a TOML file. Every section is optional; missing
+/// values fall back to the defaults below.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerInfo,
+    pub admin: AdminInfo,
+    #[serde(rename = "listen")]
+    pub listeners: Vec<ListenerConfig>,
+    pub limits: Limits,
+    #[serde(rename = "oper")]
+    pub opers: Vec<OperConfig>,
+    #[serde(rename = "privset")]
+    pub privsets: Vec<PrivilegeSet>,
+    #[serde(rename = "class")]
+    pub classes: Vec<ClassConfig>,
+    #[serde(rename = "auth")]
+    pub auth_blocks: Vec<AuthConfig>,
+    pub logging: LoggingConfig,
+    pub accounts: AccountsConfig,
+    pub history: HistoryConfig,
+    pub bans: BansConfig,
+    pub dns: DnsConfig,
+    pub cloak: CloakConfig,
+    /// Contents of `server.motd`, read along with the config.
+    #[serde(skip)]
+    pub motd: Option<Motd>,
+    /// Built-in HELP pages along with those in `server.help_dir`, read with the config.
+    #[serde(skip)]
+    pub help: HelpPages,
+}
+
+/// Identity of this server as shown to clients.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerInfo {
+    pub name: String,
+    pub network: String,
+    pub description: String,
+    pub motd: Option<PathBuf>,
+    /// Directory with one file per HELP topic, adding to or replacing the built-in pages.
+    pub help_dir: Option<PathBuf>,
+}
+
+/// Contact details returned by ADMIN.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminInfo {
+    pub location: String,
+    pub organization: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub tls: Option<TlsFiles>,
+    /// Server password clients connecting here must send with PASS, unless their auth
+    /// block has a password of its own. Stored hashed like oper passwords.
+    pub password_hash: Option<String>,
+    /// IP addresses, CIDR ranges or wildcards allowed to connect; empty allows everyone.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Expect a PROXY protocol header (v1 or v2) giving the real client address.
+    #[serde(default)]
+    pub proxy: bool,
+    /// Addresses, CIDR ranges or wildcards of the proxies allowed to send that header,
+    /// or X-Forwarded-For on a WebSocket listener.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Serve IRC over WebSocket instead of plain TCP.
+    #[serde(default)]
+    pub websocket: bool,
+    /// Origins, with wildcards, browsers may open WebSocket connections from; empty
+    /// allows any.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Host name given to clients of a Unix socket listener, which have no address of
+    /// their own. Auth blocks can match it to give local clients a class.
+    pub client_host: Option<String>,
+}
+
+/// Where a listener accepts connections, as written in its `address`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddress {
+    /// `IP:PORT`
+    Tcp(SocketAddr),
+    /// `unix:PATH`
+    Unix(PathBuf),
+    /// `systemd:N`, the Nth socket passed by systemd socket activation, counting from 0.
+    Systemd(usize),
+}
+
+impl ListenAddress {
+    pub fn parse(address: &str) -> Option<ListenAddress> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Some(path).filter(|path| !path.is_empty()).map(|path| ListenAddress::Unix(PathBuf::from(path)));
+        }
+        if let Some(index) = address.strip_prefix("systemd:") {
+            return index.parse().ok().map(ListenAddress::Systemd);
+        }
+        address.parse().ok().map(ListenAddress::Tcp)
+    }
+}
+
+impl ListenerConfig {
+    /// Host of clients on a Unix socket.
+    pub fn client_host(&self) -> &str {
+        self.client_host.as_deref().unwrap_or("localhost")
+    }
+
+    /// Hash of the server password, if one is required.
+    pub fn password(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub nick_length: usize,
+    pub channel_length: usize,
+    pub topic_length: usize,
+    /// Seconds clients get to be disconnected on shutdown before state is saved anyway.
+    pub shutdown_timeout: u64,
+    /// IP addresses, CIDR ranges or wildcards never subject to flood protection.
+    pub flood_exempt: Vec<String>,
+    /// Connections the server accepts in total, registered or not.
+    pub max_clients: usize,
+    /// Concurrent connections allowed from one IP address; 0 for no limit.
+    pub ip_connections: usize,
+    /// Concurrent connections allowed from one network block; 0 for no limit.
+    pub cidr_connections: usize,
+    /// Prefix lengths of the network blocks counted by `cidr_connections`.
+    pub ipv4_cidr: u8,
+    pub ipv6_cidr: u8,
+    /// Connection attempts one network block may make within `throttle_seconds`; 0 disables
+    /// throttling.
+    pub throttle_connections: usize,
+    pub throttle_seconds: u64,
+    /// IP addresses, CIDR ranges or wildcards exempt from all connection limits.
+    pub connection_exempt: Vec<String>,
+    /// Bytes a received line may have, CRLF included and message tags not; longer
+    /// lines get 417.
+    pub line_length: usize,
+    /// How lines that aren't valid UTF-8 are read: "latin1" or "replace".
+    pub encoding_fallback: String,
+}
+
+/// An operator login. The password is stored like account passwords, as an Argon2 hash
+/// made with --hash-password.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperConfig {
+    pub name: String,
+    pub password_hash: String,
+    /// `user@host` masks the oper may log in from.
+    #[serde(default = "default_oper_hosts")]
+    pub hosts: Vec<String>,
+    /// Client certificate fingerprint the oper must also connect with, if set.
+    pub certfp: Option<String>,
+    /// Name of the privilege set granted on login.
+    pub privset: String,
+    pub class: Option<String>,
+}
+
+/// Named group of privileges granted to oper blocks.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegeSet {
+    pub name: String,
+    pub privileges: Vec<String>,
+}
+
+/// Settings shared by a group of connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassConfig {
+    pub name: String,
+    #[serde(default = "default_class_max_clients")]
+    pub max_clients: usize,
+    /// Seconds of silence before the server sends a PING.
+    #[serde(default = "default_class_ping_frequency")]
+    pub ping_frequency: u64,
+    /// Command cost a client may spend at once before its lines are delayed.
+    #[serde(default = "default_class_flood_burst")]
+    pub flood_burst: f64,
+    /// Command cost refilled per second.
+    #[serde(default = "default_class_flood_rate")]
+    pub flood_rate: f64,
+    /// Lines a delayed client may have waiting before it is disconnected for Excess Flood.
+    #[serde(default = "default_class_flood_queue")]
+    pub flood_queue: usize,
+    /// Bytes that may wait to be written to a client before it is dropped as too slow.
+    #[serde(default = "default_class_sendq")]
+    pub sendq: usize,
+    /// Bytes of received lines that may wait to be handled before Excess Flood.
+    #[serde(default = "default_class_recvq")]
+    pub recvq: usize,
+    /// Channels a user may be in at once.
+    #[serde(default = "default_class_max_channels")]
+    pub max_channels: usize,
+}
+
+/// Auth block (I-line): puts registering clients that match one of `masks` in a class.
+/// The first matching block applies, and if it has a password the client must send it
+/// with PASS. Clients no block matches get the default class.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `user@host` masks, where the host is an IP address, CIDR range or wildcard, or a
+    /// host name mask matched once the client's host name is resolved.
+    pub masks: Vec<String>,
+    /// Password to send with PASS, hashed like the listener's.
+    pub password_hash: Option<String>,
+    #[serde(default = "default_auth_class")]
+    pub class: String,
+    /// Host shown instead of the client's address.
+    pub spoof: Option<String>,
+    /// Protections the matching clients skip, from `AUTH_EXEMPTIONS`.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn matches(&self, username: &str, ip: IpAddr, hostname: Option<&str>) -> bool {
+        self.masks.iter().any(|mask| user_host_matches(mask, username, ip, hostname))
+    }
+
+    pub fn exempts(&self, exemption: &str) -> bool {
+        self.exempt.iter().any(|granted| granted == exemption)
+    }
+
+    /// Hash of the password matching clients must send, if any.
+    pub fn password(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+}
+
+/// Settings for connections whose class is not configured.
+impl Default for ClassConfig {
+    fn default() -> Self {
+        ClassConfig {
+            name: DEFAULT_CLASS.to_string(),
+            max_clients: default_class_max_clients(),
+            ping_frequency: default_class_ping_frequency(),
+            flood_burst: default_class_flood_burst(),
+            flood_rate: default_class_flood_rate(),
+            flood_queue: default_class_flood_queue(),
+            sendq: default_class_sendq(),
+            recvq: default_class_recvq(),
+            max_channels: default_class_max_channels(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub file: PathBuf,
+    pub limit: usize,
+    /// Days messages are kept, 0 to keep them indefinitely.
+    pub max_age_days: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BansConfig {
+    /// File K-, D- and Q-lines are kept in across restarts.
+    pub file: PathBuf,
+}
+
+/// Host name lookups for connecting clients. A name is only used if it resolves back
+/// to the client's address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub resolve: bool,
+    /// Seconds to wait for the lookups before using the address.
+    pub timeout: u64,
+}
+
+/// Host cloaking with user mode +x, available once a key is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CloakConfig {
+    /// Secret the cloaks are derived from; changing it changes every cloak.
+    pub key: Option<String>,
+    /// Whether clients get +x when they connect.
+    pub automatic: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerInfo::default(),
+            admin: AdminInfo::default(),
+            listeners: vec![ListenerConfig { address: "0.0.0.0:6667".to_string(), ..Default::default() }],
+            limits: Limits::default(),
+            opers: Vec::new(),
+            privsets: Vec::new(),
+            classes: Vec::new(),
+            auth_blocks: Vec::new(),
+            logging: LoggingConfig::default(),
+            accounts: AccountsConfig::default(),
+            history: HistoryConfig::default(),
+            bans: BansConfig::default(),
+            dns: DnsConfig::default(),
+            cloak: CloakConfig::default(),
+            motd: None,
+            help: HelpPages::default(),
+        }
+    }
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        ServerInfo {
+            name: "server".to_string(),
+            network: "rustirc2".to_string(),
+            description: "rustirc2".to_string(),
+            motd: None,
+            help_dir: None,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            nick_length: DEFAULT_NICK_LENGTH,
+            channel_length: 50,
+            topic_length: 390,
+            shutdown_timeout: 10,
+            flood_exempt: Vec::new(),
+            max_clients: 1024,
+            ip_connections: 10,
+            cidr_connections: 30,
+            ipv4_cidr: 24,
+            ipv6_cidr: 64,
+            throttle_connections: 10,
+            throttle_seconds: 60,
+            connection_exempt: Vec::new(),
+            line_length: 512,
+            encoding_fallback: "latin1".to_string(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { level: "info".to_string() }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        let retention = Retention::default();
+        HistoryConfig {
+            file: PathBuf::from("history.log"),
+            limit: retention.max_messages,
+            max_age_days: retention.max_age.map(|age| age.num_days()).unwrap_or(0),
+        }
+    }
+}
+
+impl Default for BansConfig {
+    fn default() -> Self {
+        BansConfig { file: PathBuf::from("bans.txt") }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig { resolve: false, timeout: 5 }
+    }
+}
+
+impl Default for CloakConfig {
+    fn default() -> Self {
+        CloakConfig { key: None, automatic: true }
+    }
+}
+
+fn default_oper_hosts() -> Vec<String> {
+    vec!["*@*".to_string()]
+}
+
+fn default_class_max_clients() -> usize {
+    1024
+}
+
+fn default_class_ping_frequency() -> u64 {
+    120
+}
+
+fn default_class_flood_burst() -> f64 {
+    10.0
+}
+
+fn default_class_flood_rate() -> f64 {
+    1.0
+}
+
+fn default_class_flood_queue() -> usize {
+    20
+}
+
+fn default_class_sendq() -> usize {
+    1_048_576
+}
+
+fn default_class_recvq() -> usize {
+    8192
+}
+
+fn default_class_max_channels() -> usize {
+    20
+}
+
+fn default_auth_class() -> String {
+    DEFAULT_CLASS.to_string()
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    /// Every problem found while validating, so they can all be fixed in one go.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Cannot parse {}: {}", path.display(), e.trim_end()),
+            ConfigError::Invalid(problems) => write!(f, "Invalid configuration: {}", problems.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads and validates a config file.
+    #[allow(dead_code)]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut config = Config::read(path)?;
+        config.validate()?;
+        config.load_motd()?;
+        config.load_help()?;
+        Ok(config)
+    }
+
+    /// Reads a config file without validating it, so overrides can be applied first.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let name = &self.server.name;
+        if !is_valid_host(name) {
+            problems.push(format!("server.name {:?} must be a hostname", name));
+        }
+        if self.server.network.is_empty() || self.server.network.contains(' ') {
+            problems.push(format!("server.network {:?} must be a single word", self.server.network));
+        }
+
+        if self.listeners.is_empty() {
+            problems.push("at least one [[listen]] block is required".to_string());
+        }
+        let mut addresses = HashSet::new();
+        for listener in &self.listeners {
+            match ListenAddress::parse(&listener.address) {
+                Some(address) if !addresses.insert(address.clone()) => {
+                    problems.push(format!("listen address {} is configured twice", listener.address));
+                }
+                Some(_) => {}
+                None => problems.push(format!("listen address {:?} must be IP:PORT, unix:PATH or systemd:N", listener.address)),
+            }
+            if let Some(host) = listener.client_host.as_deref().filter(|host| !is_valid_host(host)) {
+                problems.push(format!("listen {} client_host {:?} must be a hostname", listener.address, host));
+            }
+            if let Some(tls) = &listener.tls {
+                for file in [&tls.cert, &tls.key] {
+                    if !file.is_file() {
+                        problems.push(format!("TLS file {} for {} does not exist", file.display(), listener.address));
+                    }
+                }
+            }
+            for mask in listener.allow.iter().filter(|mask| !is_ip_mask(mask)) {
+                problems.push(format!("listen {} allow entry {:?} is not an IP, CIDR range or wildcard", listener.address, mask));
+            }
+            for mask in listener.trusted_proxies.iter().filter(|mask| !is_ip_mask(mask)) {
+                problems.push(format!("listen {} trusted_proxies entry {:?} is not an IP, CIDR range or wildcard", listener.address, mask));
+            }
+            if !listener.origins.is_empty() && !listener.websocket {
+                problems.push(format!("listen {} has origins but is not a websocket listener", listener.address));
+            }
+            if listener.proxy && listener.trusted_proxies.is_empty() {
+                problems.push(format!("listen {} uses proxy but has no trusted_proxies", listener.address));
+            }
+            if listener.password_hash.as_deref().map(|hash| !is_password_hash(hash)).unwrap_or(false) {
+                problems.push(format!("listen {} password_hash {}", listener.address, NOT_A_PASSWORD_HASH));
+            }
+        }
+
+        let limits = &self.limits;
+        if !(1..=64).contains(&limits.nick_length) {
+            problems.push(format!("limits.nick_length must be between 1 and 64, not {}", limits.nick_length));
+        }
+        if !(2..=200).contains(&limits.channel_length) {
+            problems.push(format!("limits.channel_length must be between 2 and 200, not {}", limits.channel_length));
+        }
+        if limits.topic_length == 0 {
+            problems.push("limits.topic_length must be positive".to_string());
+        }
+        if limits.line_length < 512 {
+            problems.push(format!("limits.line_length must be at least 512, not {}", limits.line_length));
+        }
+        if EncodingFallback::parse(&limits.encoding_fallback).is_none() {
+            problems.push(format!("limits.encoding_fallback {:?} must be one of {}", limits.encoding_fallback, EncodingFallback::NAMES.join(", ")));
+        }
+
+        let mut class_names = HashSet::new();
+        for class in &self.classes {
+            if !class_names.insert(class.name.as_str()) {
+                problems.push(format!("class {:?} is defined twice", class.name));
+            }
+            if !(1.0..).contains(&class.flood_burst) {
+                problems.push(format!("class {:?} flood_burst must be at least 1", class.name));
+            }
+            if !(flood::MIN_RATE..).contains(&class.flood_rate) {
+                problems.push(format!("class {:?} flood_rate must be at least {}", class.name, flood::MIN_RATE));
+            }
+            if class.flood_queue == 0 {
+                problems.push(format!("class {:?} flood_queue must be positive", class.name));
+            }
+            if class.sendq < 1024 {
+                problems.push(format!("class {:?} sendq must be at least 1024 bytes", class.name));
+            }
+            if class.recvq < 512 {
+                problems.push(format!("class {:?} recvq must be at least 512 bytes", class.name));
+            }
+            if class.max_channels == 0 {
+                problems.push(format!("class {:?} max_channels must be positive", class.name));
+            }
+        }
+        for mask in self.limits.flood_exempt.iter().filter(|mask| !is_ip_mask(mask)) {
+            problems.push(format!("limits.flood_exempt entry {:?} is not an IP, CIDR range or wildcard", mask));
+        }
+        for mask in self.limits.connection_exempt.iter().filter(|mask| !is_ip_mask(mask)) {
+            problems.push(format!("limits.connection_exempt entry {:?} is not an IP, CIDR range or wildcard", mask));
+        }
+        if self.limits.max_clients == 0 {
+            problems.push("limits.max_clients must be positive".to_string());
+        }
+        if self.limits.ipv4_cidr > 32 || self.limits.ipv6_cidr > 128 {
+            problems.push("limits.ipv4_cidr must be at most 32 and limits.ipv6_cidr at most 128".to_string());
+        }
+        let mut privset_names = HashSet::new();
+        for privset in &self.privsets {
+            if !privset_names.insert(privset.name.as_str()) {
+                problems.push(format!("privset {:?} is defined twice", privset.name));
+            }
+            for privilege in privset.privileges.iter().filter(|p| !PRIVILEGES.contains(&p.as_str())) {
+                problems.push(format!("privset {:?} grants unknown privilege {:?}, expected one of {}", privset.name, privilege, PRIVILEGES.join(", ")));
+            }
+        }
+        let mut oper_names = HashSet::new();
+        for oper in &self.opers {
+            if !oper_names.insert(oper.name.as_str()) {
+                problems.push(format!("oper {:?} is defined twice", oper.name));
+            }
+            if !is_password_hash(&oper.password_hash) {
+                problems.push(format!("oper {:?} password_hash {}", oper.name, NOT_A_PASSWORD_HASH));
+            }
+            if !privset_names.contains(oper.privset.as_str()) {
+                problems.push(format!("oper {:?} uses undefined privset {:?}", oper.name, oper.privset));
+            }
+            if let Some(class) = oper.class.as_deref().filter(|class| !class_names.contains(class)) {
+                problems.push(format!("oper {:?} uses undefined class {:?}", oper.name, class));
+            }
+        }
+        for (number, auth) in self.auth_blocks.iter().enumerate() {
+            let name = format!("auth block {}", number + 1);
+            if auth.masks.is_empty() {
+                problems.push(format!("{} needs at least one mask", name));
+            }
+            for mask in &auth.masks {
+                match mask.split_once('@') {
+                    Some((_, host)) if is_host_mask(host) => {}
+                    _ => problems.push(format!("{} mask {:?} must be user@host with an IP, CIDR range or host name mask", name, mask)),
+                }
+            }
+            if auth.class != DEFAULT_CLASS && !class_names.contains(auth.class.as_str()) {
+                problems.push(format!("{} uses undefined class {:?}", name, auth.class));
+            }
+            if let Some(spoof) = auth.spoof.as_deref().filter(|spoof| !is_valid_host(spoof)) {
+                problems.push(format!("{} spoof {:?} is not a valid host name", name, spoof));
+            }
+            for exemption in auth.exempt.iter().filter(|exemption| !AUTH_EXEMPTIONS.contains(&exemption.as_str())) {
+                problems.push(format!("{} grants unknown exemption {:?}", name, exemption));
+            }
+            if auth.password_hash.as_deref().map(|hash| !is_password_hash(hash)).unwrap_or(false) {
+                problems.push(format!("{} password_hash {}", name, NOT_A_PASSWORD_HASH));
+            }
+        }
+
+        if parse_level(&self.logging.level).is_none() {
+            problems.push(format!("logging.level {:?} must be one of error, warn, info, debug, trace", self.logging.level));
+        }
+        if !(0..=MAX_HISTORY_AGE_DAYS).contains(&self.history.max_age_days) {
+            problems.push(format!("history.max_age_days must be between 0 and {}", MAX_HISTORY_AGE_DAYS));
+        }
+        if self.dns.timeout == 0 {
+            problems.push("dns.timeout must be positive".to_string());
+        }
+        if self.cloak.key.as_deref().map(|key| key.len() < 16).unwrap_or(false) {
+            problems.push("cloak.key must be at least 16 characters".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Reads the MOTD file, so a rehash picks up its changes too.
+    pub fn load_motd(&mut self) -> Result<(), ConfigError> {
+        self.motd = match &self.server.motd {
+            Some(path) => Some(Motd::load(path).map_err(|e| {
+                ConfigError::Invalid(vec![format!("server.motd {} cannot be read: {}", path.display(), e)])
+            })?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Reads the HELP pages in `server.help_dir`, once per load rather than per HELP.
+    pub fn load_help(&mut self) -> Result<(), ConfigError> {
+        self.help = match &self.server.help_dir {
+            Some(dir) => HelpPages::load(dir).map_err(|e| {
+                ConfigError::Invalid(vec![format!("server.help_dir {} cannot be read: {}", dir.display(), e)])
+            })?,
+            None => HelpPages::default(),
+        };
+        Ok(())
+    }
+
+    pub fn oper(&self, name: &str) -> Option<&OperConfig> {
+        self.opers.iter().find(|oper| oper.name == name)
+    }
+
+    /// Whether the oper block `oper` currently grants `privilege`. Looked up on every use,
+    /// so a rehash changes what logged-in opers may do.
+    pub fn oper_has_privilege(&self, oper: &str, privilege: &str) -> bool {
+        self.oper(oper)
+            .and_then(|oper| self.privsets.iter().find(|privset| privset.name == oper.privset))
+            .map(|privset| privset.privileges.iter().any(|p| p == privilege))
+            .unwrap_or(false)
+    }
+
+    /// First auth block matching a registering client.
+    pub fn auth_block(&self, username: &str, ip: IpAddr, hostname: Option<&str>) -> Option<&AuthConfig> {
+        self.auth_blocks.iter().find(|auth| auth.matches(username, ip, hostname))
+    }
+
+    pub fn listener(&self, address: &str) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|listener| listener.address == address)
+    }
+
+    /// Settings of a connection in `class`: the oper block's class for opers that have
+    /// one, falling back to the class named `default`, or built-in settings if that is
+    /// not configured either.
+    pub fn user_class(&self, class: &str, oper: Option<&str>) -> ClassConfig {
+        let name = oper.and_then(|oper| self.oper(oper)).and_then(|oper| oper.class.as_deref()).unwrap_or(class);
+        self.classes.iter()
+            .find(|class| class.name == name)
+            .or_else(|| self.classes.iter().find(|class| class.name == DEFAULT_CLASS))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        parse_level(&self.logging.level).unwrap_or(LevelFilter::Info)
+    }
+
+    pub fn encoding_fallback(&self) -> EncodingFallback {
+        EncodingFallback::parse(&self.limits.encoding_fallback).unwrap_or(EncodingFallback::Latin1)
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            max_messages: self.history.limit,
+            max_age: Some(self.history.max_age_days)
+                .filter(|days| *days > 0)
+                .and_then(chrono::Duration::try_days),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Settings given on the command line, which take precedence over the config file and
+/// are applied again on every rehash.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub bind: Option<String>,
+    pub port: Option<String>,
+    /// TLS port, certificate and key.
+    pub tls: Option<(String, PathBuf, PathBuf)>,
+    pub log_level: Option<String>,
+    pub accounts: Option<PathBuf>,
+    pub history: Option<PathBuf>,
+    pub history_limit: Option<usize>,
+    pub history_max_age_days: Option<i64>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if self.bind.is_some() || self.port.is_some() {
+            let current = config.listeners.iter().find(|l| l.tls.is_none()).map(|l| l.address.clone());
+            let (current_ip, current_port) = current.as_deref()
+                .and_then(|address| address.rsplit_once(':'))
+                .unwrap_or(("0.0.0.0", "6667"));
+            let address = format!(
+                "{}:{}",
+                self.bind.as_deref().unwrap_or(current_ip),
+                self.port.as_deref().unwrap_or(current_port)
+            );
+            config.listeners.retain(|l| l.tls.is_some());
+            config.listeners.insert(0, ListenerConfig { address, ..Default::default() });
+        }
+        if let Some((port, cert, key)) = &self.tls {
+            let ip = config.listeners.first()
+                .and_then(|l| l.address.rsplit_once(':'))
+                .map(|(ip, _)| ip.to_string())
+                .unwrap_or_else(|| "0.0.0.0".to_string());
+            config.listeners.retain(|l| l.tls.is_none());
+            config.listeners.push(ListenerConfig {
+                address: format!("{}:{}", ip, port),
+                tls: Some(TlsFiles { cert: cert.clone(), key: key.clone() }),
+                ..Default::default()
+            });
+        }
+
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        if let Some(path) = &self.accounts {
+            config.accounts.file = Some(path.clone());
+        }
+        if let Some(path) = &self.history {
+            config.history.file = path.clone();
+        }
+        if let Some(limit) = self.history_limit {
+            config.history.limit = limit;
+        }
+        if let Some(days) = self.history_max_age_days {
+            config.history.max_age_days = days;
+        }
+    }
+}
+
+/// The running configuration. A rehash swaps in a new, validated config as a whole, so
+/// readers always see a consistent one, and subscribers are told about the change.
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    overrides: Overrides,
+    current: watch::Sender<Arc<Config>>,
+}
+
+impl ConfigHandle {
+    /// A fixed configuration, as used without a config file.
+    pub fn new(config: Config) -> Self {
+        ConfigHandle {
+            path: None,
+            overrides: Overrides::default(),
+            current: watch::channel(Arc::new(config)).0,
+        }
+    }
+
+    /// Reads `path` if given, applies the overrides and validates the result.
+    pub fn open(path: Option<PathBuf>, overrides: Overrides) -> Result<Self, ConfigError> {
+        let config = build(path.as_deref(), &overrides)?;
+        Ok(ConfigHandle {
+            path,
+            overrides,
+            current: watch::channel(Arc::new(config)).0,
+        })
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.current.borrow())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Receives every config swapped in by `reload`.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.current.subscribe()
+    }
+
+    /// Re-reads the config file. On error the running config stays in place.
+    pub fn reload(&self) -> Result<Arc<Config>, ConfigError> {
+        let config = Arc::new(build(self.path.as_deref(), &self.overrides)?);
+        let previous = self.current.send_replace(Arc::clone(&config));
+        for setting in restart_required(&previous, &config) {
+            log::warn!("Changing {} only takes effect after a restart", setting);
+        }
+        Ok(config)
+    }
+}
+
+fn build(path: Option<&Path>, overrides: &Overrides) -> Result<Config, ConfigError> {
+    let mut config = match path {
+        Some(path) => Config::read(path)?,
+        None => Config::default(),
+    };
+    overrides.apply(&mut config);
+    config.validate()?;
+    config.load_motd()?;
+    config.load_help()?;
+    Ok(config)
+}
+
+/// Settings that are read once at startup.
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut settings = Vec::new();
+    if old.logging.level != new.logging.level {
+        settings.push("logging.level");
+    }
+    if old.accounts.file != new.accounts.file {
+        settings.push("accounts.file");
+    }
+    if old.history.file != new.history.file || old.retention() != new.retention() {
+        settings.push("history");
+    }
+    if old.bans.file != new.bans.file {
+        settings.push("bans.file");
+    }
+    settings
+}
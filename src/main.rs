@@ -1,4 +1,5 @@
 mod utils;
+mod config;
 mod models;
 mod commands;
 mod server;
@@ -6,17 +7,39 @@ mod server;
 #[cfg(test)]
 mod tests;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use env_logger::Env;
-use log::LevelFilter;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use config::{ConfigHandle, Overrides, VERSION};
+use models::account::FileAccountStore;
+use models::ban::BanList;
+use models::history::FileHistoryStore;
+use server::listener::Listeners;
+use server::shutdown::ShutdownKind;
+use server::systemd;
+use tokio::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command-line arguments
     let matches = App::new("IRC Server")
-        .version("1.0")
-        .author("Your Name")
+        .version(env!("CARGO_PKG_VERSION"))
         .about("A simple IRC server")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .help("Reads settings from a TOML config file; other flags override its values")
+            .takes_value(true))
+        .arg(Arg::with_name("check-config")
+            .long("check-config")
+            .help("Validates the configuration and exits"))
+        .arg(Arg::with_name("hash-password")
+            .long("hash-password")
+            .help("Reads a password from standard input, prints a password_hash for the config and exits"))
         .arg(Arg::with_name("bind")
             .short("b")
             .long("bind")
@@ -35,27 +58,160 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .value_name("LEVEL")
             .help("Sets the verbosity level (info, debug, trace)")
             .takes_value(true))
+        .arg(Arg::with_name("accounts")
+            .short("a")
+            .long("accounts")
+            .value_name("FILE")
+            .help("Sets the account file used for SASL authentication")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-port")
+            .long("tls-port")
+            .value_name("PORT")
+            .help("Sets the port to accept TLS connections on, usually 6697")
+            .takes_value(true)
+            .requires_all(&["tls-cert", "tls-key"]))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .help("Sets the PEM certificate chain for the TLS listener")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .value_name("FILE")
+            .help("Sets the PEM private key for the TLS listener")
+            .takes_value(true))
+        .arg(Arg::with_name("history")
+            .long("history")
+            .value_name("FILE")
+            .help("Sets the file message history is stored in (default: history.log)")
+            .takes_value(true))
+        .arg(Arg::with_name("history-limit")
+            .long("history-limit")
+            .value_name("COUNT")
+            .help("Sets how many messages are kept per channel or conversation")
+            .takes_value(true))
+        .arg(Arg::with_name("history-max-age")
+            .long("history-max-age")
+            .value_name("DAYS")
+            .help("Sets how many days messages are kept, 0 to keep them indefinitely")
+            .takes_value(true))
         .get_matches();
 
-    // Set log level based on verbosity flag
-    let log_level = match matches.value_of("verbosity").unwrap_or("info") {
-        "debug" => LevelFilter::Debug,
-        "trace" => LevelFilter::Trace,
-        _ => LevelFilter::Info,
+    if matches.is_present("hash-password") {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        println!("password_hash = \"{}\"", utils::hash_password(password));
+        return Ok(());
+    }
+
+    let config = match ConfigHandle::open(matches.value_of("config").map(PathBuf::from), overrides(&matches)) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
+    if matches.is_present("check-config") {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    let initial = config.get();
 
     // Initialize logging
     env_logger::Builder::from_env(Env::default())
-        .filter_level(log_level)
+        .filter_level(initial.log_level())
         .init();
 
-    // Set bind IP and port
-    let bind_ip = matches.value_of("bind").unwrap_or("0.0.0.0");
-    let port = matches.value_of("port").unwrap_or("6667");
-    let bind_address = format!("{}:{}", bind_ip, port);
+    log::info!("Starting {} as {}", VERSION, initial.server.name);
+
+    let mut shared_state = server::listener::SharedState::with_config(Arc::clone(&config));
+    if let Some(path) = &initial.accounts.file {
+        shared_state.accounts = Arc::new(FileAccountStore::load(path)?);
+        log::info!("Loaded accounts from {}", path.display());
+    }
+    shared_state.history = Arc::new(FileHistoryStore::open(&initial.history.file, initial.retention())?);
+    log::info!("Storing message history in {}", initial.history.file.display());
+    shared_state.bans = Arc::new(Mutex::new(BanList::load(&initial.bans.file)?));
+    log::info!("Loaded bans from {}", initial.bans.file.display());
 
-    log::info!("Starting IRC server on {}", bind_address);
+    let shutdown = Arc::clone(&shared_state.shutdown);
+
+    // Start every configured listener; failing to bind one at startup is fatal
+    let inherited = systemd::listen_fds();
+    if !inherited.is_empty() {
+        log::info!("Received {} socket(s) from systemd", inherited.len());
+    }
+    let listeners = Arc::new(Listeners::new(Arc::new(shared_state)).with_inherited(inherited));
+    let problems = listeners.apply(&initial.listeners).await;
+    if !problems.is_empty() {
+        return Err(problems.join("; ").into());
+    }
+    Arc::clone(&listeners).follow_rehashes();
+
+    // Tell systemd the server is up, when running as a notify service
+    if let Err(e) = systemd::notify("READY=1") {
+        log::warn!("Cannot notify the service manager: {}", e);
+    }
+    systemd::start_watchdog();
+
+    // Rehash on SIGHUP, like the REHASH command, and shut down on SIGINT, SIGTERM,
+    // DIE or RESTART
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (kind, reason) = loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                log::info!("Received SIGHUP, rehashing");
+                if let Err(e) = config.reload() {
+                    log::error!("Rehash failed, keeping the running configuration: {}", e);
+                }
+            }
+            _ = terminate.recv() => {
+                shutdown.request(ShutdownKind::Stop, "Received SIGTERM".to_string());
+            }
+            _ = tokio::signal::ctrl_c() => {
+                shutdown.request(ShutdownKind::Stop, "Received SIGINT".to_string());
+            }
+            request = shutdown.wait() => break request,
+        }
+    };
+
+    log::info!("Shutting down: {}", reason);
+    let _ = systemd::notify("STOPPING=1");
+    listeners.shutdown(Duration::from_secs(config.get().limits.shutdown_timeout)).await;
+    if kind == ShutdownKind::Restart {
+        // Only returns if the new process could not be started
+        let error = std::process::Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .exec();
+        return Err(format!("Cannot restart: {}", error).into());
+    }
+    log::info!("Shutdown complete");
+    Ok(())
+}
+
+/// Command-line flags that override the config file.
+fn overrides(matches: &ArgMatches) -> Overrides {
+    let tls = match (matches.value_of("tls-port"), matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(port), Some(cert), Some(key)) => Some((port.to_string(), cert.into(), key.into())),
+        _ => None,
+    };
+    Overrides {
+        bind: matches.value_of("bind").map(str::to_string),
+        port: matches.value_of("port").map(str::to_string),
+        tls,
+        log_level: matches.value_of("verbosity").map(str::to_string),
+        accounts: matches.value_of("accounts").map(PathBuf::from),
+        history: matches.value_of("history").map(PathBuf::from),
+        history_limit: matches.value_of("history-limit").map(|limit| value_or_exit("history-limit", limit)),
+        history_max_age_days: matches.value_of("history-max-age").map(|days| value_or_exit("history-max-age", days)),
+    }
+}
 
-    // Start the server
-    server::listener::start_server(&bind_address, log_level).await
+fn value_or_exit<T: std::str::FromStr>(flag: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid --{} {:?}", flag, value);
+        std::process::exit(1);
+    })
 }
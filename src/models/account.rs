@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::utils::{check_password, hash_password, is_password_hash};
+
+/// Backend used by SASL to look up accounts.
+pub trait AccountStore: Send + Sync {
+    /// Returns true if `password` is correct for `account`.
+    fn verify_password(&self, account: &str, password: &str) -> bool;
+
+    /// Returns the account that registered the given TLS client certificate fingerprint.
+    fn account_for_certfp(&self, fingerprint: &str) -> Option<String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub name: String,
+    pub password_hash: String,
+    pub certfps: Vec<String>,
+}
+
+impl Account {
+    #[allow(dead_code)]
+    pub fn new(name: String, password: &str) -> Self {
+        Account {
+            name,
+            password_hash: hash_password(password),
+            certfps: Vec::new(),
+        }
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        check_password(password, &self.password_hash)
+    }
+
+    /// Parses a line of the form `name:hash[:certfp,certfp...]`, where the hash is one
+    /// made by --hash-password.
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(3, ':');
+        let name = parts.next()?.trim().to_string();
+        let password_hash = parts.next()?.trim().to_string();
+        let certfps = parts.next()
+            .map(|fps| fps.split(',').map(normalize_certfp).filter(|fp| !fp.is_empty()).collect())
+            .unwrap_or_default();
+        if name.is_empty() || !is_password_hash(&password_hash) {
+            return None;
+        }
+        Some(Account { name, password_hash, certfps })
+    }
+
+    fn to_line(&self) -> String {
+        if self.certfps.is_empty() {
+            format!("{}:{}", self.name, self.password_hash)
+        } else {
+            format!("{}:{}:{}", self.name, self.password_hash, self.certfps.join(","))
+        }
+    }
+}
+
+/// Fingerprints are compared as lowercase hex without separators.
+pub fn normalize_certfp(fingerprint: &str) -> String {
+    fingerprint.trim().chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+/// Account store kept in a plain text file, one account per line.
+#[derive(Debug, Default)]
+pub struct FileAccountStore {
+    path: Option<PathBuf>,
+    accounts: HashMap<String, Account>,
+}
+
+impl FileAccountStore {
+    pub fn new() -> Self {
+        FileAccountStore::default()
+    }
+
+    /// Loads accounts from `path`. A missing file yields an empty store that will be created on save.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = FileAccountStore {
+            path: Some(path.clone()),
+            accounts: HashMap::new(),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Account::parse(line) {
+                Some(account) => {
+                    store.accounts.insert(account.name.to_lowercase(), account);
+                }
+                None => log::warn!("Ignoring malformed account entry on line {} of {}", number + 1, path.display()),
+            }
+        }
+
+        Ok(store)
+    }
+
+    #[allow(dead_code)]
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut accounts: Vec<_> = self.accounts.values().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        let contents: String = accounts.iter().map(|account| account.to_line() + "\n").collect();
+        fs::write(path, contents)
+    }
+
+    #[allow(dead_code)]
+    pub fn register(&mut self, name: &str, password: &str) {
+        self.accounts.insert(name.to_lowercase(), Account::new(name.to_string(), password));
+    }
+
+    #[allow(dead_code)]
+    pub fn add_certfp(&mut self, name: &str, fingerprint: &str) -> Result<(), String> {
+        let account = self.accounts.get_mut(&name.to_lowercase())
+            .ok_or_else(|| format!("Account {} not found", name))?;
+        account.certfps.push(normalize_certfp(fingerprint));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(&name.to_lowercase())
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn verify_password(&self, account: &str, password: &str) -> bool {
+        self.get(account).map(|a| a.check_password(password)).unwrap_or(false)
+    }
+
+    fn account_for_certfp(&self, fingerprint: &str) -> Option<String> {
+        let fingerprint = normalize_certfp(fingerprint);
+        self.accounts.values()
+            .find(|a| a.certfps.contains(&fingerprint))
+            .map(|a| a.name.clone())
+    }
+}
+
+/// In-progress AUTHENTICATE exchange for a single client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaslSession {
+    pub mechanism: String,
+    pub buffer: String,
+}
+
+/// Maximum size of a single AUTHENTICATE chunk; a chunk of exactly this size means more follow.
+pub const SASL_CHUNK_SIZE: usize = 400;
+/// Upper bound on a reassembled AUTHENTICATE payload.
+pub const SASL_MAX_PAYLOAD: usize = 8192;
+
+impl SaslSession {
+    pub fn new(mechanism: String) -> Self {
+        SaslSession {
+            mechanism,
+            buffer: String::new(),
+        }
+    }
+
+    /// Appends a chunk and returns the complete base64 payload once the final chunk arrives.
+    /// `+` stands for an empty chunk.
+    pub fn push_chunk(&mut self, chunk: &str) -> Result<Option<String>, &'static str> {
+        if chunk.len() > SASL_CHUNK_SIZE {
+            return Err("SASL message too long");
+        }
+        if chunk != "+" {
+            self.buffer.push_str(chunk);
+        }
+        if self.buffer.len() > SASL_MAX_PAYLOAD {
+            return Err("SASL message too long");
+        }
+        if chunk.len() == SASL_CHUNK_SIZE {
+            Ok(None)
+        } else {
+            Ok(Some(std::mem::take(&mut self.buffer)))
+        }
+    }
+}
+
+/// Splits a decoded PLAIN payload (`authzid NUL authcid NUL password`) into its parts.
+pub fn parse_plain_payload(payload: &[u8]) -> Option<(String, String, String)> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let mut parts = text.splitn(3, '\0');
+    let authzid = parts.next()?.to_string();
+    let authcid = parts.next()?.to_string();
+    let password = parts.next()?.to_string();
+    if authcid.is_empty() {
+        return None;
+    }
+    Some((authzid, authcid, password))
+}
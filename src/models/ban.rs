@@ -0,0 +1,301 @@
+use chrono::{DateTime, Duration, Utc};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+use crate::utils::{ip_mask_matches, is_ip_mask, user_host_matches, wildcard_match};
+
+/// What a server ban matches against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanKind {
+    /// K-line: `user@host` mask checked at registration.
+    UserHost,
+    /// D-line: IP address, CIDR range or wildcard checked as soon as a connection is accepted.
+    Ip,
+    /// Q-line: reserved nickname mask.
+    Nick,
+}
+
+impl BanKind {
+    pub fn letter(&self) -> &'static str {
+        match self {
+            BanKind::UserHost => "K",
+            BanKind::Ip => "D",
+            BanKind::Nick => "Q",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BanKind::UserHost => "K-line",
+            BanKind::Ip => "D-line",
+            BanKind::Nick => "Q-line",
+        }
+    }
+
+    /// Reason prefix shown when a ban disconnects someone, such as `K-lined`.
+    pub fn action(&self) -> &'static str {
+        match self {
+            BanKind::UserHost => "K-lined",
+            BanKind::Ip => "D-lined",
+            BanKind::Nick => "Q-lined",
+        }
+    }
+
+    fn from_letter(letter: &str) -> Option<Self> {
+        match letter {
+            "K" => Some(BanKind::UserHost),
+            "D" => Some(BanKind::Ip),
+            "Q" => Some(BanKind::Nick),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub mask: String,
+    pub reason: String,
+    pub set_by: String,
+    pub set_at: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    /// Creates a ban, checking the mask and normalizing a bare K-line host to `*@host`.
+    /// `duration` of `None` never expires.
+    pub fn new(kind: BanKind, mask: &str, reason: &str, set_by: &str, duration: Option<Duration>) -> Result<Self, String> {
+        let mask = mask.trim();
+        if mask.is_empty() || mask.contains(char::is_whitespace) {
+            return Err(format!("Invalid {} mask {:?}", kind.name(), mask));
+        }
+        if kind == BanKind::Ip && !is_ip_mask(mask) {
+            return Err(format!("Invalid {} mask {:?}, expected an IP, CIDR range or wildcard", kind.name(), mask));
+        }
+        let mask = normalize_mask(kind, mask);
+        let set_at = Utc::now();
+        let expires = match duration {
+            Some(duration) => Some(set_at.checked_add_signed(duration).ok_or_else(|| format!("{} duration is too long", kind.name()))?),
+            None => None,
+        };
+        Ok(Ban {
+            kind,
+            mask,
+            // Reasons are stored on a single tab-separated line
+            reason: reason.replace(['\t', '\r', '\n'], " "),
+            set_by: set_by.to_string(),
+            set_at,
+            expires,
+        })
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        self.kind == BanKind::Ip && ip_mask_matches(&self.mask, ip)
+    }
+
+    /// Matches K-lines against the real address and host name, never a cloak or spoof.
+    pub fn matches_user(&self, username: &str, ip: IpAddr, hostname: Option<&str>) -> bool {
+        self.kind == BanKind::UserHost && user_host_matches(&self.mask, username, ip, hostname)
+    }
+
+    pub fn matches_nick(&self, nick: &str) -> bool {
+        self.kind == BanKind::Nick && wildcard_match(&self.mask, nick)
+    }
+
+    /// Parses a line of the form `kind\tmask\tset_at\texpires\tset_by\treason`, with
+    /// timestamps in Unix seconds and 0 for no expiry.
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(6, '\t');
+        let kind = BanKind::from_letter(fields.next()?)?;
+        let mask = fields.next()?.to_string();
+        let set_at = DateTime::from_timestamp(fields.next()?.parse().ok()?, 0)?;
+        let expires = match fields.next()?.parse().ok()? {
+            0 => None,
+            seconds => Some(DateTime::from_timestamp(seconds, 0)?),
+        };
+        let set_by = fields.next()?.to_string();
+        let reason = fields.next()?.to_string();
+        if mask.is_empty() {
+            return None;
+        }
+        Some(Ban { kind, mask, reason, set_by, set_at, expires })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.kind.letter(),
+            self.mask,
+            self.set_at.timestamp(),
+            self.expires.map(|expires| expires.timestamp()).unwrap_or(0),
+            self.set_by,
+            self.reason,
+        )
+    }
+}
+
+fn normalize_mask(kind: BanKind, mask: &str) -> String {
+    match kind {
+        BanKind::UserHost if !mask.contains('@') => format!("*@{}", mask),
+        _ => mask.to_string(),
+    }
+}
+
+/// Pending write of the ban file, resolving once it is on disk.
+#[derive(Debug)]
+pub struct BanSave(Option<oneshot::Receiver<io::Result<()>>>);
+
+impl BanSave {
+    pub async fn wait(self) -> io::Result<()> {
+        match self.0 {
+            Some(outcome) => outcome.await.unwrap_or_else(|_| Err(io::Error::other("the ban writer has stopped"))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Thread that owns the ban file, so saving never blocks the runtime. Only the newest
+/// of the snapshots queued while it was busy is written.
+#[derive(Debug)]
+struct BanWriter {
+    queue: Option<mpsc::Sender<(String, oneshot::Sender<io::Result<()>>)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BanWriter {
+    fn start(path: PathBuf) -> io::Result<Self> {
+        let (queue, writes) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("ban-writer".to_string())
+            .spawn(move || write_bans(&path, writes))?;
+        Ok(BanWriter { queue: Some(queue), thread: Some(thread) })
+    }
+
+    fn send(&self, contents: String) -> BanSave {
+        let (done, outcome) = oneshot::channel();
+        if let Some(queue) = &self.queue {
+            // Only fails once the thread is gone, which the dropped sender reports
+            let _ = queue.send((contents, done));
+        }
+        BanSave(Some(outcome))
+    }
+}
+
+impl Drop for BanWriter {
+    /// Waits for everything queued to be written.
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_bans(path: &Path, writes: mpsc::Receiver<(String, oneshot::Sender<io::Result<()>>)>) {
+    while let Ok(write) = writes.recv() {
+        let mut pending: Vec<_> = std::iter::once(write).chain(writes.try_iter()).collect();
+        let contents = pending.last_mut().map(|(contents, _)| std::mem::take(contents)).unwrap_or_default();
+        let tmp_path = path.with_extension("tmp");
+        let result = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = &result {
+            log::error!("Failed to save bans to {}: {}", path.display(), e);
+        }
+        for (_, done) in pending {
+            let _ = done.send(result.as_ref().map(|_| ()).map_err(|e| io::Error::new(e.kind(), e.to_string())));
+        }
+    }
+}
+
+/// Server bans, optionally kept in a file so they survive restarts. Lookups skip expired
+/// bans, which are left out of the file the next time it is written.
+#[derive(Debug, Default)]
+pub struct BanList {
+    writer: Option<BanWriter>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    /// Loads bans from `path`. A missing file yields an empty list that will be created on save.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut list = BanList {
+            writer: Some(BanWriter::start(path.clone())?),
+            bans: Vec::new(),
+        };
+
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Ban::parse(line) {
+                Some(ban) => list.bans.push(ban),
+                None => log::warn!("Ignoring malformed ban on line {} of {}", number + 1, path.display()),
+            }
+        }
+
+        Ok(list)
+    }
+
+    /// Drops expired bans and queues the rest to be written to the file, if there is one.
+    pub fn save(&mut self) -> BanSave {
+        let now = Utc::now();
+        self.bans.retain(|ban| !ban.is_expired(now));
+        match &self.writer {
+            Some(writer) => writer.send(self.bans.iter().map(|ban| ban.to_line() + "\n").collect()),
+            None => BanSave(None),
+        }
+    }
+
+    /// Adds `ban`, replacing an existing ban of the same kind and mask.
+    pub fn add(&mut self, ban: Ban) -> BanSave {
+        self.bans.retain(|existing| !(existing.kind == ban.kind && existing.mask.eq_ignore_ascii_case(&ban.mask)));
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Removes the ban of `kind` on `mask`, returning `None` if there was none.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> Option<BanSave> {
+        let mask = normalize_mask(kind, mask.trim());
+        let count = self.bans.len();
+        self.bans.retain(|ban| !(ban.kind == kind && ban.mask.eq_ignore_ascii_case(&mask)));
+        if self.bans.len() == count {
+            return None;
+        }
+        Some(self.save())
+    }
+
+    /// Bans still in force, oldest first.
+    pub fn active(&self) -> Vec<&Ban> {
+        let now = Utc::now();
+        self.bans.iter().filter(|ban| !ban.is_expired(now)).collect()
+    }
+
+    pub fn find_ip(&self, ip: IpAddr) -> Option<Ban> {
+        self.active().into_iter().find(|ban| ban.matches_ip(ip)).cloned()
+    }
+
+    pub fn find_user(&self, username: &str, ip: IpAddr, hostname: Option<&str>) -> Option<Ban> {
+        self.active().into_iter().find(|ban| ban.matches_user(username, ip, hostname)).cloned()
+    }
+
+    pub fn find_nick(&self, nick: &str) -> Option<Ban> {
+        self.active().into_iter().find(|ban| ban.matches_nick(nick)).cloned()
+    }
+}
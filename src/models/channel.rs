@@ -6,11 +6,14 @@ use std::path::PathBuf;
 pub struct Channel {
     pub name: String,
     pub members: HashSet<usize>,
+    pub operators: HashSet<usize>,
+    pub voiced: HashSet<usize>,
     pub topic: Option<String>,
     pub key: Option<String>,
     pub state_path: Option<PathBuf>,
 }
 
+#[allow(dead_code)]
 impl Channel {
     pub fn get_name(&self) -> &str {
         &self.name
@@ -23,6 +26,16 @@ impl Channel {
     pub fn get_state_path(&self) -> Option<&PathBuf> {
         self.state_path.as_ref()
     }
+
+    pub fn set_key(&mut self, key: Option<String>) {
+        self.key = key;
+    }
+
+    pub fn read_state(&self) -> Result<(), std::io::Error> {
+        // Implement state reading logic here
+        // This is a placeholder implementation
+        Ok(())
+    }
 }
 
 impl Channel {
@@ -30,6 +43,8 @@ impl Channel {
         Channel {
             name,
             members: HashSet::new(),
+            operators: HashSet::new(),
+            voiced: HashSet::new(),
             topic: None,
             key: None,
             state_path: None,
@@ -42,20 +57,43 @@ impl Channel {
 
     pub fn remove_member(&mut self, user_id: &usize) {
         self.members.remove(user_id);
+        self.operators.remove(user_id);
+        self.voiced.remove(user_id);
     }
 
-    pub fn set_topic(&mut self, topic: String) {
-        self.topic = Some(topic);
+    pub fn add_operator(&mut self, user_id: usize) {
+        self.operators.insert(user_id);
     }
 
-    pub fn set_key(&mut self, key: Option<String>) {
-        self.key = key;
+    pub fn remove_operator(&mut self, user_id: &usize) {
+        self.operators.remove(user_id);
     }
 
-    pub fn read_state(&self) -> Result<(), std::io::Error> {
-        // Implement state reading logic here
-        // This is a placeholder implementation
-        Ok(())
+    pub fn add_voice(&mut self, user_id: usize) {
+        self.voiced.insert(user_id);
+    }
+
+    pub fn remove_voice(&mut self, user_id: &usize) {
+        self.voiced.remove(user_id);
+    }
+
+    /// Membership prefixes of a member, highest first. Only the highest one unless `multi_prefix`.
+    pub fn member_prefixes(&self, user_id: usize, multi_prefix: bool) -> String {
+        let mut prefixes = String::new();
+        if self.operators.contains(&user_id) {
+            prefixes.push('@');
+        }
+        if self.voiced.contains(&user_id) {
+            prefixes.push('+');
+        }
+        if !multi_prefix {
+            prefixes.truncate(1);
+        }
+        prefixes
+    }
+
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = Some(topic);
     }
 
     pub fn write_state(&self) -> Result<(), std::io::Error> {
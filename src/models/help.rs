@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Pages compiled into the server from the help/ directory, so HELP works wherever the
+/// server is started from.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("admin", include_str!("../../help/admin")),
+    ("authenticate", include_str!("../../help/authenticate")),
+    ("cap", include_str!("../../help/cap")),
+    ("chathistory", include_str!("../../help/chathistory")),
+    ("die", include_str!("../../help/die")),
+    ("dline", include_str!("../../help/dline")),
+    ("help", include_str!("../../help/help")),
+    ("index", include_str!("../../help/index")),
+    ("info", include_str!("../../help/info")),
+    ("join", include_str!("../../help/join")),
+    ("kill", include_str!("../../help/kill")),
+    ("kline", include_str!("../../help/kline")),
+    ("list", include_str!("../../help/list")),
+    ("lusers", include_str!("../../help/lusers")),
+    ("mode", include_str!("../../help/mode")),
+    ("motd", include_str!("../../help/motd")),
+    ("names", include_str!("../../help/names")),
+    ("nick", include_str!("../../help/nick")),
+    ("notice", include_str!("../../help/notice")),
+    ("oper", include_str!("../../help/oper")),
+    ("part", include_str!("../../help/part")),
+    ("pass", include_str!("../../help/pass")),
+    ("ping", include_str!("../../help/ping")),
+    ("privmsg", include_str!("../../help/privmsg")),
+    ("qline", include_str!("../../help/qline")),
+    ("quit", include_str!("../../help/quit")),
+    ("rehash", include_str!("../../help/rehash")),
+    ("restart", include_str!("../../help/restart")),
+    ("stats", include_str!("../../help/stats")),
+    ("time", include_str!("../../help/time")),
+    ("topic", include_str!("../../help/topic")),
+    ("user", include_str!("../../help/user")),
+    ("version", include_str!("../../help/version")),
+    ("wallops", include_str!("../../help/wallops")),
+    ("who", include_str!("../../help/who")),
+    ("whois", include_str!("../../help/whois")),
+];
+
+/// HELP pages by topic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HelpPages {
+    pages: HashMap<String, String>,
+}
+
+impl Default for HelpPages {
+    fn default() -> Self {
+        HelpPages {
+            pages: BUILT_IN.iter().map(|(topic, text)| (topic.to_string(), text.to_string())).collect(),
+        }
+    }
+}
+
+impl HelpPages {
+    /// The built-in pages, with each file in `dir` adding a topic or replacing one. File
+    /// names are the topics, in lowercase.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let mut help = HelpPages::default();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let topic = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if is_topic(name) => name.to_lowercase(),
+                _ => continue,
+            };
+            if path.is_file() {
+                help.pages.insert(topic, fs::read_to_string(&path)?);
+            }
+        }
+        Ok(help)
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&str> {
+        self.pages.get(&topic.to_lowercase()).map(String::as_str)
+    }
+}
+
+fn is_topic(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
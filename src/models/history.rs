@@ -0,0 +1,480 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+use chrono::{DateTime, Duration, Utc};
+use crate::models::message::{format_server_time, format_tags, Message, MessageKind};
+use crate::utils::casefold;
+
+/// Backend that records channel and private messages for CHATHISTORY.
+pub trait HistoryStore: Send + Sync {
+    /// Records a message in the given buffer (see [`channel_buffer`] and [`private_buffer`]).
+    fn append(&self, buffer: &str, entry: HistoryEntry);
+
+    /// Messages of a buffer, oldest first, with retention applied.
+    fn entries(&self, buffer: &str) -> Vec<HistoryEntry>;
+
+    /// Every buffer that has history, with the time of its latest message.
+    fn buffers(&self) -> Vec<(String, DateTime<Utc>)>;
+
+    /// Writes out anything not yet persisted, called on shutdown.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Buffer key for a channel, folded like the channel map's keys.
+pub fn channel_buffer(channel: &str) -> String {
+    casefold(channel)
+}
+
+/// Buffer key for a conversation between two accounts, independent of who sent what.
+/// Accounts rather than nicknames, since whoever takes a nickname later must not be
+/// able to read the conversations of its previous owner.
+pub fn private_buffer(account_a: &str, account_b: &str) -> String {
+    let (a, b) = (casefold(account_a), casefold(account_b));
+    if a <= b {
+        format!("{}\0{}", a, b)
+    } else {
+        format!("{}\0{}", b, a)
+    }
+}
+
+/// Participants of a private buffer, or None for a channel buffer.
+pub fn private_participants(buffer: &str) -> Option<(&str, &str)> {
+    buffer.split_once('\0')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub msgid: String,
+    pub timestamp: DateTime<Utc>,
+    pub kind: MessageKind,
+    pub sender: String,
+    pub account: Option<String>,
+    pub target: String,
+    pub content: String,
+}
+
+impl HistoryEntry {
+    pub fn from_message(message: &Message, sender: &str, account: Option<&str>, target: &str) -> Self {
+        HistoryEntry {
+            msgid: message.msgid.clone(),
+            timestamp: message.timestamp,
+            kind: message.kind,
+            sender: sender.to_string(),
+            account: account.map(|a| a.to_string()),
+            target: target.to_string(),
+            content: message.content.clone(),
+        }
+    }
+
+    /// Formats the entry as it was originally relayed, with the given tags.
+    pub fn to_line(&self, tags: &[(&str, String)]) -> String {
+        format!("{}:{} {} {} :{}", format_tags(tags), self.sender, self.kind.as_str(), self.target, self.content)
+    }
+
+    pub fn server_time(&self) -> String {
+        format_server_time(&self.timestamp)
+    }
+
+    fn to_record(&self, buffer: &str) -> String {
+        [
+            buffer,
+            &self.msgid,
+            &self.timestamp.to_rfc3339(),
+            self.kind.as_str(),
+            &self.sender,
+            self.account.as_deref().unwrap_or("*"),
+            &self.target,
+            &self.content,
+        ]
+        .iter()
+        .map(|field| escape_field(field))
+        .collect::<Vec<_>>()
+        .join("\t")
+    }
+
+    fn from_record(line: &str) -> Option<(String, Self)> {
+        let fields: Vec<String> = line.split('\t').map(unescape_field).collect();
+        if fields.len() != 8 {
+            return None;
+        }
+        let kind = match fields[3].as_str() {
+            "PRIVMSG" => MessageKind::PrivMsg,
+            "NOTICE" => MessageKind::Notice,
+            _ => return None,
+        };
+        let entry = HistoryEntry {
+            msgid: fields[1].clone(),
+            timestamp: DateTime::parse_from_rfc3339(&fields[2]).ok()?.with_timezone(&Utc),
+            kind,
+            sender: fields[4].clone(),
+            account: if fields[5] == "*" { None } else { Some(fields[5].clone()) },
+            target: fields[6].clone(),
+            content: fields[7].clone(),
+        };
+        Some((fields[0].clone(), entry))
+    }
+}
+
+fn escape_field(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r").replace('\0', "\\0")
+}
+
+fn unescape_field(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// How much history is kept per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub max_messages: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_messages: 1000,
+            max_age: Some(Duration::days(30)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistoryBuffers {
+    buffers: HashMap<String, VecDeque<HistoryEntry>>,
+    appended_since_compaction: usize,
+}
+
+/// Work for the thread that owns the log file.
+#[derive(Debug)]
+enum LogWrite {
+    Record(String),
+    /// Replaces the whole file, then reports how that went.
+    Rewrite(String, mpsc::Sender<io::Result<()>>),
+}
+
+/// Thread writing the log file through a buffer, so recording a message never waits
+/// for the disk. Records are flushed whenever the queue runs empty.
+#[derive(Debug)]
+struct LogWriter {
+    queue: Option<mpsc::Sender<LogWrite>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    fn start(path: PathBuf) -> io::Result<Self> {
+        let (queue, writes) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || write_log(&path, writes))?;
+        Ok(LogWriter { queue: Some(queue), thread: Some(thread) })
+    }
+
+    fn send(&self, write: LogWrite) {
+        if let Some(queue) = &self.queue {
+            // Only fails once the thread is gone, which it has already logged
+            let _ = queue.send(write);
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    /// Waits for everything queued to be written.
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_log(path: &Path, writes: mpsc::Receiver<LogWrite>) {
+    let mut file: Option<BufWriter<File>> = None;
+    while let Ok(write) = writes.recv() {
+        for write in std::iter::once(write).chain(writes.try_iter()) {
+            match write {
+                LogWrite::Record(record) => {
+                    if file.is_none() {
+                        match OpenOptions::new().create(true).append(true).open(path) {
+                            Ok(opened) => file = Some(BufWriter::new(opened)),
+                            Err(e) => log::error!("Failed to open {}: {}", path.display(), e),
+                        }
+                    }
+                    if let Some(Err(e)) = file.as_mut().map(|file| writeln!(file, "{}", record)) {
+                        log::error!("Failed to write history record: {}", e);
+                    }
+                }
+                LogWrite::Rewrite(contents, done) => {
+                    // Reopened for the next record, since the old file is replaced
+                    if let Some(Err(e)) = file.take().map(|mut file| file.flush()) {
+                        log::error!("Failed to write history records: {}", e);
+                    }
+                    let tmp_path = path.with_extension("tmp");
+                    let result = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+                    if let Err(e) = &result {
+                        log::error!("Failed to compact history: {}", e);
+                    }
+                    let _ = done.send(result);
+                }
+            }
+        }
+        if let Some(Err(e)) = file.as_mut().map(|file| file.flush()) {
+            log::error!("Failed to write history records: {}", e);
+        }
+    }
+}
+
+/// History kept in memory and mirrored to an append-only log file, which is compacted
+/// on load and whenever it has grown well past what retention keeps.
+#[derive(Debug)]
+pub struct FileHistoryStore {
+    retention: Retention,
+    state: Mutex<HistoryBuffers>,
+    writer: Option<LogWriter>,
+}
+
+impl FileHistoryStore {
+    /// In-memory store that is lost on restart.
+    pub fn new(retention: Retention) -> Self {
+        FileHistoryStore {
+            retention,
+            state: Mutex::new(HistoryBuffers::default()),
+            writer: None,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, retention: Retention) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = HistoryBuffers::default();
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|line| !line.is_empty()) {
+                    match HistoryEntry::from_record(line) {
+                        Some((buffer, entry)) => state.buffers.entry(buffer).or_default().push_back(entry),
+                        None => log::warn!("Ignoring malformed history record in {}", path.display()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let store = FileHistoryStore {
+            retention,
+            state: Mutex::new(state),
+            writer: Some(LogWriter::start(path)?),
+        };
+        store.flush()?;
+        Ok(store)
+    }
+
+    fn prune(&self, state: &mut HistoryBuffers, buffer: &str) {
+        let entries = match state.buffers.get_mut(buffer) {
+            Some(entries) => entries,
+            None => return,
+        };
+        while entries.len() > self.retention.max_messages {
+            entries.pop_front();
+        }
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = Utc::now() - max_age;
+            while entries.front().map(|e| e.timestamp < cutoff).unwrap_or(false) {
+                entries.pop_front();
+            }
+        }
+        if entries.is_empty() {
+            state.buffers.remove(buffer);
+        }
+    }
+
+    /// Has the log file rewritten with only the retained entries. The returned receiver
+    /// gets the outcome once the file is written.
+    fn compact(&self, state: &mut HistoryBuffers) -> mpsc::Receiver<io::Result<()>> {
+        let (done, outcome) = mpsc::channel();
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => {
+                let _ = done.send(Ok(()));
+                return outcome;
+            }
+        };
+        let mut records: Vec<(&String, &HistoryEntry)> = state.buffers.iter()
+            .flat_map(|(buffer, entries)| entries.iter().map(move |entry| (buffer, entry)))
+            .collect();
+        records.sort_by_key(|(_, entry)| entry.timestamp);
+        let contents: String = records.iter().map(|(buffer, entry)| entry.to_record(buffer) + "\n").collect();
+        writer.send(LogWrite::Rewrite(contents, done));
+        state.appended_since_compaction = 0;
+        outcome
+    }
+}
+
+impl HistoryStore for FileHistoryStore {
+    fn append(&self, buffer: &str, entry: HistoryEntry) {
+        let mut state = self.state.lock().unwrap();
+        // Queued under the lock so records and rewrites reach the file in order
+        if let Some(writer) = &self.writer {
+            writer.send(LogWrite::Record(entry.to_record(buffer)));
+        }
+        state.buffers.entry(buffer.to_string()).or_default().push_back(entry);
+        state.appended_since_compaction += 1;
+        self.prune(&mut state, buffer);
+
+        if state.appended_since_compaction > self.retention.max_messages.max(100) * 4 {
+            // The writer logs any failure
+            self.compact(&mut state);
+        }
+    }
+
+    fn entries(&self, buffer: &str) -> Vec<HistoryEntry> {
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state, buffer);
+        state.buffers.get(buffer).map(|entries| entries.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    fn buffers(&self) -> Vec<(String, DateTime<Utc>)> {
+        let state = self.state.lock().unwrap();
+        state.buffers.iter()
+            .filter_map(|(buffer, entries)| entries.back().map(|entry| (buffer.clone(), entry.timestamp)))
+            .collect()
+    }
+
+    /// Compacts away pruned records and waits for the file to be written.
+    fn flush(&self) -> io::Result<()> {
+        let outcome = {
+            let mut state = self.state.lock().unwrap();
+            let buffers: Vec<String> = state.buffers.keys().cloned().collect();
+            for buffer in buffers {
+                self.prune(&mut state, &buffer);
+            }
+            self.compact(&mut state)
+        };
+        outcome.recv().unwrap_or_else(|_| Err(io::Error::other("history writer stopped")))
+    }
+}
+
+/// A message reference in a CHATHISTORY request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryRef {
+    /// `*`, only valid for LATEST.
+    Any,
+    MsgId(String),
+    Timestamp(DateTime<Utc>),
+}
+
+impl HistoryRef {
+    pub fn parse(input: &str) -> Option<Self> {
+        if input == "*" {
+            return Some(HistoryRef::Any);
+        }
+        if let Some(msgid) = input.strip_prefix("msgid=") {
+            return Some(HistoryRef::MsgId(msgid.to_string()));
+        }
+        let timestamp = input.strip_prefix("timestamp=")?;
+        DateTime::parse_from_rfc3339(timestamp).ok().map(|t| HistoryRef::Timestamp(t.with_timezone(&Utc)))
+    }
+
+    /// End of the entries strictly before this reference.
+    fn before_index(&self, entries: &[HistoryEntry]) -> Option<usize> {
+        match self {
+            HistoryRef::Any => Some(entries.len()),
+            HistoryRef::MsgId(msgid) => entries.iter().position(|e| &e.msgid == msgid),
+            HistoryRef::Timestamp(t) => Some(entries.partition_point(|e| e.timestamp < *t)),
+        }
+    }
+
+    /// Index of the first entry strictly after this reference.
+    fn after_index(&self, entries: &[HistoryEntry]) -> Option<usize> {
+        match self {
+            HistoryRef::Any => Some(0),
+            HistoryRef::MsgId(msgid) => entries.iter().position(|e| &e.msgid == msgid).map(|i| i + 1),
+            HistoryRef::Timestamp(t) => Some(entries.partition_point(|e| e.timestamp <= *t)),
+        }
+    }
+
+    fn timestamp(&self, entries: &[HistoryEntry]) -> Option<DateTime<Utc>> {
+        match self {
+            HistoryRef::Any => None,
+            HistoryRef::MsgId(msgid) => entries.iter().find(|e| &e.msgid == msgid).map(|e| e.timestamp),
+            HistoryRef::Timestamp(t) => Some(*t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryQuery {
+    Latest(HistoryRef),
+    Before(HistoryRef),
+    After(HistoryRef),
+    Around(HistoryRef),
+    Between(HistoryRef, HistoryRef),
+}
+
+/// Selects at most `limit` entries for a query, returned oldest first.
+/// Unknown msgid references select nothing.
+pub fn select_history(entries: &[HistoryEntry], query: &HistoryQuery, limit: usize) -> Vec<HistoryEntry> {
+    let last = |slice: &[HistoryEntry]| slice[slice.len().saturating_sub(limit)..].to_vec();
+    let first = |slice: &[HistoryEntry]| slice[..slice.len().min(limit)].to_vec();
+
+    match query {
+        HistoryQuery::Latest(reference) => match reference.after_index(entries) {
+            Some(start) => last(&entries[start..]),
+            None => Vec::new(),
+        },
+        HistoryQuery::Before(reference) => match reference.before_index(entries) {
+            Some(end) => last(&entries[..end]),
+            None => Vec::new(),
+        },
+        HistoryQuery::After(reference) => match reference.after_index(entries) {
+            Some(start) => first(&entries[start..]),
+            None => Vec::new(),
+        },
+        HistoryQuery::Around(reference) => match reference.before_index(entries) {
+            Some(center) => {
+                let start = center.saturating_sub(limit / 2);
+                let end = (start + limit).min(entries.len());
+                entries[start..end].to_vec()
+            }
+            None => Vec::new(),
+        },
+        HistoryQuery::Between(from, to) => {
+            let (from_time, to_time) = match (from.timestamp(entries), to.timestamp(entries)) {
+                (Some(from_time), Some(to_time)) => (from_time, to_time),
+                _ => return Vec::new(),
+            };
+            if from_time <= to_time {
+                match (from.after_index(entries), to.before_index(entries)) {
+                    (Some(start), Some(end)) if start < end => first(&entries[start..end]),
+                    _ => Vec::new(),
+                }
+            } else {
+                match (to.after_index(entries), from.before_index(entries)) {
+                    (Some(start), Some(end)) if start < end => last(&entries[start..end]),
+                    _ => Vec::new(),
+                }
+            }
+        }
+    }
+}
@@ -1,24 +1,55 @@
-
 use chrono::{DateTime, Utc};
+use crate::utils::generate_msgid;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum Recipient {
     User(usize),
     Channel(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    PrivMsg,
+    Notice,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::PrivMsg => "PRIVMSG",
+            MessageKind::Notice => "NOTICE",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Message {
+    #[allow(dead_code)]
     pub sender_id: usize,
+    #[allow(dead_code)]
     pub recipient: Recipient,
     pub content: String,
     pub timestamp: DateTime<Utc>,
+    pub msgid: String,
+    pub kind: MessageKind,
 }
 
 impl Message {
+    #[allow(dead_code)]
     pub fn get_content(&self) -> &str {
         &self.content
     }
+
+    /// Timestamp in the format used by the `server-time` tag.
+    pub fn server_time(&self) -> String {
+        format_server_time(&self.timestamp)
+    }
+
+    /// Formats the message as a protocol line, e.g. `@time=... :nick PRIVMSG #chan :text`.
+    pub fn to_line(&self, prefix: &str, target: &str, tags: &[(&str, String)]) -> String {
+        format!("{}:{} {} {} :{}", format_tags(tags), prefix, self.kind.as_str(), target, self.content)
+    }
 }
 
 impl Message {
@@ -28,6 +59,50 @@ impl Message {
             recipient,
             content,
             timestamp: Utc::now(),
+            msgid: generate_msgid(),
+            kind: MessageKind::PrivMsg,
+        }
+    }
+
+    pub fn with_kind(mut self, kind: MessageKind) -> Self {
+        self.kind = kind;
+        self
+    }
+}
+
+pub fn format_server_time(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Renders IRCv3 message tags as `@key=value;key2=value2 `, or an empty string if there are none.
+pub fn format_tags(tags: &[(&str, String)]) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+    let rendered = tags.iter()
+        .map(|(key, value)| {
+            if value.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}={}", key, escape_tag_value(value))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    format!("@{} ", rendered)
+}
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
         }
     }
+    escaped
 }
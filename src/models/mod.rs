@@ -2,3 +2,9 @@
 pub mod user;
 pub mod channel;
 pub mod message;
+pub mod account;
+pub mod history;
+pub mod motd;
+pub mod help;
+pub mod stats;
+pub mod ban;
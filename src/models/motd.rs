@@ -0,0 +1,59 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Widest MOTD line clients are expected to display without wrapping.
+pub const MOTD_WIDTH: usize = 80;
+
+/// Message of the day, split into lines ready to be sent as 372 replies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Motd {
+    pub lines: Vec<String>,
+}
+
+impl Motd {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Motd::from_text(&fs::read_to_string(path)?))
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        let lines = text.lines()
+            .flat_map(|line| wrap_line(line.trim_end(), MOTD_WIDTH))
+            .collect();
+        Motd { lines }
+    }
+}
+
+/// Splits a line into pieces of at most `width` characters, breaking at spaces where
+/// possible. Empty lines are kept so paragraphs stay apart.
+pub fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for word in line.split(' ') {
+        let needed = if current.is_empty() { word.chars().count() } else { current.chars().count() + 1 + word.chars().count() };
+        if needed <= width {
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+            continue;
+        }
+        if !current.is_empty() {
+            pieces.push(std::mem::take(&mut current));
+        }
+        // Words longer than a line are cut
+        let chars: Vec<char> = word.chars().collect();
+        let mut chunks = chars.chunks(width).peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_some() {
+                pieces.push(chunk.iter().collect());
+            } else {
+                current = chunk.iter().collect();
+            }
+        }
+    }
+    if !current.is_empty() || pieces.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use chrono::{DateTime, Utc};
+
+/// Server-wide counters reported by LUSERS and STATS.
+#[derive(Debug)]
+pub struct ServerStats {
+    pub started: DateTime<Utc>,
+    max_local_users: AtomicUsize,
+    max_global_users: AtomicUsize,
+    commands: Mutex<HashMap<String, CommandUsage>>,
+}
+
+/// How often a command was used and how many bytes it took, for STATS m.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommandUsage {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats {
+            started: Utc::now(),
+            max_local_users: AtomicUsize::new(0),
+            max_global_users: AtomicUsize::new(0),
+            commands: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the current user counts, raising the peaks if they were exceeded.
+    pub fn record_users(&self, local: usize, global: usize) {
+        self.max_local_users.fetch_max(local, Ordering::Relaxed);
+        self.max_global_users.fetch_max(global, Ordering::Relaxed);
+    }
+
+    pub fn max_local_users(&self) -> usize {
+        self.max_local_users.load(Ordering::Relaxed)
+    }
+
+    pub fn max_global_users(&self) -> usize {
+        self.max_global_users.load(Ordering::Relaxed)
+    }
+
+    pub fn record_command(&self, command: &str, bytes: usize) {
+        let mut commands = self.commands.lock().unwrap();
+        let usage = commands.entry(command.to_uppercase()).or_default();
+        usage.count += 1;
+        usage.bytes += bytes as u64;
+    }
+
+    /// Usage of every command received so far, by name.
+    pub fn command_usage(&self) -> Vec<(String, CommandUsage)> {
+        let mut usage: Vec<_> = self.commands.lock().unwrap().iter().map(|(name, usage)| (name.clone(), *usage)).collect();
+        usage.sort_by(|a, b| a.0.cmp(&b.0));
+        usage
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Traffic on one client connection, for STATS l. Shared between the connection task,
+/// which counts, and the user entry other commands read.
+#[derive(Debug)]
+pub struct LinkTraffic {
+    pub opened: DateTime<Utc>,
+    sent_messages: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_messages: AtomicU64,
+    received_bytes: AtomicU64,
+    queued_bytes: AtomicU64,
+}
+
+impl LinkTraffic {
+    pub fn new() -> Self {
+        LinkTraffic {
+            opened: Utc::now(),
+            sent_messages: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            received_messages: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            queued_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.sent_messages.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.received_messages.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records `bytes` waiting in the send queue.
+    pub fn record_queued(&self, bytes: usize) {
+        self.queued_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records `bytes` written out of the send queue.
+    pub fn record_written(&self, bytes: usize) {
+        self.queued_bytes.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes queued for the client but not written yet.
+    pub fn queued(&self) -> u64 {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Messages and bytes sent to the client.
+    pub fn sent(&self) -> (u64, u64) {
+        (self.sent_messages.load(Ordering::Relaxed), self.sent_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Messages and bytes received from the client.
+    pub fn received(&self) -> (u64, u64) {
+        (self.received_messages.load(Ordering::Relaxed), self.received_bytes.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for LinkTraffic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,6 +1,18 @@
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::net::IpAddr;
+use std::sync::Arc;
+use crate::models::account::SaslSession;
+use crate::config::DEFAULT_CLASS;
+use crate::models::stats::LinkTraffic;
+use crate::utils::user_host_matches;
+
+/// Longest nickname accepted when no limit is configured.
+pub const DEFAULT_NICK_LENGTH: usize = 20;
+
+/// Server notice mask letters: c connects and exits, f flooding, k kills, o oper-ups
+/// and failed OPER attempts, x bans.
+pub const SNOMASK_LETTERS: &str = "cfkox";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum UserStatus {
@@ -17,9 +29,43 @@ pub struct User {
     pub host: IpAddr,
     pub channels: HashSet<String>,
     pub status: UserStatus,
+    pub registered: bool,
+    pub cap_negotiating: bool,
+    pub capabilities: HashSet<String>,
+    pub account: Option<String>,
+    pub certfp: Option<String>,
+    pub secure: bool,
+    pub sasl: Option<SaslSession>,
+    /// Name of the oper block the user logged in with.
+    pub oper: Option<String>,
+    /// User mode +w: receives WALLOPS.
+    pub wallops: bool,
+    /// Server notices subscribed to with user mode +s, empty without it.
+    pub snomask: BTreeSet<char>,
+    /// Connection class, set from the matching auth block at registration.
+    pub class: String,
+    /// Host shown instead of the IP address, from an auth block's spoof.
+    pub vhost: Option<String>,
+    /// Host name the address resolved to, confirmed by a forward lookup.
+    pub hostname: Option<String>,
+    /// Host shown with user mode +x, when cloaking is configured.
+    pub cloaked_host: Option<String>,
+    /// User mode +x: shows the cloaked host.
+    pub cloaked: bool,
+    /// Exemptions granted by the auth block.
+    pub flood_exempt: bool,
+    pub kline_exempt: bool,
+    /// Password sent with PASS, checked and forgotten at registration.
+    pub password: Option<String>,
+    /// Address of the listener the client connected through, if any.
+    pub listener: Option<String>,
+    /// Connected through a Unix socket, so `host` is only the loopback address.
+    pub unix_socket: bool,
+    pub traffic: Arc<LinkTraffic>,
 }
 
 impl User {
+    #[allow(dead_code)]
     pub fn get_host(&self) -> &IpAddr {
         &self.host
     }
@@ -35,18 +81,107 @@ impl User {
             host,
             channels: HashSet::new(),
             status: UserStatus::Online,
+            registered: false,
+            cap_negotiating: false,
+            capabilities: HashSet::new(),
+            account: None,
+            certfp: None,
+            secure: false,
+            sasl: None,
+            oper: None,
+            wallops: false,
+            snomask: BTreeSet::new(),
+            class: DEFAULT_CLASS.to_string(),
+            vhost: None,
+            hostname: None,
+            cloaked_host: None,
+            cloaked: false,
+            flood_exempt: false,
+            kline_exempt: false,
+            password: None,
+            listener: None,
+            unix_socket: false,
+            traffic: Arc::new(LinkTraffic::new()),
         }
     }
 
+    #[cfg(test)]
     pub fn set_nickname(&mut self, nickname: String) -> Result<(), &'static str> {
+        self.set_nickname_with_limit(nickname, DEFAULT_NICK_LENGTH)
+    }
+
+    /// Sets the nickname, allowing at most `max_length` characters.
+    pub fn set_nickname_with_limit(&mut self, nickname: String, max_length: usize) -> Result<(), &'static str> {
         // Basic nickname validation
-        if nickname.is_empty() || nickname.len() > 20 || !nickname.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        if nickname.is_empty() || nickname.chars().count() > max_length || !nickname.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
             return Err("Invalid nickname");
         }
         self.nickname = Some(nickname);
         Ok(())
     }
 
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.capabilities.contains(cap)
+    }
+
+    /// Nickname used as the target of numeric replies, `*` before one is chosen.
+    pub fn reply_nick(&self) -> String {
+        self.nickname.clone().unwrap_or_else(|| "*".to_string())
+    }
+
+    /// Real host: the resolved host name, or the address without one.
+    pub fn real_host(&self) -> String {
+        self.hostname.clone().unwrap_or_else(|| self.host.to_string())
+    }
+
+    /// Real `user@host`, as shown to opers in server notices.
+    pub fn user_host(&self) -> String {
+        format!("{}@{}", self.username.as_deref().unwrap_or("*"), self.real_host())
+    }
+
+    /// Whether a `user@host` mask from an oper block, auth block or K-line matches the
+    /// real address or host name.
+    pub fn matches_host_mask(&self, mask: &str) -> bool {
+        user_host_matches(mask, self.username.as_deref().unwrap_or("*"), self.host, self.hostname.as_deref())
+    }
+
+    /// Host shown to other users: a spoofed host, then the cloak with +x, then the real host.
+    pub fn display_host(&self) -> String {
+        match (&self.vhost, &self.cloaked_host) {
+            (Some(vhost), _) => vhost.clone(),
+            (None, Some(cloak)) if self.cloaked => cloak.clone(),
+            _ => self.real_host(),
+        }
+    }
+
+    /// Full `nick!user@host` mask.
+    pub fn mask(&self) -> String {
+        format!(
+            "{}!{}@{}",
+            self.reply_nick(),
+            self.username.clone().unwrap_or_else(|| "*".to_string()),
+            self.display_host()
+        )
+    }
+
+    /// User modes such as `+osw`.
+    pub fn modes(&self) -> String {
+        let mut modes = "+".to_string();
+        if self.oper.is_some() {
+            modes.push('o');
+        }
+        if !self.snomask.is_empty() {
+            modes.push('s');
+        }
+        if self.wallops {
+            modes.push('w');
+        }
+        if self.cloaked {
+            modes.push('x');
+        }
+        modes
+    }
+
     pub fn join_channel(&mut self, channel: String) {
         self.channels.insert(channel);
     }
@@ -55,10 +190,12 @@ impl User {
         self.channels.remove(channel);
     }
 
+    #[allow(dead_code)]
     pub fn set_away(&mut self, message: Option<String>) {
         self.status = UserStatus::Away(message);
     }
 
+    #[allow(dead_code)]
     pub fn set_online(&mut self) {
         self.status = UserStatus::Online;
     }
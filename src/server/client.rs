@@ -1,69 +1,202 @@
-
 use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, BufReader, AsyncBufReadExt};
-use crate::commands::parser::parse_command;
-use crate::commands::handler::{handle_command, SharedState as HandlerSharedState};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::commands::parser::{command_name, parse_message};
+use crate::commands::batch::CommandReplies;
+use crate::commands::handler::{disconnect_client, handle_tagged_command};
+use crate::config::{ClassConfig, DEFAULT_CLASS};
 use crate::models::user::User;
+use crate::utils::ip_mask_matches;
+use std::collections::VecDeque;
+use std::net::IpAddr;
 use std::sync::Arc;
+use crate::server::codec::{decode_line, too_long, Line, LineReader, MAX_TAGS_LENGTH};
+use crate::server::flood::{command_cost, TokenBucket};
 use crate::server::listener::SharedState as ListenerSharedState;
+use crate::server::sendq::SendQueue;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+/// How long a closing connection gets to write out what is still queued.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub struct Client {
+/// A connected client, generic over the transport so plain TCP and TLS connections
+/// share the same command loop.
+pub struct Client<S = TcpStream> {
     pub id: usize,
-    pub stream: TcpStream,
+    pub stream: S,
     pub user: User,
+    flood: TokenBucket,
 }
 
-impl Client {
-    pub fn new(id: usize, stream: TcpStream, ip: std::net::IpAddr) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    pub fn new(id: usize, stream: S, ip: std::net::IpAddr) -> Self {
         Client {
             id,
             stream,
             user: User::new(id, ip),
+            flood: TokenBucket::new(),
         }
     }
 
-    pub async fn handle(&mut self, shared_state: Arc<ListenerSharedState>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let (reader, mut writer) = self.stream.split();
-        let mut reader = BufReader::new(reader).lines();
-
-        let handler_shared_state = HandlerSharedState {
-            users: Arc::clone(&shared_state.users),
-            channels: Arc::clone(&shared_state.channels),
-        };
-
-        while let Some(line) = reader.next_line().await? {
-            log::trace!("Received from client {}: {}", self.id, line);
-
-            if let Some(command) = parse_command(&line) {
-                log::debug!("Parsed command from client {}: {:?}", self.id, command);
-
-                match handle_command(command, self.id, &handler_shared_state).await {
-                    Ok(responses) => {
-                        for (recipient_id, response) in responses {
-                            if recipient_id == self.id {
-                                // This is a response to the current client
-                                log::trace!("Sending to client {}: {}", self.id, response);
-                                writer.write_all(response.as_bytes()).await?;
-                                writer.write_all(b"\r\n").await?;
-                            } else {
-                                // This is a message that needs to be sent to other clients
-                                log::trace!("Sending to client {}: {}", recipient_id, response);
-                                shared_state.tx.send(format!("{}:{}", recipient_id, response)).unwrap();
+    pub async fn handle(self, shared_state: Arc<ListenerSharedState>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        S: Send + 'static,
+    {
+        let Client { id, stream, user, mut flood } = self;
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = LineReader::new(reader);
+        let (queue, mut writer_task) = SendQueue::start(writer, Arc::clone(&user.traffic));
+        shared_state.queues.lock().unwrap().insert(id, queue.clone());
+
+        let handler_shared_state = shared_state.handler_state();
+        // Lines received but not handled yet, held back by flood protection
+        let mut pending: VecDeque<String> = VecDeque::new();
+        let mut ready_at = Instant::now();
+        let mut closed = false;
+        // Set when the client stopped reading, which leaves nothing worth writing
+        let mut overflowed = false;
+        // Silent clients get a PING after the class ping frequency and are dropped if
+        // they stay silent for another one
+        let mut last_active = Instant::now();
+        let mut pinged = false;
+        let mut ping_at = last_active + Duration::from_secs(connection_class(&shared_state, id, user.host).0.ping_frequency);
+
+        loop {
+            if closed && pending.is_empty() {
+                break;
+            }
+            tokio::select! {
+                line = reader.next_line(MAX_TAGS_LENGTH + shared_state.config.get().limits.line_length), if !closed => {
+                    let config = shared_state.config.get();
+                    last_active = Instant::now();
+                    pinged = false;
+                    let line = match line? {
+                        Some(Line::Complete(bytes)) if !too_long(&bytes, config.limits.line_length) => {
+                            user.traffic.record_received(bytes.len() + 2);
+                            decode_line(&bytes, config.encoding_fallback())
+                        }
+                        Some(_) => {
+                            log::debug!("Client {} sent an overlong line", id);
+                            let nick = shared_state.users.lock().unwrap().get(&id).map(|user| user.reply_nick()).unwrap_or_else(|| "*".to_string());
+                            let reply = format!(":{} 417 {} :Input line was too long", config.server.name, nick);
+                            if !deliver(&queue, id, vec![(id, reply)], &shared_state) {
+                                overflowed = true;
+                                break;
+                            }
+                            continue;
+                        }
+                        None => {
+                            closed = true;
+                            continue;
+                        }
+                    };
+                    log::trace!("Received from client {}: {}", id, line);
+                    pending.push_back(line);
+
+                    let (class, exempt) = connection_class(&shared_state, id, user.host);
+                    let pending_bytes: usize = pending.iter().map(|line| line.len() + 2).sum();
+                    if !exempt && (pending.len() > class.flood_queue || pending_bytes > class.recvq) {
+                        log::warn!("Disconnecting client {} for Excess Flood", id);
+                        let responses = disconnect_client(id, "Excess Flood", Some('f'), &handler_shared_state);
+                        deliver(&queue, id, responses, &shared_state);
+                        break;
+                    }
+                }
+                _ = sleep_until(ready_at), if !pending.is_empty() => {
+                    // Fake lag: leave the line queued until the bucket has enough tokens
+                    let (class, exempt) = connection_class(&shared_state, id, user.host);
+                    if !exempt && !closed {
+                        let cost = pending.front().and_then(|line| command_name(line)).map(|name| command_cost(&name)).unwrap_or(1.0);
+                        if let Err(wait) = flood.take(cost, class.flood_burst, class.flood_rate, Instant::now()) {
+                            ready_at = Instant::now() + wait;
+                            continue;
+                        }
+                    }
+                    let line = match pending.pop_front() {
+                        Some(line) => line,
+                        None => continue,
+                    };
+
+                    if let Some((tags, command)) = parse_message(&line) {
+                        log::debug!("Parsed command from client {}: {:?}", id, command);
+                        if let Some(name) = command_name(&line) {
+                            shared_state.stats.record_command(&name, line.len() + 2);
+                        }
+
+                        let replies = match handle_tagged_command(command, &tags, id, &handler_shared_state).await {
+                            Ok(replies) => replies,
+                            Err(e) => {
+                                log::error!("Error handling command for client {}: {}", id, e);
+                                CommandReplies { own: vec![format!("ERROR :{}", e)], others: Vec::new() }
                             }
+                        };
+                        if !deliver_replies(&queue, id, replies, &shared_state) {
+                            overflowed = true;
+                            break;
                         }
-                        writer.flush().await?;
+                        // QUIT, KILL or a ban removed the user, which ends the connection
+                        if !shared_state.users.lock().unwrap().contains_key(&id) {
+                            break;
+                        }
+                    } else {
+                        log::warn!("Unable to parse command from client {}: {}", id, line);
+                    }
+                }
+                overflowed_by_others = queue.alerted() => {
+                    if overflowed_by_others {
+                        overflowed = true;
+                        break;
+                    }
+                    // A KILL or ban removes the user and ends with an ERROR
+                    if !shared_state.users.lock().unwrap().contains_key(&id) {
+                        break;
+                    }
+                }
+                _ = sleep_until(ping_at) => {
+                    let frequency = Duration::from_secs(connection_class(&shared_state, id, user.host).0.ping_frequency);
+                    if pinged {
+                        let reason = format!("Ping timeout: {} seconds", last_active.elapsed().as_secs());
+                        log::info!("Disconnecting client {}: {}", id, reason);
+                        let responses = disconnect_client(id, &reason, None, &handler_shared_state);
+                        deliver(&queue, id, responses, &shared_state);
+                        break;
                     }
-                    Err(e) => {
-                        log::error!("Error handling command for client {}: {}", self.id, e);
-                        writer.write_all(format!("ERROR :{}\r\n", e).as_bytes()).await?;
-                        writer.flush().await?;
+                    if last_active.elapsed() >= frequency {
+                        // Silent for a whole ping period: the reply to this PING is the last chance
+                        queue.push(&format!("PING :{}", shared_state.config.get().server.name), usize::MAX);
+                        pinged = true;
+                        ping_at = Instant::now() + frequency;
+                    } else {
+                        ping_at = last_active + frequency;
                     }
                 }
-            } else {
-                log::warn!("Unable to parse command from client {}: {}", self.id, line);
+                _ = queue.closed() => {
+                    // The writer failed, so the connection is gone
+                    break;
+                }
+                (kind, _) = shared_state.shutdown.wait() => {
+                    queue.push(&format!("ERROR :{}", kind.message()), usize::MAX);
+                    break;
+                }
             }
         }
 
+        // Other connections stop queueing lines here, leaving this one the only sender
+        shared_state.queues.lock().unwrap().remove(&id);
+
+        if overflowed {
+            log::warn!("Disconnecting client {}: Max SendQ exceeded", id);
+            writer_task.abort();
+            let responses = disconnect_client(id, "Max SendQ exceeded", Some('f'), &handler_shared_state);
+            deliver(&queue, id, responses.into_iter().filter(|(recipient_id, _)| *recipient_id != id).collect(), &shared_state);
+            return Ok(());
+        }
+
+        // Give the writer a moment to get the last lines out, such as the ERROR
+        drop(queue);
+        if let Ok(Ok(Err(e))) = timeout(CLOSE_TIMEOUT, &mut writer_task).await {
+            log::debug!("Error writing to client {}: {}", id, e);
+        }
+        writer_task.abort();
         Ok(())
     }
 
@@ -74,3 +207,68 @@ impl Client {
     }
 
 }
+
+/// Queues `line` for another client, held to that client's sendq like its own replies.
+/// A client that can't take it is disconnected with Max SendQ exceeded.
+pub fn send_to(shared_state: &ListenerSharedState, recipient_id: usize, line: &str) {
+    log::trace!("Sending to client {}: {}", recipient_id, line);
+    let queue = match shared_state.queues.lock().unwrap().get(&recipient_id) {
+        Some(queue) => queue.clone(),
+        None => return,
+    };
+    if !queue.push(line, sendq_limit(shared_state, recipient_id)) {
+        queue.overflow();
+    } else if line.starts_with("ERROR ") {
+        queue.alert();
+    }
+}
+
+/// Queues the replies meant for `client_id` and forwards the rest to their recipients.
+/// Returns false if the client's send queue overflowed.
+fn deliver(queue: &SendQueue, client_id: usize, responses: Vec<(usize, String)>, shared_state: &ListenerSharedState) -> bool {
+    let limit = sendq_limit(shared_state, client_id);
+    for (recipient_id, response) in responses {
+        log::trace!("Sending to client {}: {}", recipient_id, response);
+        if recipient_id == client_id {
+            if !queue.push(&response, limit) {
+                return false;
+            }
+        } else {
+            send_to(shared_state, recipient_id, &response);
+        }
+    }
+    true
+}
+
+/// Delivers one command's replies. Those to its sender are queued together, or not at
+/// all if they don't fit in the sendq, so a batch is never cut short; returns false in
+/// that case.
+fn deliver_replies(queue: &SendQueue, client_id: usize, replies: CommandReplies, shared_state: &ListenerSharedState) -> bool {
+    log::trace!("Sending to client {}: {:?}", client_id, replies.own);
+    let queued = queue.push_all(&replies.own, sendq_limit(shared_state, client_id));
+    for (recipient_id, response) in replies.others {
+        send_to(shared_state, recipient_id, &response);
+    }
+    queued
+}
+
+/// Send queue limit of a client's class.
+fn sendq_limit(shared_state: &ListenerSharedState, client_id: usize) -> usize {
+    let config = shared_state.config.get();
+    let (class, oper) = shared_state.users.lock().unwrap().get(&client_id)
+        .map(|user| (user.class.clone(), user.oper.clone()))
+        .unwrap_or_else(|| (DEFAULT_CLASS.to_string(), None));
+    config.user_class(&class, oper.as_deref()).sendq
+}
+
+/// Class settings of a connection and whether it skips flood protection, as opers,
+/// exempt hosts and auth blocks granting "flood" do. Looked up for every use so OPER,
+/// registration and rehashes take effect right away.
+fn connection_class(shared_state: &ListenerSharedState, client_id: usize, ip: IpAddr) -> (ClassConfig, bool) {
+    let config = shared_state.config.get();
+    let (class, oper, exempt) = shared_state.users.lock().unwrap().get(&client_id)
+        .map(|user| (user.class.clone(), user.oper.clone(), user.flood_exempt))
+        .unwrap_or_else(|| (DEFAULT_CLASS.to_string(), None, false));
+    let exempt = exempt || oper.is_some() || config.limits.flood_exempt.iter().any(|mask| ip_mask_matches(mask, ip));
+    (config.user_class(&class, oper.as_deref()), exempt)
+}
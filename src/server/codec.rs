@@ -0,0 +1,127 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest tag section of a line, the `@` and the space after it included.
+pub const MAX_TAGS_LENGTH: usize = 8191;
+
+/// Encoding lines that aren't valid UTF-8 are read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingFallback {
+    /// Latin-1 (ISO 8859-1), which older clients commonly send.
+    Latin1,
+    /// UTF-8 with invalid sequences replaced by U+FFFD.
+    Replace,
+}
+
+impl EncodingFallback {
+    pub const NAMES: &'static [&'static str] = &["latin1", "replace"];
+
+    pub fn parse(name: &str) -> Option<EncodingFallback> {
+        match name {
+            "latin1" => Some(EncodingFallback::Latin1),
+            "replace" => Some(EncodingFallback::Replace),
+            _ => None,
+        }
+    }
+}
+
+/// Text of a received line, decoded with `fallback` unless it is valid UTF-8.
+pub fn decode_line(bytes: &[u8], fallback: EncodingFallback) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(line) => line.to_string(),
+        Err(_) => match fallback {
+            EncodingFallback::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+            EncodingFallback::Replace => String::from_utf8_lossy(bytes).into_owned(),
+        },
+    }
+}
+
+/// Whether a line is over the limits: `line_length` bytes for the message with its CRLF,
+/// and `MAX_TAGS_LENGTH` for any tags in front of it.
+pub fn too_long(line: &[u8], line_length: usize) -> bool {
+    let tags = match line.first() {
+        Some(b'@') => line.iter().position(|byte| *byte == b' ').map(|space| space + 1).unwrap_or(line.len()),
+        _ => 0,
+    };
+    tags > MAX_TAGS_LENGTH || line.len() - tags + 2 > line_length
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// A line without its terminator.
+    Complete(Vec<u8>),
+    /// A line over the length limit, which was thrown away.
+    TooLong,
+}
+
+/// Splits a client's input into lines ended by CRLF, LF or a bare CR, holding at most
+/// one line's worth of it in memory.
+pub struct LineReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Set while throwing away the rest of an overlong line.
+    discarding: bool,
+    /// Set after a CR, so the LF of a CRLF split across reads doesn't end an empty line.
+    skip_lf: bool,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader { reader, buffer: Vec::new(), discarding: false, skip_lf: false }
+    }
+
+    /// Next non-empty line, or `None` at the end of the input. Lines longer than
+    /// `max_length` bytes come out as `Line::TooLong`. Cancel safe, so it can be raced
+    /// in `select!`.
+    pub async fn next_line(&mut self, max_length: usize) -> io::Result<Option<Line>> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(line) = self.take_line(max_length) {
+                return Ok(Some(line));
+            }
+            let n = self.reader.read(&mut chunk).await?;
+            if n == 0 {
+                // The last line may be missing its terminator
+                if std::mem::take(&mut self.discarding) {
+                    return Ok(Some(Line::TooLong));
+                }
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let line = std::mem::take(&mut self.buffer);
+                return Ok(Some(if line.len() > max_length { Line::TooLong } else { Line::Complete(line) }));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn take_line(&mut self, max_length: usize) -> Option<Line> {
+        loop {
+            if self.skip_lf && !self.buffer.is_empty() {
+                if self.buffer[0] == b'\n' {
+                    self.buffer.remove(0);
+                }
+                self.skip_lf = false;
+            }
+            let end = match self.buffer.iter().position(|byte| *byte == b'\r' || *byte == b'\n') {
+                Some(end) => end,
+                None => {
+                    if self.buffer.len() > max_length {
+                        self.discarding = true;
+                        self.buffer.clear();
+                    }
+                    return None;
+                }
+            };
+            self.skip_lf = self.buffer[end] == b'\r';
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            line.pop();
+            if std::mem::take(&mut self.discarding) || line.len() > max_length {
+                return Some(Line::TooLong);
+            }
+            if !line.is_empty() {
+                return Some(Line::Complete(line));
+            }
+        }
+    }
+}
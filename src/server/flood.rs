@@ -0,0 +1,49 @@
+use tokio::time::{Duration, Instant};
+
+/// Slowest refill rate a class can set, one token every 100 seconds.
+pub const MIN_RATE: f64 = 0.01;
+
+/// Longest a command is held back, whatever the rate.
+const MAX_DELAY: Duration = Duration::from_secs(3600);
+
+/// Per-connection token bucket. Each command spends tokens according to its cost and
+/// tokens refill at a steady rate up to the burst size; the bucket starts full.
+#[derive(Debug, Default)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        TokenBucket::default()
+    }
+
+    /// Spends `cost` tokens if there are enough, otherwise returns how long until there
+    /// will be. Costs above `burst` are capped so every command can eventually run.
+    pub fn take(&mut self, cost: f64, burst: f64, rate: f64, now: Instant) -> Result<(), Duration> {
+        self.tokens = match self.updated {
+            Some(updated) => (self.tokens + now.duration_since(updated).as_secs_f64() * rate).min(burst),
+            None => burst,
+        };
+        self.updated = Some(now);
+
+        let cost = cost.min(burst);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::try_from_secs_f64((cost - self.tokens) / rate).unwrap_or(MAX_DELAY).min(MAX_DELAY))
+        }
+    }
+}
+
+/// Tokens a command costs. Commands with long replies cost more, keep-alives nothing.
+pub fn command_cost(command: &str) -> f64 {
+    match command {
+        "PING" | "PONG" => 0.0,
+        "JOIN" | "PART" | "NICK" => 2.0,
+        "WHO" | "WHOIS" | "LIST" | "NAMES" | "CHATHISTORY" | "STATS" | "HELP" | "INFO" | "MOTD" => 3.0,
+        _ => 1.0,
+    }
+}
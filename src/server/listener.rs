@@ -1,59 +1,301 @@
-
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::TlsAcceptor;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use crate::config::{ConfigHandle, ListenAddress, ListenerConfig};
 use crate::models::user::User;
 use crate::models::channel::Channel;
-use crate::utils::generate_client_id;
-use std::net::SocketAddr;
-use crate::server::client::Client;
-use log::LevelFilter;
-use tokio::sync::broadcast;
+use crate::models::account::{AccountStore, FileAccountStore};
+use crate::models::ban::BanList;
+use crate::models::history::{FileHistoryStore, HistoryStore, Retention};
+use crate::models::stats::ServerStats;
+use crate::commands::handler::{handle_command, server_notices, SharedState as HandlerSharedState};
+use crate::commands::parser::Command;
+use crate::utils::{cloak_host, generate_client_id, ip_mask_matches, network, same_network};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use crate::server::client::{send_to, Client};
+use crate::server::sendq::SendQueue;
+use crate::server::proxy::{read_proxy_header, PROXY_TIMEOUT};
+use crate::server::resolver::{resolve_hostname, Resolver, SystemResolver};
+use crate::server::shutdown::Shutdown;
+use crate::server::systemd::PassedListener;
+use crate::server::websocket;
+use crate::server::throttle::ConnectionThrottle;
+use crate::server::tls::{self, certificate_fingerprint, TlsSettings};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use std::fs;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
 
+/// State shared by every connection. Code holding more than one of these locks takes
+/// `channels` before `users`, and `users` before `bans`, so two commands can't
+/// deadlock each other.
 pub struct SharedState {
     pub users: Arc<Mutex<HashMap<usize, User>>>,
     pub channels: Arc<Mutex<HashMap<String, Channel>>>,
-    pub tx: broadcast::Sender<String>,
+    pub accounts: Arc<dyn AccountStore>,
+    pub history: Arc<dyn HistoryStore>,
+    pub config: Arc<ConfigHandle>,
+    pub stats: Arc<ServerStats>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub shutdown: Arc<Shutdown>,
+    pub throttle: Arc<Mutex<ConnectionThrottle>>,
+    /// Open connections by ID, those still in a handshake included, with the address
+    /// each one counts against the per-address limits.
+    pub connections: Arc<Mutex<HashMap<usize, Option<IpAddr>>>>,
+    pub resolver: Arc<dyn Resolver>,
+    /// Send queues of the connected clients by ID, through which lines reach other clients.
+    pub queues: Arc<Mutex<HashMap<usize, SendQueue>>>,
 }
 
 impl SharedState {
+    #[cfg(test)]
     pub fn new() -> Self {
-        let (tx, _) = broadcast::channel(100);
+        Self::with_config(Arc::new(ConfigHandle::new(crate::config::Config::default())))
+    }
+
+    pub fn with_config(config: Arc<ConfigHandle>) -> Self {
         SharedState {
             users: Arc::new(Mutex::new(HashMap::new())),
             channels: Arc::new(Mutex::new(HashMap::new())),
-            tx,
+            accounts: Arc::new(FileAccountStore::new()),
+            history: Arc::new(FileHistoryStore::new(Retention::default())),
+            config,
+            stats: Arc::new(ServerStats::new()),
+            bans: Arc::new(Mutex::new(BanList::new())),
+            shutdown: Arc::new(Shutdown::new()),
+            throttle: Arc::new(Mutex::new(ConnectionThrottle::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            resolver: Arc::new(SystemResolver),
+            queues: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// View of the shared state used by the command handlers.
+    pub fn handler_state(&self) -> HandlerSharedState {
+        HandlerSharedState {
+            users: Arc::clone(&self.users),
+            channels: Arc::clone(&self.channels),
+            accounts: Arc::clone(&self.accounts),
+            history: Arc::clone(&self.history),
+            config: Arc::clone(&self.config),
+            stats: Arc::clone(&self.stats),
+            bans: Arc::clone(&self.bans),
+            shutdown: Arc::clone(&self.shutdown),
         }
     }
 }
 
-pub async fn start_server(address: &str, _log_level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+#[cfg(test)]
+pub async fn start_server(address: &str, _log_level: log::LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+    run_server(address, Arc::new(SharedState::new())).await
+}
+
+#[cfg(test)]
+pub async fn run_server(address: &str, shared_state: Arc<SharedState>) -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(address).await?;
     log::info!("Server listening on {}", address);
+    accept_loop(BoundListener::Tcp(listener), shared_state, None, address.to_string()).await
+}
 
-    let shared_state = Arc::new(SharedState::new());
+/// Accepts TLS connections, typically on port 6697.
+#[cfg(test)]
+pub async fn run_tls_server(address: &str, shared_state: Arc<SharedState>, tls: Arc<TlsSettings>) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(address).await?;
+    log::info!("Server listening for TLS connections on {}", address);
+    accept_loop(BoundListener::Tcp(listener), shared_state, Some(tls), address.to_string()).await
+}
 
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        let state = Arc::clone(&shared_state);
+/// Listeners started from the config, keyed by address so a rehash can start, stop and
+/// reconfigure them without touching established connections.
+pub struct Listeners {
+    shared_state: Arc<SharedState>,
+    running: AsyncMutex<HashMap<String, RunningListener>>,
+    /// Sockets passed by systemd, kept open so a rehash can listen on them again.
+    inherited: Vec<OwnedFd>,
+}
 
-        tokio::spawn(async move {
-            let mut rx = state.tx.subscribe();
-            let mut client = Client::new(generate_client_id(), socket, addr.ip());
-
-            loop {
-                tokio::select! {
-                    result = client.handle(Arc::clone(&state)) => {
-                        if let Err(e) = result {
-                            log::error!("Error handling client {}: {}", addr, e);
-                        }
-                        break;
+struct RunningListener {
+    tls: Option<Arc<TlsSettings>>,
+    task: JoinHandle<()>,
+    /// Unix socket file to remove once stopped.
+    socket_path: Option<PathBuf>,
+}
+
+impl RunningListener {
+    async fn stop(self, address: &str) {
+        self.task.abort();
+        // Wait for the socket to be closed so the address can be bound again
+        let _ = self.task.await;
+        if let Some(path) = &self.socket_path {
+            let _ = fs::remove_file(path);
+        }
+        log::info!("Stopped listening on {}", address);
+    }
+}
+
+impl Listeners {
+    pub fn new(shared_state: Arc<SharedState>) -> Self {
+        Listeners {
+            shared_state,
+            running: AsyncMutex::new(HashMap::new()),
+            inherited: Vec::new(),
+        }
+    }
+
+    /// Makes the sockets passed by systemd available as `systemd:N` listeners.
+    pub fn with_inherited(mut self, sockets: Vec<OwnedFd>) -> Self {
+        self.inherited = sockets;
+        self
+    }
+
+    /// Makes the running listeners match `configs`, reloading the certificates of TLS
+    /// listeners that stay. Returns what could not be applied.
+    pub async fn apply(&self, configs: &[ListenerConfig]) -> Vec<String> {
+        let mut running = self.running.lock().await;
+        let mut problems = Vec::new();
+
+        // Stop listeners that were removed or switched between plain and TLS
+        let stale: Vec<String> = running.iter()
+            .filter(|(address, listener)| {
+                !configs.iter().any(|c| &c.address == *address && c.tls.is_some() == listener.tls.is_some())
+            })
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in stale {
+            if let Some(listener) = running.remove(&address) {
+                listener.stop(&address).await;
+            }
+        }
+
+        for config in configs {
+            if let Some(listener) = running.get(&config.address) {
+                if let (Some(tls), Some(files)) = (&listener.tls, &config.tls) {
+                    if let Err(e) = tls.reload(&files.cert, &files.key) {
+                        problems.push(format!("Keeping the current certificate for {}: {}", config.address, e));
                     }
-                    Ok(msg) = rx.recv() => {
-                        if let Err(e) = client.send(&msg).await {
-                            log::error!("Error sending message to client {}: {}", addr, e);
-                            break;
-                        }
+                }
+                continue;
+            }
+
+            let tls = match &config.tls {
+                Some(files) => match TlsSettings::load(&files.cert, &files.key) {
+                    Ok(settings) => Some(Arc::new(settings)),
+                    Err(e) => {
+                        problems.push(format!("Cannot load the certificate for {}: {}", config.address, e));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let listener = match self.bind(&config.address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    problems.push(format!("Cannot listen on {}: {}", config.address, e));
+                    continue;
+                }
+            };
+            log::info!("Server listening{} on {}", if tls.is_some() { " for TLS connections" } else { "" }, config.address);
+
+            let state = Arc::clone(&self.shared_state);
+            let task_tls = tls.clone();
+            let address = config.address.clone();
+            let task = tokio::spawn(async move {
+                if let Err(e) = accept_loop(listener, state, task_tls, address.clone()).await {
+                    log::error!("Listener on {} stopped: {}", address, e);
+                }
+            });
+            let socket_path = match ListenAddress::parse(&config.address) {
+                Some(ListenAddress::Unix(path)) => Some(path),
+                _ => None,
+            };
+            running.insert(config.address.clone(), RunningListener { tls, task, socket_path });
+        }
+
+        problems
+    }
+
+    /// Opens the socket for a listener: binds its address or Unix socket path, or takes
+    /// the socket systemd passed for it.
+    async fn bind(&self, address: &str) -> io::Result<BoundListener> {
+        match ListenAddress::parse(address) {
+            Some(ListenAddress::Unix(path)) => {
+                // A socket file left behind by a previous run would make binding fail
+                if fs::symlink_metadata(&path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+                    fs::remove_file(&path)?;
+                }
+                Ok(BoundListener::Unix(UnixListener::bind(&path)?))
+            }
+            Some(ListenAddress::Systemd(index)) => {
+                let fd = self.inherited.get(index).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("systemd passed {} socket(s)", self.inherited.len()))
+                })?;
+                match PassedListener::from_fd(fd)? {
+                    PassedListener::Tcp(listener) => {
+                        listener.set_nonblocking(true)?;
+                        Ok(BoundListener::Tcp(TcpListener::from_std(listener)?))
+                    }
+                    PassedListener::Unix(listener) => {
+                        listener.set_nonblocking(true)?;
+                        Ok(BoundListener::Unix(UnixListener::from_std(listener)?))
+                    }
+                }
+            }
+            _ => Ok(BoundListener::Tcp(TcpListener::bind(address).await?)),
+        }
+    }
+
+    #[cfg(test)]
+    pub async fn addresses(&self) -> Vec<String> {
+        let mut addresses: Vec<String> = self.running.lock().await.keys().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// Stops accepting connections, then gives clients up to `timeout` to be told about
+    /// the shutdown and disconnect before the server state is saved.
+    pub async fn shutdown(&self, timeout: Duration) {
+        for (address, listener) in self.running.lock().await.drain() {
+            listener.stop(&address).await;
+        }
+
+        // Each connection closes itself once it sees the shutdown request
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = self.shared_state.users.lock().unwrap().len();
+            if remaining == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                log::warn!("{} client(s) still connected after {:?}, shutting down anyway", remaining, timeout);
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        persist_state(&self.shared_state).await;
+    }
+
+    /// Applies the listeners of every config swapped in by a rehash. Listeners that could
+    /// not be started or updated are reported to the opers allowed to rehash.
+    pub fn follow_rehashes(self: Arc<Self>) {
+        let mut updates = self.shared_state.config.subscribe();
+        tokio::spawn(async move {
+            while updates.changed().await.is_ok() {
+                let config = updates.borrow_and_update().clone();
+                for problem in self.apply(&config.listeners).await {
+                    log::error!("{}", problem);
+                    let notices: Vec<(usize, String)> = self.shared_state.users.lock().unwrap().values()
+                        .filter(|user| user.oper.as_deref().map(|oper| config.oper_has_privilege(oper, "rehash")).unwrap_or(false))
+                        .map(|user| (user.id, format!(":{} NOTICE {} :*** Rehash: {}", config.server.name, user.reply_nick(), problem)))
+                        .collect();
+                    for (recipient_id, notice) in notices {
+                        send_to(&self.shared_state, recipient_id, &notice);
                     }
                 }
             }
@@ -61,47 +303,389 @@ pub async fn start_server(address: &str, _log_level: LevelFilter) -> Result<(),
     }
 }
 
-pub async fn handle_client(socket: tokio::net::TcpStream, state: Arc<SharedState>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_id = generate_client_id();
-    let mut client = Client::new(client_id, socket, addr.ip());
-    
-    // Initialize client state
-    {
-        let mut users = state.users.lock().unwrap();
-        users.insert(client_id, client.user.clone());
+/// Saves everything kept on disk, logging what could not be written.
+async fn persist_state(state: &SharedState) {
+    for channel in state.channels.lock().unwrap().values() {
+        if let Err(e) = channel.write_state() {
+            log::error!("Cannot save the state of {}: {}", channel.name, e);
+        }
+    }
+    let saved = state.bans.lock().unwrap().save();
+    if let Err(e) = saved.wait().await {
+        log::error!("Cannot save bans: {}", e);
+    }
+    if let Err(e) = state.history.flush() {
+        log::error!("Cannot save message history: {}", e);
     }
+}
 
-    log::info!("New client connected: {}", addr);
+/// Socket a listener accepts connections on.
+enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A connection accepted on a listener, before a client is created for it.
+struct Connection {
+    /// Address of the client, after any PROXY header or X-Forwarded-For.
+    addr: SocketAddr,
+    /// Address of the listener in the config.
+    listener: String,
+    /// Client certificate fingerprint of a TLS connection, if the client sent one.
+    tls: Option<Option<String>>,
+    /// Host name of a client on a Unix socket.
+    local_host: Option<String>,
+    admission: Admission,
+}
+
+/// A connection's place among the open ones, which it holds until dropped.
+pub struct Admission {
+    pub id: usize,
+    /// Set while the per-address limits wait for a trusted proxy to pass on the
+    /// client's address.
+    deferred: bool,
+    connections: Arc<Mutex<HashMap<usize, Option<IpAddr>>>>,
+}
 
-    let mut rx = state.tx.subscribe();
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.id);
+    }
+}
 
+/// Time a refused client has to take the ERROR line before the connection is dropped.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts connections on the listener configured as `address`.
+async fn accept_loop(listener: BoundListener, shared_state: Arc<SharedState>, tls: Option<Arc<TlsSettings>>, address: String) -> Result<(), Box<dyn std::error::Error>> {
+    // Take the current certificate; a later reload only affects new connections
+    let acceptor = || tls.as_ref().map(|tls| tls.acceptor());
+    // Refusals can only be read on connections without a handshake to go through first
+    let plain = || tls.is_none() && !shared_state.config.get().listener(&address).map(|listener| listener.websocket).unwrap_or(false);
     loop {
-        tokio::select! {
-            result = client.handle(state.clone()) => {
-                if let Err(e) = result {
-                    log::error!("Error handling client {}: {}", addr, e);
-                }
-                break;
+        let state = Arc::clone(&shared_state);
+        match &listener {
+            BoundListener::Tcp(listener) => {
+                let (socket, peer) = listener.accept().await?;
+                let admission = match admit_connection(&state, &address, peer, false) {
+                    Ok(admission) => admission,
+                    Err(error) => {
+                        if plain() {
+                            tokio::spawn(send_refusal(socket, error));
+                        }
+                        continue;
+                    }
+                };
+                let connection = Connection { addr: peer, listener: address.clone(), tls: None, local_host: None, admission };
+                tokio::spawn(accept_connection(socket, connection, state, acceptor()));
             }
-            Ok(msg) = rx.recv() => {
-                if let Some((recipient_id, message)) = msg.split_once(':') {
-                    if recipient_id.parse::<usize>().ok() == Some(client_id) {
-                        if let Err(e) = client.send(message).await {
-                            log::error!("Error sending message to client {}: {}", addr, e);
-                            break;
+            BoundListener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                // Unix clients have no address; they get the loopback one and a host name
+                let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+                let admission = match admit_connection(&state, &address, addr, true) {
+                    Ok(admission) => admission,
+                    Err(error) => {
+                        if plain() {
+                            tokio::spawn(send_refusal(socket, error));
                         }
+                        continue;
                     }
-                }
+                };
+                let local_host = state.config.get().listener(&address).map(|listener| listener.client_host().to_string())
+                    .unwrap_or_else(|| "localhost".to_string());
+                let connection = Connection {
+                    addr,
+                    listener: address.clone(),
+                    tls: None,
+                    local_host: Some(local_host),
+                    admission,
+                };
+                tokio::spawn(accept_connection(socket, connection, state, acceptor()));
             }
         }
     }
+}
 
-    // Clean up client state
-    {
-        let mut users = state.users.lock().unwrap();
-        users.remove(&client_id);
+/// Sends the ERROR line a connection was refused with, giving up on a client that
+/// doesn't read it.
+async fn send_refusal<S: AsyncWrite + Unpin>(mut socket: S, error: String) {
+    let _ = timeout(REFUSAL_TIMEOUT, socket.write_all(format!("{}\r\n", error).as_bytes())).await;
+}
+
+/// Reads any PROXY header and completes any TLS handshake on a new connection.
+async fn accept_connection<S>(mut socket: S, mut connection: Connection, state: Arc<SharedState>, acceptor: Option<TlsAcceptor>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let peer = connection.addr;
+    connection.addr = match proxied_address(&mut socket, peer, &state, &connection.listener).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::warn!("Dropping connection from {} on {}: {}", peer, connection.listener, e);
+            return;
+        }
+    };
+
+    match acceptor {
+        None => serve_connection(socket, connection, state).await,
+        Some(acceptor) => {
+            let stream = match timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::warn!("TLS handshake with {} failed: {}", connection.addr, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("TLS handshake with {} timed out", connection.addr);
+                    return;
+                }
+            };
+            connection.tls = Some(stream.get_ref().1.peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| certificate_fingerprint(&cert.0)));
+            serve_connection(stream, connection, state).await;
+        }
+    }
+}
+
+/// Serves a connection once it is through any TLS handshake. WebSocket listeners
+/// complete the upgrade first.
+async fn serve_connection<S>(stream: S, mut connection: Connection, state: Arc<SharedState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let listener = state.config.get().listener(&connection.listener).cloned().unwrap_or_default();
+    if !listener.websocket {
+        return start_client(stream, connection, state).await;
+    }
+    match timeout(websocket::HANDSHAKE_TIMEOUT, websocket::accept(stream, connection.addr, &listener)).await {
+        Ok(Ok((lines, addr))) => {
+            connection.addr = addr;
+            start_client(lines, connection, state).await;
+        }
+        Ok(Err(e)) => log::warn!("WebSocket handshake with {} failed: {}", connection.addr, e),
+        Err(_) => log::warn!("WebSocket handshake with {} timed out", connection.addr),
+    }
+}
+
+async fn start_client<S>(stream: S, connection: Connection, state: Arc<SharedState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = connection.addr;
+    let mut client = Client::new(connection.admission.id, stream, addr.ip());
+    if let Some(certfp) = connection.tls {
+        client.user.secure = true;
+        client.user.certfp = certfp;
+    }
+    client.user.unix_socket = connection.local_host.is_some();
+    client.user.hostname = connection.local_host;
+    client.user.listener = Some(connection.listener);
+    if let Err(e) = serve_client(client, state, addr, connection.admission).await {
+        log::error!("Error handling client {}: {}", addr, e);
+    }
+}
+
+/// Address of the client behind `peer`. Listeners with `proxy` set expect a PROXY
+/// protocol header from a trusted proxy before anything else, TLS included.
+async fn proxied_address<S: AsyncRead + Unpin>(socket: &mut S, peer: SocketAddr, state: &SharedState, address: &str) -> io::Result<SocketAddr> {
+    let trusted = match state.config.get().listener(address) {
+        Some(listener) if listener.proxy => listener.trusted_proxies.clone(),
+        _ => return Ok(peer),
+    };
+    if !trusted.iter().any(|mask| ip_mask_matches(mask, peer.ip())) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not a trusted proxy"));
+    }
+    let header = timeout(PROXY_TIMEOUT, read_proxy_header(socket)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY header received"))??;
+    Ok(header.unwrap_or(peer))
+}
+
+#[cfg(test)]
+pub async fn handle_client<S>(mut socket: S, state: Arc<SharedState>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let admission = match admit_connection(&state, "", addr, false) {
+        Ok(admission) => admission,
+        Err(error) => {
+            socket.write_all(format!("{}\r\n", error).as_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let client = Client::new(admission.id, socket, addr.ip());
+    serve_client(client, state, addr, admission).await
+}
+
+/// Serves a client through to its disconnection. `admission` is its place among the
+/// open connections, given up once this returns.
+pub async fn serve_client<S>(mut client: Client<S>, state: Arc<SharedState>, addr: SocketAddr, admission: Admission) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client_id = client.id;
+
+    // D-lines refuse the connection before anything else happens
+    let dline = state.bans.lock().unwrap().find_ip(addr.ip());
+    if let Some(ban) = dline {
+        log::info!("Refusing D-lined client {} ({})", addr, ban.mask);
+        let notices = server_notices(&state.config.get(), &state.users.lock().unwrap(), 'x', &format!("Rejecting D-lined connection from {} [{}]", addr.ip(), ban.mask));
+        for (recipient_id, notice) in notices {
+            send_to(&state, recipient_id, &notice);
+        }
+        client.send(&format!("ERROR :Closing Link: {} ({}: {})", addr.ip(), ban.kind.action(), ban.reason)).await?;
+        return Ok(());
+    }
+
+    // Listeners can be restricted to some addresses, such as a port for local bots
+    let allowed = client.user.listener.as_deref()
+        .and_then(|address| state.config.get().listener(address).map(|listener| listener.allow.clone()))
+        .map(|allow| allow.is_empty() || allow.iter().any(|mask| ip_mask_matches(mask, addr.ip())))
+        .unwrap_or(true);
+    if !allowed {
+        log::info!("Refusing client {}: not allowed on this listener", addr);
+        client.send(&format!("ERROR :Closing Link: {} (Not allowed on this port)", addr.ip())).await?;
+        return Ok(());
+    }
+
+    // Initialize client state, unless the connection limits turn it away
+    if let Err(error) = admit(&state, &client.user, &admission) {
+        client.send(&error).await?;
+        return Ok(());
+    }
+
+    log::info!("New client connected: {}", addr);
+    identify_host(&mut client, &state).await?;
+
+    if let Err(e) = client.handle(state.clone()).await {
+        log::error!("Error handling client {}: {}", addr, e);
+    }
+
+    // Clean up client state, telling channel members the user is gone
+    let quit = Command::Quit(Some("Connection closed".to_string()));
+    if let Ok(responses) = handle_command(quit, client_id, &state.handler_state()).await {
+        for (recipient_id, response) in responses {
+            if recipient_id != client_id {
+                send_to(&state, recipient_id, &response);
+            }
+        }
     }
 
     log::info!("Client disconnected: {}", addr);
     Ok(())
 }
+
+/// Looks up the client's hostname if enabled and works out their cloak, before the
+/// client gets to register with the host in place.
+async fn identify_host<S>(client: &mut Client<S>, state: &SharedState) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = state.config.get();
+    let ip = client.user.host;
+    // Unix socket clients already have the listener's host name
+    if config.dns.resolve && !client.user.unix_socket {
+        let notice = |text: &str| format!(":{} NOTICE * :*** {}", config.server.name, text);
+        client.send(&notice("Looking up your hostname...")).await?;
+        client.user.hostname = resolve_hostname(state.resolver.as_ref(), ip, Duration::from_secs(config.dns.timeout)).await;
+        let result = if client.user.hostname.is_some() { "Found your hostname" } else { "Couldn't look up your hostname" };
+        client.send(&notice(result)).await?;
+    }
+    if let Some(key) = &config.cloak.key {
+        client.user.cloaked_host = Some(cloak_host(key, ip, client.user.hostname.as_deref()));
+        client.user.cloaked = config.cloak.automatic;
+    }
+
+    if let Some(user) = state.users.lock().unwrap().get_mut(&client.id) {
+        user.hostname = client.user.hostname.clone();
+        user.cloaked_host = client.user.cloaked_host.clone();
+        user.cloaked = client.user.cloaked;
+    }
+    Ok(())
+}
+
+/// Checks the connection limits for a connection just accepted from `addr` on the
+/// listener at `address`, counting every open connection, including those still in a
+/// TLS, PROXY or WebSocket handshake. Returns the connection's place, or the ERROR line
+/// it is refused with once opers have been told.
+fn admit_connection(state: &SharedState, address: &str, addr: SocketAddr, unix_socket: bool) -> Result<Admission, String> {
+    let ip = addr.ip();
+    // Unix socket clients all share the loopback address, so only the total applies to
+    // them. A trusted proxy's clients are checked once the proxy says who they are.
+    let deferred = !unix_socket && state.config.get().listener(address)
+        .map(|listener| (listener.proxy || listener.websocket) && listener.trusted_proxies.iter().any(|mask| ip_mask_matches(mask, ip)))
+        .unwrap_or(false);
+    let per_address = !unix_socket && !deferred;
+    let id = generate_client_id();
+
+    let mut connections = state.connections.lock().unwrap();
+    if let Some(refusal) = limit_refusal(state, &connections, id, ip, per_address, true) {
+        drop(connections);
+        return Err(refuse(state, addr, refusal));
+    }
+    connections.insert(id, per_address.then_some(ip));
+    Ok(Admission { id, deferred, connections: Arc::clone(&state.connections) })
+}
+
+/// Adds `user` to the connected users, once the per-address limits allow it if they
+/// had to wait for the client's address. Otherwise returns the ERROR line the
+/// connection is refused with.
+fn admit(state: &SharedState, user: &User, admission: &Admission) -> Result<(), String> {
+    if admission.deferred {
+        let mut connections = state.connections.lock().unwrap();
+        if let Some(refusal) = limit_refusal(state, &connections, admission.id, user.host, true, false) {
+            drop(connections);
+            return Err(refuse(state, SocketAddr::new(user.host, 0), refusal));
+        }
+        connections.insert(admission.id, Some(user.host));
+    }
+    state.users.lock().unwrap().insert(user.id, user.clone());
+    Ok(())
+}
+
+/// Why the connection limits refuse connection `id` from `ip`, given the open
+/// `connections`: the ERROR line to close it with and a notice for opers. The total is
+/// only checked with `total` set, and the throttle and per-address limits only with
+/// `per_address`.
+fn limit_refusal(state: &SharedState, connections: &HashMap<usize, Option<IpAddr>>, id: usize, ip: IpAddr, per_address: bool, total: bool) -> Option<(String, String)> {
+    let config = state.config.get();
+    let limits = &config.limits;
+    let exempt = per_address && limits.connection_exempt.iter().any(|mask| ip_mask_matches(mask, ip));
+    let throttled = per_address && !exempt && state.throttle.lock().unwrap().attempt(
+        network(ip, limits.ipv4_cidr, limits.ipv6_cidr),
+        limits.throttle_connections,
+        Duration::from_secs(limits.throttle_seconds),
+        Instant::now(),
+    );
+
+    let others = || connections.iter().filter(|(other, _)| **other != id).filter_map(|(_, other)| *other);
+    let closing = |reason: &str| format!("ERROR :Closing Link: {} ({})", ip, reason);
+    if exempt {
+        None
+    } else if throttled {
+        Some((closing("Throttled: reconnecting too fast"), format!("Throttling connection from {}", ip)))
+    } else if total && connections.len() >= limits.max_clients {
+        Some(("ERROR :Server full".to_string(), format!("Rejecting connection from {}: server full ({} clients)", ip, connections.len())))
+    } else if !per_address {
+        None
+    } else if limits.ip_connections > 0 && others().filter(|other| *other == ip).count() >= limits.ip_connections {
+        Some((closing("Too many connections from your host"), format!("Rejecting connection from {}: too many connections from the host", ip)))
+    } else if limits.cidr_connections > 0
+        && others().filter(|other| same_network(ip, *other, limits.ipv4_cidr, limits.ipv6_cidr)).count() >= limits.cidr_connections
+    {
+        Some((closing("Too many connections from your network"), format!("Rejecting connection from {}: too many connections from the network", ip)))
+    } else {
+        None
+    }
+}
+
+/// Logs a refused connection and tells opers about it, returning the ERROR line.
+fn refuse(state: &SharedState, addr: SocketAddr, (error, notice): (String, String)) -> String {
+    log::info!("Refusing client {}: {}", addr, error);
+    let notices = server_notices(&state.config.get(), &state.users.lock().unwrap(), 'c', &notice);
+    for (recipient_id, notice) in notices {
+        send_to(state, recipient_id, &notice);
+    }
+    error
+}
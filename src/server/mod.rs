@@ -1,3 +1,13 @@
 
 pub mod listener;
 pub mod client;
+pub mod tls;
+pub mod shutdown;
+pub mod flood;
+pub mod throttle;
+pub mod sendq;
+pub mod resolver;
+pub mod proxy;
+pub mod websocket;
+pub mod systemd;
+pub mod codec;
@@ -0,0 +1,99 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Duration;
+
+/// Time a proxy has to send its header once connected.
+pub const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First bytes of a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the HAProxy PROXY protocol header, version 1 or 2, from the start of
+/// `stream` and returns the client address it carries. Connections the proxy makes
+/// on its own behalf, such as health checks, give `None`.
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are longer than the v2 signature, so this never reads past the header
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Reads the rest of a text header such as `PROXY TCP4 192.0.2.1 198.51.100.1 51000 6667`.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut header = start.to_vec();
+    // One byte at a time so nothing after the header is consumed
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("PROXY header is not ASCII"))?;
+    parse_v1(header)
+}
+
+pub fn parse_v1(header: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = header.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad PROXY source address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("PROXY source address does not match the protocol"));
+            }
+            let port = source_port.parse().map_err(|_| invalid("bad PROXY source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+/// Reads the rest of a binary header after its signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed).await?;
+    let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(fixed[0], fixed[1], &addresses)
+}
+
+pub fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY command")),
+    }
+    // Addresses are followed by the ports; anything after them (TLVs) is ignored
+    let (ip, port_offset) = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let octets: [u8; 4] = addresses[..4].try_into().unwrap();
+            (IpAddr::V4(Ipv4Addr::from(octets)), 8)
+        }
+        2 if addresses.len() >= 36 => {
+            let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+            (IpAddr::V6(Ipv6Addr::from(octets)), 32)
+        }
+        1 | 2 => return Err(invalid("PROXY address block too short")),
+        // Unspecified and Unix socket sources carry no usable client address
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes([addresses[port_offset], addresses[port_offset + 1]]);
+    Ok(Some(SocketAddr::new(ip, port)))
+}
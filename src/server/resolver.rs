@@ -0,0 +1,51 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use crate::utils::is_valid_host;
+use tokio::time::{timeout, Duration};
+
+pub type Lookup<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// DNS lookups for client hostnames, replaceable so tests don't depend on real DNS.
+pub trait Resolver: Send + Sync {
+    /// Name the PTR record of `ip` points to.
+    fn reverse(&self, ip: IpAddr) -> Lookup<'_, String>;
+    /// Addresses `host` resolves to.
+    fn forward<'a>(&'a self, host: &'a str) -> Lookup<'a, Vec<IpAddr>>;
+}
+
+/// Resolver using the system's DNS configuration.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn reverse(&self, ip: IpAddr) -> Lookup<'_, String> {
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip))
+                .await
+                .map_err(io::Error::other)?
+        })
+    }
+
+    fn forward<'a>(&'a self, host: &'a str) -> Lookup<'a, Vec<IpAddr>> {
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Hostname for `ip`, if its reverse lookup names a valid host that resolves back to
+/// `ip` within `limit`. Unconfirmed names are ignored since anyone controlling the
+/// reverse zone could otherwise claim an arbitrary host.
+pub async fn resolve_hostname(resolver: &dyn Resolver, ip: IpAddr, limit: Duration) -> Option<String> {
+    let lookup = async {
+        let name = resolver.reverse(ip).await.ok()?;
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.parse::<IpAddr>().is_ok() || !is_valid_host(&name) || name.len() > 63 {
+            return None;
+        }
+        let addresses = resolver.forward(&name).await.ok()?;
+        addresses.iter().any(|address| address.to_canonical() == ip.to_canonical()).then_some(name)
+    };
+    timeout(limit, lookup).await.ok().flatten()
+}
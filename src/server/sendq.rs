@@ -0,0 +1,91 @@
+use crate::models::stats::LinkTraffic;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// Outbound lines of a connection. A separate task writes them, so a client that stops
+/// reading fills its own queue instead of holding up command handling. Other connections
+/// queue their lines to this client through a clone.
+#[derive(Clone)]
+pub struct SendQueue {
+    lines: mpsc::UnboundedSender<String>,
+    traffic: Arc<LinkTraffic>,
+    /// Wakes the connection's own loop when another connection overflowed the queue or
+    /// sent it a line that ends the connection.
+    alert: Arc<Notify>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl SendQueue {
+    /// Starts the task writing queued lines to `writer`.
+    pub fn start<W>(writer: W, traffic: Arc<LinkTraffic>) -> (Self, JoinHandle<io::Result<()>>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (lines, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_lines(writer, receiver, Arc::clone(&traffic)));
+        let queue = SendQueue { lines, traffic, alert: Arc::new(Notify::new()), overflowed: Arc::new(AtomicBool::new(false)) };
+        (queue, task)
+    }
+
+    /// Queues `line` unless that would take the queue past `limit` bytes, which means the
+    /// client is not keeping up. Returns whether the line was queued.
+    pub fn push(&self, line: &str, limit: usize) -> bool {
+        let bytes = line.len() + 2;
+        if self.traffic.queued() as usize + bytes > limit {
+            return false;
+        }
+        self.traffic.record_sent(bytes);
+        self.traffic.record_queued(bytes);
+        self.lines.send(line.to_string()).is_ok()
+    }
+
+    /// Queues all of `lines`, or none of them if together they would take the queue past
+    /// `limit` bytes. Returns whether they were queued.
+    pub fn push_all(&self, lines: &[String], limit: usize) -> bool {
+        let bytes: usize = lines.iter().map(|line| line.len() + 2).sum();
+        if self.traffic.queued() as usize + bytes > limit {
+            return false;
+        }
+        lines.iter().all(|line| self.push(line, limit))
+    }
+
+    /// Marks the queue as overflowed by a line from another connection.
+    pub fn overflow(&self) {
+        self.overflowed.store(true, Ordering::Relaxed);
+        self.alert.notify_one();
+    }
+
+    /// Wakes the connection's loop to check whether it has been closed.
+    pub fn alert(&self) {
+        self.alert.notify_one();
+    }
+
+    /// Resolves once the queue was alerted or overflowed, returning whether it overflowed.
+    /// Cancel safe, so it can be raced in `select!`.
+    pub async fn alerted(&self) -> bool {
+        self.alert.notified().await;
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the writer has stopped, such as after a write error.
+    pub async fn closed(&self) {
+        self.lines.closed().await
+    }
+}
+
+async fn write_lines<W: AsyncWrite + Unpin>(mut writer: W, mut lines: mpsc::UnboundedReceiver<String>, traffic: Arc<LinkTraffic>) -> io::Result<()> {
+    while let Some(line) = lines.recv().await {
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
+        traffic.record_written(line.len() + 2);
+        // Flush once the queue is drained rather than after every line
+        if lines.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await
+}
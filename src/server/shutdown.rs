@@ -0,0 +1,65 @@
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownKind {
+    Stop,
+    /// Re-execute the binary once everyone has been disconnected.
+    Restart,
+}
+
+impl ShutdownKind {
+    /// Text of the ERROR sent to every client.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ShutdownKind::Stop => "Server shutting down",
+            ShutdownKind::Restart => "Server restarting",
+        }
+    }
+}
+
+/// Server-wide shutdown requested by a signal, DIE or RESTART. Client connections and
+/// `main` wait on it; the first request wins.
+pub struct Shutdown {
+    requested: watch::Sender<Option<(ShutdownKind, String)>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown { requested: watch::channel(None).0 }
+    }
+
+    /// Asks the server to shut down, returning false if a shutdown was already requested.
+    pub fn request(&self, kind: ShutdownKind, reason: String) -> bool {
+        self.requested.send_if_modified(|requested| {
+            if requested.is_some() {
+                return false;
+            }
+            log::info!("{} requested: {}", kind.message(), reason);
+            *requested = Some((kind, reason));
+            true
+        })
+    }
+
+    #[cfg(test)]
+    pub fn requested(&self) -> Option<(ShutdownKind, String)> {
+        self.requested.borrow().clone()
+    }
+
+    /// Resolves once a shutdown has been requested, immediately if one already was.
+    pub async fn wait(&self) -> (ShutdownKind, String) {
+        let mut receiver = self.requested.subscribe();
+        loop {
+            if let Some(request) = receiver.borrow_and_update().clone() {
+                return request;
+            }
+            // The sender lives as long as `self`, so this cannot fail
+            let _ = receiver.changed().await;
+        }
+    }
+}
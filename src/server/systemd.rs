@@ -0,0 +1,108 @@
+use std::io;
+use std::ops::Range;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use tokio::time::Duration;
+
+/// First descriptor passed by socket activation; the others follow it.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Descriptors passed to the process `pid` according to the LISTEN_PID and LISTEN_FDS
+/// variables. Variables meant for another process, such as a parent, pass nothing.
+pub fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<RawFd> {
+    let count = match (listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()), listen_fds.and_then(|count| count.parse::<RawFd>().ok())) {
+        (Some(listen_pid), Some(count)) if listen_pid == pid && count > 0 => count,
+        _ => 0,
+    };
+    LISTEN_FDS_START..LISTEN_FDS_START + count
+}
+
+/// Takes the sockets systemd passed to this process, in order. The variables are left
+/// in place since RESTART keeps the process ID and the descriptors, so the new binary
+/// takes them again.
+pub fn listen_fds() -> Vec<OwnedFd> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    passed_fds(listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())
+        // Safety: socket activation hands these descriptors to this process, and this is
+        // the only place they are taken
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect()
+}
+
+/// A passed socket, told apart by its address family.
+pub enum PassedListener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+impl PassedListener {
+    /// Duplicates `fd` into a listener, leaving `fd` open for a later rehash or restart.
+    pub fn from_fd(fd: &OwnedFd) -> io::Result<PassedListener> {
+        let tcp = std::net::TcpListener::from(fd.try_clone()?);
+        let listener = if tcp.local_addr().is_ok() {
+            PassedListener::Tcp(tcp)
+        } else {
+            let unix = UnixListener::from(OwnedFd::from(tcp));
+            unix.local_addr()?;
+            PassedListener::Unix(unix)
+        };
+        Ok(listener)
+    }
+}
+
+/// Sends `state`, such as `READY=1`, to the service manager when running under one
+/// with NOTIFY_SOCKET set. Returns whether there was one to tell.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => notify_socket(&socket, state).map(|_| true),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Sends `state` to the notification socket at `socket`; a leading `@` names an
+/// abstract socket.
+pub fn notify_socket(socket: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets need Linux")),
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1` to process `pid` given WATCHDOG_USEC and
+/// WATCHDOG_PID: half the timeout, so one late message doesn't get the server killed.
+pub fn watchdog_interval(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    if watchdog_pid.map(|watchdog_pid| watchdog_pid.parse() != Ok(pid)).unwrap_or(false) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Keeps the service manager's watchdog from firing for as long as the runtime runs.
+pub fn start_watchdog() {
+    let usec = std::env::var("WATCHDOG_USEC").ok();
+    let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+    if let Some(interval) = watchdog_interval(usec.as_deref(), watchdog_pid.as_deref(), std::process::id()) {
+        log::info!("Sending watchdog notifications every {:?}", interval);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = notify("WATCHDOG=1") {
+                    log::warn!("Cannot notify the service manager: {}", e);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
+
+/// Recent connection attempts per network block, used to throttle hosts that reconnect
+/// too quickly. A block keeps no more attempts than it takes to tell it is over the
+/// limit, and blocks without recent attempts are dropped once per window.
+#[derive(Debug, Default)]
+pub struct ConnectionThrottle {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When blocks without recent attempts were last dropped.
+    swept: Option<Instant>,
+}
+
+impl ConnectionThrottle {
+    pub fn new() -> Self {
+        ConnectionThrottle::default()
+    }
+
+    /// Records an attempt from the block starting at `network` and returns whether it
+    /// makes more than `limit` attempts within `window`. A limit of 0 never throttles.
+    pub fn attempt(&mut self, network: IpAddr, limit: usize, window: Duration, now: Instant) -> bool {
+        if limit == 0 {
+            return false;
+        }
+        if self.swept.map(|swept| now.duration_since(swept) >= window).unwrap_or(true) {
+            self.attempts.retain(|_, times| times.back().map(|last| now.duration_since(*last) < window).unwrap_or(false));
+            self.swept = Some(now);
+        }
+        let times = self.attempts.entry(network).or_default();
+        while times.front().map(|time| now.duration_since(*time) >= window).unwrap_or(false) {
+            times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() > limit + 1 {
+            times.pop_front();
+        }
+        times.len() > limit
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, DistinguishedName, PrivateKey, ServerConfig};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+use crate::utils::to_hex;
+
+/// Time a client has to finish the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate and key of a TLS listener. The server config is swapped on reload, so
+/// connections that are already established keep the config they were accepted with.
+pub struct TlsSettings {
+    server_config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsSettings {
+    pub fn load<P: AsRef<Path>>(cert_path: P, key_path: P) -> io::Result<Self> {
+        let server_config = build_server_config(cert_path.as_ref(), key_path.as_ref())?;
+        Ok(TlsSettings {
+            server_config: RwLock::new(Arc::new(server_config)),
+        })
+    }
+
+    /// Re-reads the certificate and key, which may come from other files when the config
+    /// changed. On error the previous certificate stays in use.
+    pub fn reload(&self, cert_path: &Path, key_path: &Path) -> io::Result<()> {
+        let server_config = build_server_config(cert_path, key_path)?;
+        *self.server_config.write().unwrap() = Arc::new(server_config);
+        log::info!("Reloaded TLS certificate {}", cert_path.display());
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(&self.server_config.read().unwrap()))
+    }
+}
+
+fn build_server_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(AcceptAnyClientCert))
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", cert_path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates found in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("No private key found in {}", path.display())))
+}
+
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex.
+pub fn certificate_fingerprint(cert: &[u8]) -> String {
+    to_hex(&Sha256::digest(cert))
+}
+
+/// Requests a client certificate without requiring one or checking who issued it.
+/// Certificates are only used to identify clients by fingerprint (SASL EXTERNAL, WHOIS).
+struct AcceptAnyClientCert;
+
+impl ClientCertVerifier for AcceptAnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
@@ -0,0 +1,140 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use crate::config::ListenerConfig;
+use crate::utils::{ip_mask_matches, wildcard_match};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Time a client has to finish the WebSocket handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// IRCv3 subprotocols: lines as UTF-8 text frames or as raw binary frames.
+pub const TEXT_PROTOCOL: &str = "text.ircv3.net";
+pub const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+/// Largest frame accepted, enough for a line with the maximum tags.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// Completes the WebSocket handshake on `stream` and returns a byte stream carrying
+/// the connection's IRC lines, as a TCP client would send them, along with the client
+/// address: `peer`, or the one forwarded by a trusted reverse proxy.
+pub async fn accept<S>(stream: S, peer: SocketAddr, listener: &ListenerConfig) -> io::Result<(DuplexStream, SocketAddr)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut binary = false;
+    let mut addr = peer;
+    // The error type is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        let origin = request.headers().get("Origin").and_then(|origin| origin.to_str().ok());
+        if !listener.origins.is_empty() && !origin.map(|origin| listener.origins.iter().any(|allowed| wildcard_match(allowed, origin))).unwrap_or(false) {
+            let mut refusal = ErrorResponse::new(Some("Origin not allowed".to_string()));
+            *refusal.status_mut() = StatusCode::FORBIDDEN;
+            return Err(refusal);
+        }
+
+        let offered = request.headers().get_all("Sec-WebSocket-Protocol").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim);
+        // Clients offering neither subprotocol get text frames
+        if let Some(protocol) = offered.into_iter().find(|protocol| *protocol == TEXT_PROTOCOL || *protocol == BINARY_PROTOCOL) {
+            binary = protocol == BINARY_PROTOCOL;
+            response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(if binary { BINARY_PROTOCOL } else { TEXT_PROTOCOL }));
+        }
+
+        let forwarded: Vec<&str> = request.headers().get_all("X-Forwarded-For").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        if let Some(ip) = forwarded_address(peer.ip(), &forwarded, &listener.trusted_proxies) {
+            addr = SocketAddr::new(ip, 0);
+        }
+        Ok(response)
+    };
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let socket = tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config)).await
+        .map_err(io::Error::other)?;
+
+    let (client_end, bridge_end) = tokio::io::duplex(MAX_MESSAGE_SIZE);
+    tokio::spawn(bridge(socket, bridge_end, binary));
+    Ok((client_end, addr))
+}
+
+/// Client address behind `peer` given the X-Forwarded-For entries. Entries are taken
+/// from the right, each one only while the address it came from is a trusted proxy,
+/// so a client can't claim an address by sending the header itself.
+pub fn forwarded_address(peer: IpAddr, forwarded: &[&str], trusted_proxies: &[String]) -> Option<IpAddr> {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|mask| ip_mask_matches(mask, ip));
+    let mut client = peer;
+    for entry in forwarded.iter().rev() {
+        if !trusted(client) {
+            break;
+        }
+        client = entry.parse().ok()?;
+    }
+    (client != peer).then_some(client)
+}
+
+/// Moves lines between the WebSocket and the client's end of `lines` until either side
+/// closes: each frame received becomes a CRLF terminated line and each line written to
+/// the client becomes a frame. A frame holding a line break or NUL anywhere but at its
+/// end closes the connection, since it would smuggle in more than one line.
+async fn bridge<S>(socket: tokio_tungstenite::WebSocketStream<S>, lines: DuplexStream, binary: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut source) = socket.split();
+    let (reader, mut writer) = tokio::io::split(lines);
+
+    let incoming = async move {
+        while let Some(Ok(message)) = source.next().await {
+            let mut line = match message {
+                Message::Text(text) => text.into_bytes(),
+                Message::Binary(data) => data,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            while line.last().map(|byte| *byte == b'\n' || *byte == b'\r').unwrap_or(false) {
+                line.pop();
+            }
+            if line.iter().any(|byte| matches!(byte, b'\r' | b'\n' | b'\0')) {
+                log::warn!("Closing WebSocket connection that sent a frame with a line break or NUL");
+                break;
+            }
+            line.extend_from_slice(b"\r\n");
+            if writer.write_all(&line).await.is_err() {
+                break;
+            }
+        }
+        // The client sees the end of the stream and quits
+        let _ = writer.shutdown().await;
+    };
+
+    let outgoing = async move {
+        let mut lines = BufReader::new(reader).split(b'\n');
+        while let Ok(Some(mut line)) = lines.next_segment().await {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let message = if binary { Message::Binary(line) } else { Message::Text(String::from_utf8_lossy(&line).into_owned()) };
+            if sink.send(message).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.send(Message::Close(None)).await;
+    };
+
+    tokio::join!(incoming, outgoing);
+}
@@ -1,8 +1,16 @@
 
-use crate::commands::parser::Command;
-use crate::commands::handler::{handle_command, SharedState};
+use crate::commands::parser::{command_name, parse_command, parse_message, Command};
+use crate::commands::batch::CommandReplies;
+use crate::commands::handler::{handle_command, handle_tagged_command, SharedState};
+use crate::config::{Config, ConfigHandle, Overrides};
+use crate::models::motd::Motd;
 use crate::models::user::User;
 use crate::models::channel::Channel;
+use crate::models::account::FileAccountStore;
+use crate::models::ban::{Ban, BanKind};
+use crate::server::shutdown::ShutdownKind;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -15,6 +23,7 @@ async fn test_handle_nick_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
     };
 
     let command = Command::Nick("newname".to_string());
@@ -23,8 +32,18 @@ async fn test_handle_nick_command() {
     let messages = result.unwrap();
     assert_eq!(messages, vec![(1, ":<unknown> NICK :newname".to_string())]);
 
-    let users = shared_state.users.lock().unwrap();
-    assert_eq!(users.get(&1).unwrap().nickname, Some("newname".to_string()));
+    {
+        let users = shared_state.users.lock().unwrap();
+        assert_eq!(users.get(&1).unwrap().nickname, Some("newname".to_string()));
+    }
+
+    // Nicknames in use, in any case, are refused; changing the case of your own is not
+    shared_state.users.lock().unwrap().insert(2, User::new(2, "127.0.0.1".parse().unwrap()));
+    let messages = handle_command(Command::Nick("NewName".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(2, ":server 433 * NewName :Nickname is already in use".to_string())]);
+    assert_eq!(shared_state.users.lock().unwrap()[&2].nickname, None);
+    let messages = handle_command(Command::Nick("NewName".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":newname NICK :NewName".to_string())]);
 }
 
 #[tokio::test]
@@ -36,19 +55,25 @@ async fn test_handle_user_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
     };
 
     let command = Command::User("username".to_string(), "0".to_string(), "realname".to_string());
     let result = handle_command(command, 1, &shared_state).await;
     assert!(result.is_ok());
     let messages = result.unwrap();
-    assert_eq!(messages.len(), 6);
+    assert_eq!(messages.len(), 10);
     assert!(messages[0].1.contains("001 User1 :Welcome to the IRC server!"));
-    assert!(messages[1].1.contains("002 User1 :Your host is rustirc2"));
+    assert!(messages[1].1.contains("002 User1 :Your host is server, running version rustirc2-"));
     assert!(messages[2].1.contains("003 User1 :This server was created"));
-    assert!(messages[3].1.contains("004 User1 rustirc2 1.0 o o"));
-    assert!(messages[4].1.contains("005 User1 CHANTYPES=# CHARSET=utf-8"));
+    assert!(messages[3].1.contains("004 User1 server rustirc2-"));
+    assert!(messages[4].1.contains("005 User1 CASEMAPPING=ascii CHANNELLEN=50 CHANTYPES=# CHARSET=utf-8"));
+    assert!(messages[4].1.contains(" TOPICLEN=390 UTF8ONLY :are supported by this server"));
     assert!(messages[5].1.contains("251 User1 :There are 1 users and 0 services on 1 server"));
+    assert!(messages[6].1.contains("255 User1 :I have 1 clients and 0 servers"));
+    assert!(messages[7].1.contains("265 User1 1 1 :Current local users 1, max 1"));
+    assert!(messages[8].1.contains("266 User1 1 1 :Current global users 1, max 1"));
+    assert!(messages[9].1.contains("422 User1 :MOTD File is missing"));
 
     let users = shared_state.users.lock().unwrap();
     let user = users.get(&1).unwrap();
@@ -65,6 +90,7 @@ async fn test_handle_join_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
     };
 
     let command = Command::Join("#testchannel".to_string());
@@ -73,7 +99,7 @@ async fn test_handle_join_command() {
     let messages = result.unwrap();
     assert_eq!(messages, vec![
         (1, ":testuser JOIN :#testchannel".to_string()),
-        (1, ":server 353 testuser = #testchannel :testuser".to_string()),
+        (1, ":server 353 testuser = #testchannel :@testuser".to_string()),
         (1, ":server 366 testuser #testchannel :End of /NAMES list".to_string()),
     ]);
 
@@ -98,6 +124,7 @@ async fn test_handle_part_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     let command = Command::Part("#testchannel".to_string());
@@ -130,6 +157,7 @@ async fn test_handle_privmsg_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     // Test private message
@@ -146,12 +174,12 @@ async fn test_handle_privmsg_command() {
     let messages = result.unwrap();
     assert_eq!(messages, vec![(2, ":user1 PRIVMSG #testchannel :Hello, channel!".to_string())]);
 
-    // Test self-message (should be empty)
+    // Test self-message (delivered back to the sender)
     let command = Command::PrivMsg("user1".to_string(), "Hello, myself!".to_string());
     let result = handle_command(command, 1, &shared_state).await;
     assert!(result.is_ok());
     let messages = result.unwrap();
-    assert_eq!(messages, Vec::<(usize, String)>::new());
+    assert_eq!(messages, vec![(1, ":user1 PRIVMSG user1 :Hello, myself!".to_string())]);
 }
 
 #[tokio::test]
@@ -173,6 +201,7 @@ async fn test_handle_privmsg_no_echo() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     // Test channel message
@@ -184,9 +213,11 @@ async fn test_handle_privmsg_no_echo() {
     assert_eq!(messages[0], (2, ":user1 PRIVMSG #testchannel :Hello, channel!".to_string()));
 
     // Verify that the message is not echoed back to the sender
-    let users = shared_state.users.lock().unwrap();
-    let sender = users.get(&1).unwrap();
-    assert!(!messages.iter().any(|(id, _)| *id == sender.id));
+    {
+        let users = shared_state.users.lock().unwrap();
+        let sender = users.get(&1).unwrap();
+        assert!(!messages.iter().any(|(id, _)| *id == sender.id));
+    }
 
     // Test private message
     let command = Command::PrivMsg("user2".to_string(), "Hello, user2!".to_string());
@@ -221,6 +252,7 @@ async fn test_handle_quit_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     let command = Command::Quit(Some("Goodbye!".to_string()));
@@ -241,6 +273,7 @@ async fn test_handle_ping_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(HashMap::new())),
         channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
     };
 
     let command = Command::Ping("server1".to_string());
@@ -264,6 +297,7 @@ async fn test_handle_topic_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     // Set topic
@@ -300,6 +334,7 @@ async fn test_handle_names_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     let command = Command::Names("#testchannel".to_string());
@@ -332,6 +367,7 @@ async fn test_handle_list_command() {
     let shared_state = SharedState {
         users: Arc::new(Mutex::new(users)),
         channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
     };
 
     // List all channels
@@ -357,3 +393,1216 @@ async fn test_handle_list_command() {
         (1, ":server 323 :End of /LIST".to_string()),
     ]);
 }
+
+fn sasl_shared_state(accounts: FileAccountStore) -> SharedState {
+    let mut users = HashMap::new();
+    let mut user = User::new(1, "127.0.0.1".parse().unwrap());
+    user.set_nickname("testuser".to_string()).unwrap();
+    users.insert(1, user);
+    SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        accounts: Arc::new(accounts),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_sasl_plain_authentication() {
+    let mut accounts = FileAccountStore::new();
+    accounts.register("alice", "s3cret");
+    let shared_state = sasl_shared_state(accounts);
+
+    let messages = handle_command(Command::Cap("LS".to_string(), Some("302".to_string())), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server CAP testuser LS :"));
+    assert!(messages[0].1.contains(" sasl=PLAIN,EXTERNAL "));
+
+    let messages = handle_command(Command::Cap("REQ".to_string(), Some(":sasl".to_string())), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server CAP testuser ACK :sasl".to_string())]);
+
+    // Registration is held back while capabilities are being negotiated
+    let messages = handle_command(Command::User("alice".to_string(), "0".to_string(), "Alice".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages.is_empty());
+
+    let messages = handle_command(Command::Authenticate("PLAIN".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, "AUTHENTICATE +".to_string())]);
+
+    let payload = BASE64.encode("\0alice\0s3cret");
+    let messages = handle_command(Command::Authenticate(payload), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 900 testuser testuser!alice@127.0.0.1 alice :You are now logged in as alice".to_string()),
+        (1, ":server 903 testuser :SASL authentication successful".to_string()),
+    ]);
+
+    let messages = handle_command(Command::Cap("END".to_string(), None), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("001 testuser"));
+
+    let users = shared_state.users.lock().unwrap();
+    assert_eq!(users.get(&1).unwrap().account, Some("alice".to_string()));
+}
+
+#[tokio::test]
+async fn test_sasl_failures() {
+    let mut accounts = FileAccountStore::new();
+    accounts.register("alice", "s3cret");
+    let shared_state = sasl_shared_state(accounts);
+    handle_command(Command::Cap("REQ".to_string(), Some("sasl".to_string())), 1, &shared_state).await.unwrap();
+
+    // Unknown mechanism
+    let messages = handle_command(Command::Authenticate("SCRAM-SHA-1".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 908 testuser PLAIN,EXTERNAL :are available SASL mechanisms".to_string()),
+        (1, ":server 904 testuser :SASL authentication failed".to_string()),
+    ]);
+
+    // Wrong password
+    handle_command(Command::Authenticate("PLAIN".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Authenticate(BASE64.encode("\0alice\0wrong")), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 904 testuser :SASL authentication failed".to_string())]);
+
+    // Abort
+    handle_command(Command::Authenticate("PLAIN".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Authenticate("*".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 906 testuser :SASL authentication aborted".to_string())]);
+
+    // EXTERNAL without a client certificate
+    handle_command(Command::Authenticate("EXTERNAL".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Authenticate("+".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 904 testuser :SASL authentication failed".to_string())]);
+
+    let users = shared_state.users.lock().unwrap();
+    assert_eq!(users.get(&1).unwrap().account, None);
+}
+
+#[tokio::test]
+async fn test_sasl_external_and_chunking() {
+    let long_password = "p".repeat(400);
+    let mut accounts = FileAccountStore::new();
+    accounts.register("bob", &long_password);
+    accounts.add_certfp("bob", "AB:CD:EF").unwrap();
+    let shared_state = sasl_shared_state(accounts);
+    handle_command(Command::Cap("REQ".to_string(), Some("sasl".to_string())), 1, &shared_state).await.unwrap();
+
+    // PLAIN payload split over 400-byte chunks
+    handle_command(Command::Authenticate("PLAIN".to_string()), 1, &shared_state).await.unwrap();
+    let payload = BASE64.encode(format!("\0bob\0{}", long_password));
+    let chunks: Vec<String> = payload.as_bytes().chunks(400).map(|c| String::from_utf8(c.to_vec()).unwrap()).collect();
+    for chunk in &chunks[..chunks.len() - 1] {
+        let messages = handle_command(Command::Authenticate(chunk.clone()), 1, &shared_state).await.unwrap();
+        assert!(messages.is_empty());
+    }
+    let messages = handle_command(Command::Authenticate(chunks.last().unwrap().clone()), 1, &shared_state).await.unwrap();
+    assert!(messages[1].1.contains("903"), "Unexpected response: {:?}", messages);
+
+    // EXTERNAL matches the client certificate fingerprint
+    {
+        let mut users = shared_state.users.lock().unwrap();
+        let user = users.get_mut(&1).unwrap();
+        user.account = None;
+        user.certfp = Some("abcdef".to_string());
+    }
+    handle_command(Command::Authenticate("EXTERNAL".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Authenticate("+".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("900 testuser testuser!*@127.0.0.1 bob"));
+}
+
+#[tokio::test]
+async fn test_privmsg_tags_follow_recipient_caps() {
+    let mut users = HashMap::new();
+    let mut sender = User::new(1, "127.0.0.1".parse().unwrap());
+    sender.set_nickname("sender".to_string()).unwrap();
+    sender.account = Some("senderacct".to_string());
+    users.insert(1, sender);
+    let mut tagged = User::new(2, "127.0.0.1".parse().unwrap());
+    tagged.set_nickname("tagged".to_string()).unwrap();
+    for cap in ["server-time", "message-tags", "account-tag"] {
+        tagged.capabilities.insert(cap.to_string());
+    }
+    users.insert(2, tagged);
+    let mut plain = User::new(3, "127.0.0.1".parse().unwrap());
+    plain.set_nickname("plain".to_string()).unwrap();
+    users.insert(3, plain);
+
+    let mut channels = HashMap::new();
+    let mut channel = Channel::new("#testchannel".to_string());
+    channel.add_member(1);
+    channel.add_member(2);
+    channel.add_member(3);
+    channels.insert("#testchannel".to_string(), channel);
+
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+
+    let command = Command::PrivMsg("#testchannel".to_string(), "Hello".to_string());
+    let messages = handle_command(command, 1, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 2);
+
+    let tagged_line = &messages.iter().find(|(id, _)| *id == 2).unwrap().1;
+    assert!(tagged_line.starts_with("@time="), "Unexpected line: {}", tagged_line);
+    assert!(tagged_line.contains(";msgid="));
+    assert!(tagged_line.ends_with(";account=senderacct :sender PRIVMSG #testchannel :Hello"));
+
+    let plain_line = &messages.iter().find(|(id, _)| *id == 3).unwrap().1;
+    assert_eq!(plain_line, ":sender PRIVMSG #testchannel :Hello");
+
+    let command = Command::Notice("plain".to_string(), "Psst".to_string());
+    let messages = handle_command(command, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(3, ":sender NOTICE plain :Psst".to_string())]);
+
+    // NOTICE to an unknown target is silently dropped
+    let command = Command::Notice("nobody".to_string(), "Psst".to_string());
+    assert_eq!(handle_command(command, 1, &shared_state).await.unwrap(), vec![]);
+}
+
+#[tokio::test]
+async fn test_echo_message_and_extended_join() {
+    let mut users = HashMap::new();
+    let mut user1 = User::new(1, "127.0.0.1".parse().unwrap());
+    user1.set_nickname("user1".to_string()).unwrap();
+    user1.capabilities.insert("echo-message".to_string());
+    user1.capabilities.insert("extended-join".to_string());
+    users.insert(1, user1);
+    let mut user2 = User::new(2, "127.0.0.1".parse().unwrap());
+    user2.set_nickname("user2".to_string()).unwrap();
+    user2.realname = Some("Second User".to_string());
+    user2.account = Some("second".to_string());
+    users.insert(2, user2);
+
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
+    };
+
+    handle_command(Command::Join("#testchannel".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Join("#testchannel".to_string()), 2, &shared_state).await.unwrap();
+    assert!(messages.contains(&(1, ":user2 JOIN #testchannel second :Second User".to_string())));
+    assert_eq!(messages[0], (2, ":user2 JOIN :#testchannel".to_string()));
+
+    let command = Command::PrivMsg("#testchannel".to_string(), "Hello".to_string());
+    let messages = handle_command(command, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (2, ":user1 PRIVMSG #testchannel :Hello".to_string()),
+        (1, ":user1 PRIVMSG #testchannel :Hello".to_string()),
+    ]);
+
+    // Without echo-message the sender gets nothing back
+    let command = Command::PrivMsg("user1".to_string(), "Hi".to_string());
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":user2 PRIVMSG user1 :Hi".to_string())]);
+}
+
+#[tokio::test]
+async fn test_names_are_compared_with_ascii_casemapping() {
+    let mut users = HashMap::new();
+    for (id, nick) in [(1, "Alice"), (2, "bob")] {
+        let mut user = User::new(id, "127.0.0.1".parse().unwrap());
+        user.set_nickname(nick.to_string()).unwrap();
+        users.insert(id, user);
+    }
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
+    };
+
+    handle_command(Command::Join("#Rust".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Join("#rust".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages[0], (2, ":bob JOIN :#Rust".to_string()));
+    assert_eq!(shared_state.channels.lock().unwrap().len(), 1);
+    assert_eq!(shared_state.channels.lock().unwrap()["#rust"].members.len(), 2);
+
+    let messages = handle_command(Command::PrivMsg("#RUST".to_string(), "hi".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":bob PRIVMSG #Rust :hi".to_string())]);
+    let messages = handle_command(Command::PrivMsg("ALICE".to_string(), "hi".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":bob PRIVMSG Alice :hi".to_string())]);
+    let messages = handle_command(Command::List(None), 2, &shared_state).await.unwrap();
+    assert!(messages[1].1.contains(" 322 #Rust 2 "), "Unexpected line: {}", messages[1].1);
+
+    handle_command(Command::Part("#RUST".to_string()), 1, &shared_state).await.unwrap();
+    handle_command(Command::Part("#rust".to_string()), 2, &shared_state).await.unwrap();
+    assert!(shared_state.channels.lock().unwrap().is_empty());
+    assert!(shared_state.users.lock().unwrap().values().all(|user| user.channels.is_empty()));
+}
+
+#[tokio::test]
+async fn test_multi_prefix_and_userhost_in_names() {
+    let mut users = HashMap::new();
+    let mut user1 = User::new(1, "127.0.0.1".parse().unwrap());
+    user1.set_nickname("user1".to_string()).unwrap();
+    user1.username = Some("ident".to_string());
+    users.insert(1, user1);
+
+    let mut channels = HashMap::new();
+    let mut channel = Channel::new("#testchannel".to_string());
+    channel.add_member(1);
+    channel.add_operator(1);
+    channel.add_voice(1);
+    channels.insert("#testchannel".to_string(), channel);
+
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::Names("#testchannel".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 353 * = #testchannel :@user1");
+    let messages = handle_command(Command::Who("#testchannel".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 352 user1 #testchannel ident 127.0.0.1 server user1 H@ :0 ".to_string()),
+        (1, ":server 315 user1 #testchannel :End of WHO list".to_string()),
+    ]);
+
+    {
+        let mut users = shared_state.users.lock().unwrap();
+        let user = users.get_mut(&1).unwrap();
+        user.capabilities.insert("multi-prefix".to_string());
+        user.capabilities.insert("userhost-in-names".to_string());
+    }
+
+    let messages = handle_command(Command::Names("#testchannel".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 353 * = #testchannel :@+user1!ident@127.0.0.1");
+    let messages = handle_command(Command::Who("#testchannel".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains(" user1 H@+ :0 "));
+}
+
+#[tokio::test]
+async fn test_channel_membership_modes() {
+    let mut users = HashMap::new();
+    for (id, nick) in [(1, "op"), (2, "member"), (3, "outsider")] {
+        let mut user = User::new(id, "127.0.0.1".parse().unwrap());
+        user.set_nickname(nick.to_string()).unwrap();
+        user.username = Some("ident".to_string());
+        users.insert(id, user);
+    }
+    let mut channels = HashMap::new();
+    let mut channel = Channel::new("#chan".to_string());
+    channel.add_member(1);
+    channel.add_operator(1);
+    channel.add_member(2);
+    channels.insert("#chan".to_string(), channel);
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+    let mode = |modes: &str, param: Option<&str>| Command::Mode("#chan".to_string(), modes.to_string(), param.map(str::to_string));
+
+    let mut messages = handle_command(mode("+v", Some("member")), 1, &shared_state).await.unwrap();
+    messages.sort();
+    assert_eq!(messages, vec![
+        (1, ":op!ident@127.0.0.1 MODE #chan +v member".to_string()),
+        (2, ":op!ident@127.0.0.1 MODE #chan +v member".to_string()),
+    ]);
+    assert!(shared_state.channels.lock().unwrap()["#chan"].voiced.contains(&2));
+    let messages = handle_command(Command::Names("#chan".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("+member"));
+
+    let messages = handle_command(mode("+o", Some("member")), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(2, ":server 482 member #chan :You're not channel operator".to_string())]);
+    let messages = handle_command(mode("+v", Some("outsider")), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 441 op outsider #chan :They aren't on that channel".to_string())]);
+    let messages = handle_command(mode("+v", None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 461 op MODE :Not enough parameters".to_string())]);
+    let messages = handle_command(mode("+k", Some("key")), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 472 op k :is unknown mode char to me".to_string())]);
+
+    handle_command(mode("-v", Some("member")), 1, &shared_state).await.unwrap();
+    assert!(shared_state.channels.lock().unwrap()["#chan"].voiced.is_empty());
+    let messages = handle_command(mode("", None), 3, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(3, ":server 324 outsider #chan +".to_string())]);
+}
+
+#[test]
+fn test_parse_message_with_tags() {
+    let (tags, command) = parse_message("@label=abc;+draft/x=a\\sb PRIVMSG #chan :hi there").unwrap();
+    assert_eq!(tags.get("label"), Some(&"abc".to_string()));
+    assert_eq!(tags.get("+draft/x"), Some(&"a b".to_string()));
+    assert_eq!(command, Command::PrivMsg("#chan".to_string(), "hi there".to_string()));
+
+    let (tags, command) = parse_message("PING server1").unwrap();
+    assert!(tags.is_empty());
+    assert_eq!(command, Command::Ping("server1".to_string()));
+}
+
+#[test]
+fn test_parse_informational_commands() {
+    assert_eq!(parse_command("VERSION"), Some(Command::Version));
+    assert_eq!(parse_command("TIME irc.example.org"), Some(Command::Time));
+    assert_eq!(parse_command("STATS m"), Some(Command::Stats("m".to_string())));
+    assert_eq!(parse_command("STATS"), Some(Command::Stats(String::new())));
+    assert_eq!(parse_command("HELP join"), Some(Command::Help(Some("join".to_string()))));
+    assert_eq!(parse_command("HELP"), Some(Command::Help(None)));
+    assert_eq!(parse_command("OPER admin :secret"), Some(Command::Oper("admin".to_string(), "secret".to_string())));
+    assert_eq!(parse_command("OPER admin"), None);
+    assert_eq!(parse_command("KILL spammer :Go away"), Some(Command::Kill("spammer".to_string(), Some("Go away".to_string()))));
+    assert_eq!(parse_command("PASS :team secret"), Some(Command::Pass("team secret".to_string())));
+    assert_eq!(parse_command("PASS secret extra"), Some(Command::Pass("secret".to_string())));
+    assert_eq!(parse_command("PASS"), None);
+    assert_eq!(parse_command("KLINE 60 *@192.0.2.* :Spam"), Some(Command::AddBan(BanKind::UserHost, Some(60), "*@192.0.2.*".to_string(), Some("Spam".to_string()))));
+    assert_eq!(parse_command("QLINE NickServ"), Some(Command::AddBan(BanKind::Nick, None, "NickServ".to_string(), None)));
+    assert_eq!(parse_command("UNDLINE 192.0.2.0/24"), Some(Command::RemoveBan(BanKind::Ip, "192.0.2.0/24".to_string())));
+    assert_eq!(parse_command("WALLOPS :Restarting soon"), Some(Command::Wallops("Restarting soon".to_string())));
+    assert_eq!(parse_command("WALLOPS"), None);
+    assert_eq!(parse_command("DIE"), Some(Command::Die));
+    assert_eq!(parse_command("restart"), Some(Command::Restart));
+
+    assert_eq!(command_name("@label=1 stats u"), Some("STATS".to_string()));
+    assert_eq!(command_name("privmsg #a :b"), Some("PRIVMSG".to_string()));
+}
+
+#[tokio::test]
+async fn test_labeled_response_and_batch() {
+    let mut users = HashMap::new();
+    let mut user1 = User::new(1, "127.0.0.1".parse().unwrap());
+    user1.set_nickname("user1".to_string()).unwrap();
+    user1.capabilities.insert("batch".to_string());
+    user1.capabilities.insert("labeled-response".to_string());
+    users.insert(1, user1);
+    let mut user2 = User::new(2, "127.0.0.1".parse().unwrap());
+    user2.set_nickname("user2".to_string()).unwrap();
+    users.insert(2, user2);
+
+    let mut channels = HashMap::new();
+    let mut channel = Channel::new("#testchannel".to_string());
+    channel.add_member(1);
+    channels.insert("#testchannel".to_string(), channel);
+
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+
+    // Single reply carries the label
+    let (tags, command) = parse_message("@label=one PING server1").unwrap();
+    let replies = handle_tagged_command(command, &tags, 1, &shared_state).await.unwrap();
+    assert_eq!(replies, CommandReplies { own: vec!["@label=one PONG server1".to_string()], others: Vec::new() });
+
+    // No reply to the sender is acknowledged
+    let (tags, command) = parse_message("@label=two PRIVMSG user2 :hi").unwrap();
+    let replies = handle_tagged_command(command, &tags, 1, &shared_state).await.unwrap();
+    assert_eq!(replies, CommandReplies {
+        own: vec!["@label=two :server ACK".to_string()],
+        others: vec![(2, ":user1 PRIVMSG user2 :hi".to_string())],
+    });
+
+    // Several replies are wrapped in a labeled-response batch
+    let (tags, command) = parse_message("@label=three NAMES #testchannel").unwrap();
+    let messages = handle_tagged_command(command, &tags, 1, &shared_state).await.unwrap().own;
+    assert_eq!(messages.len(), 4);
+    let start = &messages[0];
+    assert!(start.starts_with("@label=three :server BATCH +"), "Unexpected line: {}", start);
+    assert!(start.ends_with(" labeled-response"));
+    let batch_id = start.split('+').nth(1).unwrap().split(' ').next().unwrap();
+    assert_eq!(messages[1], format!("@batch={} :server 353 * = #testchannel :user1", batch_id));
+    assert_eq!(messages[3], format!(":server BATCH -{}", batch_id));
+
+    // Without a label, multi-line replies use a batch of the command's type
+    let (tags, command) = parse_message("WHO #testchannel").unwrap();
+    let messages = handle_tagged_command(command, &tags, 1, &shared_state).await.unwrap().own;
+    assert!(messages[0].ends_with(" rustirc2/who"));
+    assert!(messages[1].starts_with("@batch="));
+}
+
+#[tokio::test]
+async fn test_chathistory_replays_channel_and_private_messages() {
+    let mut users = HashMap::new();
+    for (id, nick) in [(1, "user1"), (2, "user2"), (3, "guest")] {
+        let mut user = User::new(id, "127.0.0.1".parse().unwrap());
+        user.set_nickname(nick.to_string()).unwrap();
+        users.insert(id, user);
+    }
+    users.get_mut(&1).unwrap().account = Some("first".to_string());
+    users.get_mut(&2).unwrap().account = Some("second".to_string());
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+        ..Default::default()
+    };
+
+    handle_command(Command::Join("#history".to_string()), 1, &shared_state).await.unwrap();
+    handle_command(Command::Join("#history".to_string()), 2, &shared_state).await.unwrap();
+    for i in 1..=3 {
+        handle_command(Command::PrivMsg("#history".to_string(), format!("message {}", i)), 1, &shared_state).await.unwrap();
+    }
+    handle_command(Command::PrivMsg("user2".to_string(), "private".to_string()), 1, &shared_state).await.unwrap();
+    // Not kept: the guest isn't logged in
+    handle_command(Command::PrivMsg("guest".to_string(), "unlogged".to_string()), 1, &shared_state).await.unwrap();
+
+    {
+        let mut users = shared_state.users.lock().unwrap();
+        let user = users.get_mut(&2).unwrap();
+        for cap in ["batch", "server-time", "message-tags", "draft/chathistory"] {
+            user.capabilities.insert(cap.to_string());
+        }
+    }
+
+    // Any case of the channel name finds its history
+    let command = Command::ChatHistory("LATEST".to_string(), vec!["#HISTORY".to_string(), "*".to_string(), "2".to_string()]);
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 4);
+    assert!(messages[0].1.ends_with(" chathistory #history"), "Unexpected line: {}", messages[0].1);
+    assert!(messages[1].1.contains(";time="));
+    assert!(messages[1].1.ends_with(":user1 PRIVMSG #history :message 2"));
+    assert!(messages[2].1.ends_with(":user1 PRIVMSG #history :message 3"));
+
+    // BEFORE the last message, using its msgid
+    let msgid = messages[2].1.split("msgid=").nth(1).unwrap().split([';', ' ']).next().unwrap().to_string();
+    let command = Command::ChatHistory("BEFORE".to_string(), vec!["#history".to_string(), format!("msgid={}", msgid), "10".to_string()]);
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 4);
+    assert!(messages[1].1.ends_with(":message 1"));
+    assert!(messages[2].1.ends_with(":message 2"));
+
+    // Private history is visible to both accounts, by nickname or account name
+    let command = Command::ChatHistory("LATEST".to_string(), vec!["user1".to_string(), "*".to_string(), "10".to_string()]);
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert!(messages[1].1.ends_with(":user1 PRIVMSG user2 :private"));
+    let command = Command::ChatHistory("LATEST".to_string(), vec!["second".to_string(), "*".to_string(), "10".to_string()]);
+    let messages = handle_command(command, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":user1 PRIVMSG user2 :private".to_string())]);
+
+    // but not to whoever has one of their nicknames without the account
+    let command = Command::ChatHistory("LATEST".to_string(), vec!["user1".to_string(), "*".to_string(), "10".to_string()]);
+    let messages = handle_command(command, 3, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("FAIL CHATHISTORY INVALID_TARGET"));
+
+    let command = Command::ChatHistory("TARGETS".to_string(), vec![
+        "timestamp=2000-01-01T00:00:00.000Z".to_string(),
+        "timestamp=2100-01-01T00:00:00.000Z".to_string(),
+        "10".to_string(),
+    ]);
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 4);
+    assert!(messages[1].1.contains("CHATHISTORY TARGETS #history "));
+    assert!(messages[2].1.contains("CHATHISTORY TARGETS first "));
+
+    // Channels the user is not in are refused
+    let command = Command::ChatHistory("LATEST".to_string(), vec!["#other".to_string(), "*".to_string(), "10".to_string()]);
+    let messages = handle_command(command, 2, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("FAIL CHATHISTORY INVALID_TARGET"));
+
+    // Short or overlong parameter lists are refused rather than sliced
+    for (subcommand, params) in [
+        ("LATEST", vec!["5"]),
+        ("BEFORE", vec!["#history", "5"]),
+        ("BETWEEN", vec!["#history", "*", "5"]),
+        ("AROUND", vec!["#history", "*", "*", "5"]),
+        ("BOGUS", vec!["#history", "*", "5"]),
+    ] {
+        let command = Command::ChatHistory(subcommand.to_string(), params.into_iter().map(str::to_string).collect());
+        let messages = handle_command(command, 2, &shared_state).await.unwrap();
+        assert!(messages[0].1.starts_with(&format!(":server FAIL CHATHISTORY INVALID_PARAMS {} :", subcommand)), "Unexpected line: {}", messages[0].1);
+    }
+    assert!(!shared_state.channels.is_poisoned() && !shared_state.users.is_poisoned());
+}
+
+#[tokio::test]
+async fn test_handle_whois_command() {
+    let mut users = HashMap::new();
+    let mut user1 = User::new(1, "127.0.0.1".parse().unwrap());
+    user1.set_nickname("user1".to_string()).unwrap();
+    user1.username = Some("ident".to_string());
+    user1.realname = Some("Real Name".to_string());
+    user1.account = Some("acct".to_string());
+    user1.certfp = Some("abcdef".to_string());
+    user1.join_channel("#testchannel".to_string());
+    users.insert(1, user1);
+    let mut user2 = User::new(2, "127.0.0.1".parse().unwrap());
+    user2.set_nickname("user2".to_string()).unwrap();
+    users.insert(2, user2);
+
+    let mut channels = HashMap::new();
+    let mut channel = Channel::new("#testchannel".to_string());
+    channel.add_member(1);
+    channel.add_operator(1);
+    channels.insert("#testchannel".to_string(), channel);
+
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::WhoisUser("user1".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 311 user1 user1 ident 127.0.0.1 * :Real Name".to_string()),
+        (1, ":server 319 user1 user1 :@#testchannel".to_string()),
+        (1, ":server 312 user1 user1 server :rustirc2".to_string()),
+        (1, ":server 378 user1 user1 :is connecting from ident@127.0.0.1 127.0.0.1".to_string()),
+        (1, ":server 276 user1 user1 :has client certificate fingerprint abcdef".to_string()),
+        (1, ":server 330 user1 user1 acct :is logged in as".to_string()),
+        (1, ":server 318 user1 user1 :End of /WHOIS list".to_string()),
+    ]);
+
+    // Other users don't see the fingerprint or the real host
+    let messages = handle_command(Command::WhoisUser("user1".to_string()), 2, &shared_state).await.unwrap();
+    assert!(!messages.iter().any(|(_, line)| line.contains(" 276 ") || line.contains(" 378 ")));
+
+    let messages = handle_command(Command::WhoisUser("nobody".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 401 user2 nobody :No such nick/channel");
+}
+
+#[tokio::test]
+async fn test_configured_server_name_and_nick_length() {
+    let mut config = Config::default();
+    config.server.name = "irc.example.org".to_string();
+    config.server.description = "Example server".to_string();
+    config.limits.nick_length = 5;
+
+    let mut users = HashMap::new();
+    users.insert(1, User::new(1, "127.0.0.1".parse().unwrap()));
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        config: Arc::new(ConfigHandle::new(config)),
+        ..Default::default()
+    };
+
+    assert!(handle_command(Command::Nick("toolong".to_string()), 1, &shared_state).await.is_err());
+    handle_command(Command::Nick("short".to_string()), 1, &shared_state).await.unwrap();
+
+    let messages = handle_command(Command::User("user".to_string(), "0".to_string(), "Real".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":irc.example.org 001 short :Welcome to the IRC server!");
+    assert!(messages[1].1.starts_with(":irc.example.org 002 short :Your host is irc.example.org, running version rustirc2-"));
+
+    let messages = handle_command(Command::WhoisUser("short".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages.contains(&(1, ":irc.example.org 312 short short irc.example.org :Example server".to_string())));
+}
+
+/// Oper blocks for tests: "admin" may do everything, "helper" only see stats. Both use
+/// the password "secret".
+const OPER_BLOCKS: &str = r#"
+[[privset]]
+name = "all"
+privileges = ["ban", "die", "kill", "rehash", "sajoin", "see-invisible", "stats", "wallops"]
+
+[[privset]]
+name = "helper"
+privileges = ["stats"]
+
+[[oper]]
+name = "admin"
+password_hash = "$argon2id$v=19$m=19456,t=2,p=1$nNzvFRC1RKLHR1m7x4vUPg$lOAndSSc/YzlOA1GJqaL1sIVKM9W7ZBTwAIxgeaJcC8"
+hosts = ["*@127.0.0.1"]
+privset = "all"
+
+[[oper]]
+name = "helper"
+password_hash = "$argon2id$v=19$m=19456,t=2,p=1$nNzvFRC1RKLHR1m7x4vUPg$lOAndSSc/YzlOA1GJqaL1sIVKM9W7ZBTwAIxgeaJcC8"
+privset = "helper"
+"#;
+
+#[tokio::test]
+async fn test_handle_rehash_command() {
+    let path = std::env::temp_dir().join(format!("rustirc2-rehash-{}.toml", crate::utils::generate_client_id()));
+    std::fs::write(&path, format!("[server]\nname = \"irc.example.org\"\n{}", OPER_BLOCKS)).unwrap();
+
+    let mut users = HashMap::new();
+    let mut user = User::new(1, "127.0.0.1".parse().unwrap());
+    user.set_nickname("admin".to_string()).unwrap();
+    users.insert(1, user);
+    let mut user = User::new(2, "192.0.2.1".parse().unwrap());
+    user.set_nickname("someone".to_string()).unwrap();
+    users.insert(2, user);
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        config: Arc::new(ConfigHandle::open(Some(path.clone()), Overrides::default()).unwrap()),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::Rehash, 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(2, ":irc.example.org 481 someone :Permission Denied- You're not an IRC operator".to_string())]);
+
+    // Opers need the rehash privilege
+    shared_state.users.lock().unwrap().get_mut(&2).unwrap().oper = Some("helper".to_string());
+    let messages = handle_command(Command::Rehash, 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(2, ":irc.example.org 723 someone rehash :Insufficient oper privileges.".to_string())]);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    std::fs::write(&path, format!("[server]\nname = \"irc.example.net\"\n[limits]\nnick_length = 30\n{}", OPER_BLOCKS)).unwrap();
+    let messages = handle_command(Command::Rehash, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, format!(":irc.example.net 382 admin {} :Rehashing", path.display()))]);
+    assert_eq!(shared_state.config().limits.nick_length, 30);
+
+    // An invalid file is reported and the running config is kept
+    std::fs::write(&path, "[limits]\nnick_length = 0\n").unwrap();
+    let messages = handle_command(Command::Rehash, 1, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].1.starts_with(":irc.example.net NOTICE admin :*** Rehash failed, keeping the running configuration: Invalid configuration: limits.nick_length"));
+    assert_eq!(shared_state.config().server.name, "irc.example.net");
+    assert_eq!(shared_state.config().limits.nick_length, 30);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_handle_motd_command() {
+    let mut users = HashMap::new();
+    let mut user = User::new(1, "127.0.0.1".parse().unwrap());
+    user.set_nickname("reader".to_string()).unwrap();
+    users.insert(1, user);
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::Motd, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 422 reader :MOTD File is missing".to_string())]);
+
+    let config = Config {
+        motd: Some(Motd::from_text("Hello\nWorld")),
+        ..Default::default()
+    };
+    let shared_state = SharedState {
+        config: Arc::new(ConfigHandle::new(config)),
+        ..shared_state
+    };
+    let messages = handle_command(Command::Motd, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 375 reader :- server Message of the day - ".to_string()),
+        (1, ":server 372 reader :- Hello".to_string()),
+        (1, ":server 372 reader :- World".to_string()),
+        (1, ":server 376 reader :End of /MOTD command.".to_string()),
+    ]);
+
+    // The MOTD also ends the welcome burst
+    handle_command(Command::Nick("reader".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::User("reader".to_string(), "0".to_string(), "Reader".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages.last().unwrap().1, ":server 376 reader :End of /MOTD command.");
+}
+
+#[tokio::test]
+async fn test_handle_lusers_command() {
+    let mut users = HashMap::new();
+    for (id, nick) in [(1, "oper"), (2, "user"), (3, "pending")] {
+        let mut user = User::new(id, "127.0.0.1".parse().unwrap());
+        user.set_nickname(nick.to_string()).unwrap();
+        user.registered = id != 3;
+        users.insert(id, user);
+    }
+    users.get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let mut channels = HashMap::new();
+    channels.insert("#one".to_string(), Channel::new("#one".to_string()));
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        channels: Arc::new(Mutex::new(channels)),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::Lusers, 2, &shared_state).await.unwrap();
+    let lines: Vec<&str> = messages.iter().map(|(_, line)| line.as_str()).collect();
+    assert_eq!(lines, vec![
+        ":server 251 user :There are 2 users and 0 services on 1 server",
+        ":server 252 user 1 :operator(s) online",
+        ":server 253 user 1 :unknown connection(s)",
+        ":server 254 user 1 :channels formed",
+        ":server 255 user :I have 2 clients and 0 servers",
+        ":server 265 user 2 2 :Current local users 2, max 2",
+        ":server 266 user 2 2 :Current global users 2, max 2",
+    ]);
+
+    // The peak is kept after users leave
+    shared_state.users.lock().unwrap().remove(&1);
+    let messages = handle_command(Command::Lusers, 2, &shared_state).await.unwrap();
+    assert!(messages.iter().any(|(_, line)| line == ":server 265 user 1 2 :Current local users 1, max 2"));
+    assert!(!messages.iter().any(|(_, line)| line.contains(" 252 ")));
+}
+
+#[tokio::test]
+async fn test_isupport_follows_config() {
+    let mut config = Config::default();
+    config.server.network = "TestNet".to_string();
+    config.limits.nick_length = 16;
+    let mut users = HashMap::new();
+    let mut user = User::new(1, "127.0.0.1".parse().unwrap());
+    user.set_nickname("nick".to_string()).unwrap();
+    users.insert(1, user);
+    let shared_state = SharedState {
+        users: Arc::new(Mutex::new(users)),
+        config: Arc::new(ConfigHandle::new(config)),
+        ..Default::default()
+    };
+
+    let messages = handle_command(Command::User("nick".to_string(), "0".to_string(), "Nick".to_string()), 1, &shared_state).await.unwrap();
+    let isupport: Vec<&str> = messages.iter().map(|(_, line)| line.as_str()).filter(|line| line.contains(" 005 ")).collect();
+    assert_eq!(isupport.len(), 1);
+    let tokens: Vec<&str> = isupport[0].split(" :").next().unwrap().split(' ').skip(3).collect();
+    assert!(tokens.len() <= 13);
+    assert!(tokens.contains(&"NETWORK=TestNet"));
+    assert!(tokens.contains(&"NICKLEN=16"));
+    assert!(tokens.contains(&"CHATHISTORY=100"));
+}
+
+fn registered_state(config: Config) -> SharedState {
+    let mut users = HashMap::new();
+    let mut user = User::new(1, "127.0.0.1".parse().unwrap());
+    user.set_nickname("asker".to_string()).unwrap();
+    user.registered = true;
+    users.insert(1, user);
+    SharedState {
+        users: Arc::new(Mutex::new(users)),
+        config: Arc::new(ConfigHandle::new(config)),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_informational_commands() {
+    let mut config = Config::default();
+    config.server.description = "Test server".to_string();
+    let shared_state = registered_state(config);
+
+    let messages = handle_command(Command::Version, 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 351 asker rustirc2-"));
+    assert!(messages[0].1.ends_with(" server :Test server"));
+    assert!(messages[1].1.contains(" 005 asker "));
+
+    let messages = handle_command(Command::Time, 1, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].1.starts_with(":server 391 asker server :"));
+
+    let messages = handle_command(Command::Admin, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 423 asker server :No administrative info available".to_string())]);
+
+    let messages = handle_command(Command::Info, 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 371 asker :rustirc2-"));
+    assert_eq!(messages.last().unwrap().1, ":server 374 asker :End of INFO list");
+
+    let mut config = Config::default();
+    config.admin.location = "Somewhere".to_string();
+    config.admin.organization = "Example Org".to_string();
+    config.admin.email = "admin@example.org".to_string();
+    let shared_state = registered_state(config);
+    let messages = handle_command(Command::Admin, 1, &shared_state).await.unwrap();
+    let lines: Vec<&str> = messages.iter().map(|(_, line)| line.as_str()).collect();
+    assert_eq!(lines, vec![
+        ":server 256 asker server :Administrative info",
+        ":server 257 asker :Somewhere",
+        ":server 258 asker :Example Org",
+        ":server 259 asker :admin@example.org",
+    ]);
+}
+
+#[tokio::test]
+async fn test_handle_stats_command() {
+    let shared_state = registered_state(toml::from_str(OPER_BLOCKS).unwrap());
+    let mut other = User::new(2, "127.0.0.2".parse().unwrap());
+    other.set_nickname("other".to_string()).unwrap();
+    shared_state.users.lock().unwrap().insert(2, other);
+    shared_state.users.lock().unwrap()[&1].traffic.record_received(2048);
+    shared_state.stats.record_command("privmsg", 30);
+    shared_state.stats.record_command("PRIVMSG", 20);
+    shared_state.stats.record_command("JOIN", 10);
+
+    let messages = handle_command(Command::Stats("u".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 242 asker :Server Up 0 days 0:00:"));
+    assert_eq!(messages[1].1, ":server 219 asker u :End of /STATS report");
+
+    let messages = handle_command(Command::Stats("m".to_string()), 1, &shared_state).await.unwrap();
+    let lines: Vec<&str> = messages.iter().map(|(_, line)| line.as_str()).collect();
+    assert_eq!(lines, vec![
+        ":server 212 asker JOIN 1 10 0",
+        ":server 212 asker PRIVMSG 2 50 0",
+        ":server 219 asker m :End of /STATS report",
+    ]);
+
+    // Without oper status, only your own link is shown and y/o/k are refused
+    let messages = handle_command(Command::Stats("l".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].1.starts_with(":server 211 asker asker[127.0.0.1] 0 0 0 1 2 "));
+    let messages = handle_command(Command::Stats("o".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 481 asker :Permission Denied- You're not an IRC operator".to_string())]);
+    let messages = handle_command(Command::Stats("y".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 481 asker :Permission Denied- You're not an IRC operator".to_string())]);
+
+    // The stats privilege shows every link and the oper blocks, but k needs ban
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("helper".to_string());
+    let messages = handle_command(Command::Stats("l".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(messages[1].1.starts_with(":server 211 asker other[127.0.0.2] "));
+    let messages = handle_command(Command::Stats("o".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 243 asker O *@127.0.0.1 * admin");
+    assert_eq!(messages[1].1, ":server 243 asker O *@* * helper");
+    let messages = handle_command(Command::Stats("y".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 218 asker Y default 120 0 1024 1048576");
+    let messages = handle_command(Command::Stats("k".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 723 asker ban :Insufficient oper privileges.".to_string())]);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let messages = handle_command(Command::Stats("k".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 219 asker k :End of /STATS report".to_string())]);
+}
+
+#[tokio::test]
+async fn test_handle_oper_command() {
+    let mut config: Config = toml::from_str(OPER_BLOCKS).unwrap();
+    config.opers[1].certfp = Some("AB:CD:EF".to_string());
+    let shared_state = registered_state(config);
+    let oper = |name: &str, password: &str| Command::Oper(name.to_string(), password.to_string());
+
+    let messages = handle_command(oper("admin", "wrong"), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 464 asker :Password incorrect".to_string())]);
+    let messages = handle_command(oper("nobody", "secret"), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 491 asker :No appropriate operator blocks were found for your host".to_string())]);
+    // The helper block also requires a client certificate
+    let messages = handle_command(oper("helper", "secret"), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 491 asker :No appropriate operator blocks were found for your host");
+    assert_eq!(shared_state.users.lock().unwrap()[&1].oper, None);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().certfp = Some("abcdef".to_string());
+    let messages = handle_command(oper("helper", "secret"), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 381 asker :You are now an IRC operator".to_string()),
+        (1, ":asker MODE asker :+o".to_string()),
+    ]);
+    assert_eq!(shared_state.users.lock().unwrap()[&1].oper.as_deref(), Some("helper"));
+
+    // Logging in again with another block swaps the privileges without a second mode change
+    let messages = handle_command(oper("admin", "secret"), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 381 asker :You are now an IRC operator".to_string())]);
+    assert_eq!(shared_state.users.lock().unwrap()[&1].oper.as_deref(), Some("admin"));
+
+    // The host mask must match
+    let mut user = User::new(2, "10.0.0.1".parse().unwrap());
+    user.set_nickname("remote".to_string()).unwrap();
+    user.registered = true;
+    shared_state.users.lock().unwrap().insert(2, user);
+    let messages = handle_command(oper("admin", "secret"), 2, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 491 remote :No appropriate operator blocks were found for your host");
+
+    let messages = handle_command(Command::WhoisUser("asker".to_string()), 2, &shared_state).await.unwrap();
+    assert!(messages.contains(&(2, ":server 313 remote asker :is an IRC operator".to_string())));
+}
+
+#[tokio::test]
+async fn test_server_bans() {
+    let shared_state = registered_state(toml::from_str(OPER_BLOCKS).unwrap());
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("helper".to_string());
+    let kline = || Command::AddBan(BanKind::UserHost, Some(60), "spam*@10.0.0.*".to_string(), Some("Spamming".to_string()));
+    let messages = handle_command(kline(), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 723 asker ban :Insufficient oper privileges.".to_string())]);
+
+    // Durations past what a timestamp can hold are refused
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    for minutes in [u64::MAX, i64::MAX as u64, 1 << 40] {
+        let messages = handle_command(Command::AddBan(BanKind::UserHost, Some(minutes), "*@10.0.0.*".to_string(), None), 1, &shared_state).await.unwrap();
+        assert_eq!(messages, vec![(1, ":server NOTICE asker :*** K-line duration is too long".to_string())]);
+    }
+    assert!(shared_state.bans.lock().unwrap().active().is_empty());
+
+    // Adding a ban disconnects matching users and tells their channels
+    let mut spammer = User::new(2, "10.0.0.5".parse().unwrap());
+    spammer.set_nickname("spammer".to_string()).unwrap();
+    spammer.username = Some("spammy".to_string());
+    spammer.registered = true;
+    spammer.channels.insert("#test".to_string());
+    shared_state.users.lock().unwrap().insert(2, spammer);
+    let mut channel = Channel::new("#test".to_string());
+    channel.add_member(1);
+    channel.add_member(2);
+    shared_state.channels.lock().unwrap().insert("#test".to_string(), channel);
+
+    let messages = handle_command(kline(), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server NOTICE asker :*** Added K-line on spam*@10.0.0.* for 60 minutes (Spamming)".to_string()),
+        (1, ":spammer QUIT :K-lined: Spamming".to_string()),
+        (2, "ERROR :Closing Link: 10.0.0.5 (K-lined: Spamming)".to_string()),
+        (1, ":server NOTICE asker :*** Disconnected 1 matching user(s)".to_string()),
+    ]);
+    assert!(!shared_state.users.lock().unwrap().contains_key(&2));
+
+    let messages = handle_command(Command::Stats("k".to_string()), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 216 asker K spam*@10.0.0.* "));
+    assert!(messages[0].1.ends_with(" asker :Spamming"));
+
+    // Registration is refused while the ban is in place
+    let mut returning = User::new(3, "10.0.0.9".parse().unwrap());
+    returning.set_nickname("back".to_string()).unwrap();
+    shared_state.users.lock().unwrap().insert(3, returning);
+    let messages = handle_command(Command::User("spamagain".to_string(), "0".to_string(), "*".to_string()), 3, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (3, ":server 465 back :You are banned from this server- Spamming".to_string()),
+        (3, "ERROR :Closing Link: 10.0.0.9 (K-lined: Spamming)".to_string()),
+    ]);
+    assert!(!shared_state.users.lock().unwrap().contains_key(&3));
+
+    let messages = handle_command(Command::RemoveBan(BanKind::UserHost, "spam*@10.0.0.*".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server NOTICE asker :*** Removed K-line on spam*@10.0.0.*");
+
+    // Q-lines reserve nicknames
+    handle_command(Command::AddBan(BanKind::Nick, None, "*Serv".to_string(), None), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Nick("NickServ".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 432 asker NickServ :Nickname is reserved: No reason".to_string())]);
+
+    let messages = handle_command(Command::AddBan(BanKind::Ip, None, "example.org".to_string(), None), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server NOTICE asker :*** Invalid D-line mask"));
+}
+
+#[tokio::test]
+async fn test_handle_kill_command() {
+    let shared_state = registered_state(toml::from_str(OPER_BLOCKS).unwrap());
+    let mut target = User::new(2, "127.0.0.2".parse().unwrap());
+    target.set_nickname("target".to_string()).unwrap();
+    shared_state.users.lock().unwrap().insert(2, target);
+
+    let kill = || Command::Kill("Target".to_string(), None);
+    let messages = handle_command(kill(), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 481 asker :Permission Denied- You're not an IRC operator".to_string())]);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let messages = handle_command(Command::Kill("nobody".to_string(), None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 401 asker nobody :No such nick/channel".to_string())]);
+    let messages = handle_command(kill(), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (2, ":asker KILL target :asker".to_string()),
+        (2, "ERROR :Closing Link: 127.0.0.2 (Killed (asker (asker)))".to_string()),
+    ]);
+    assert!(!shared_state.users.lock().unwrap().contains_key(&2));
+}
+
+#[tokio::test]
+async fn test_user_modes_and_server_notices() {
+    let shared_state = registered_state(toml::from_str(OPER_BLOCKS).unwrap());
+    let mode = |modes: &str, param: Option<&str>| Command::Mode("asker".to_string(), modes.to_string(), param.map(str::to_string));
+
+    let messages = handle_command(mode("", None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 221 asker +".to_string())]);
+    let messages = handle_command(Command::Mode("other".to_string(), "+w".to_string(), None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 502 asker :Can't change mode for other users".to_string())]);
+    // Server notices need oper status, +o needs OPER and unknown letters are reported
+    let messages = handle_command(mode("+wsoz", None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":server 501 asker :Unknown MODE flag".to_string()),
+        (1, ":asker MODE asker :+w".to_string()),
+    ]);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let messages = handle_command(mode("+s", Some("+ck-c+o")), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (1, ":asker MODE asker :+s".to_string()),
+        (1, ":server 008 asker +ko :Server notice mask".to_string()),
+    ]);
+    let messages = handle_command(mode("", None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 221 asker +osw".to_string())]);
+
+    // Subscribed opers see oper-ups and failed attempts
+    let mut user = User::new(2, "127.0.0.1".parse().unwrap());
+    user.set_nickname("newbie".to_string()).unwrap();
+    user.username = Some("new".to_string());
+    user.registered = true;
+    shared_state.users.lock().unwrap().insert(2, user);
+    let messages = handle_command(Command::Oper("admin".to_string(), "guess".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages[1], (1, ":server NOTICE asker :*** Notice -- Failed OPER attempt by newbie (new@127.0.0.1) for admin: wrong password".to_string()));
+    let messages = handle_command(Command::Oper("admin".to_string(), "secret".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages[2], (1, ":server NOTICE asker :*** Notice -- newbie (new@127.0.0.1) is now an operator using admin".to_string()));
+
+    // Only users with +w get WALLOPS
+    let messages = handle_command(Command::Wallops("Maintenance at noon".to_string()), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":newbie!new@127.0.0.1 WALLOPS :Maintenance at noon".to_string())]);
+
+    // Kills are reported under k, and the exit of the killed user under c, which is not subscribed
+    let messages = handle_command(Command::Kill("newbie".to_string(), Some("Testing".to_string())), 1, &shared_state).await.unwrap();
+    assert_eq!(messages.last().unwrap(), &(1, ":server NOTICE asker :*** Notice -- asker killed newbie (Testing)".to_string()));
+    assert_eq!(messages.len(), 3);
+
+    // Dropping +o also drops the server notice mask
+    let messages = handle_command(mode("-o", None), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":asker MODE asker :-os".to_string())]);
+    let messages = handle_command(Command::Wallops("Hello".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 481 asker :Permission Denied- You're not an IRC operator".to_string())]);
+}
+
+#[tokio::test]
+async fn test_handle_die_and_restart_commands() {
+    let shared_state = registered_state(toml::from_str(OPER_BLOCKS).unwrap());
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("helper".to_string());
+    let messages = handle_command(Command::Restart, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 723 asker die :Insufficient oper privileges.".to_string())]);
+    assert_eq!(shared_state.shutdown.requested(), None);
+
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let messages = handle_command(Command::Restart, 1, &shared_state).await.unwrap();
+    assert!(messages.is_empty());
+    assert_eq!(shared_state.shutdown.requested(), Some((ShutdownKind::Restart, "RESTART by asker".to_string())));
+    let messages = handle_command(Command::Die, 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server NOTICE asker :*** The server is already shutting down".to_string())]);
+}
+
+#[tokio::test]
+async fn test_handle_help_command() {
+    let shared_state = registered_state(Config::default());
+
+    let messages = handle_command(Command::Help(Some("PART".to_string())), 1, &shared_state).await.unwrap();
+    let lines: Vec<&str> = messages.iter().map(|(_, line)| line.as_str()).collect();
+    assert_eq!(lines, vec![
+        ":server 704 asker part :PART <channel>",
+        ":server 705 asker part :",
+        ":server 705 asker part :Leaves a channel.",
+        ":server 706 asker part :End of /HELP",
+    ]);
+
+    let messages = handle_command(Command::Help(None), 1, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 704 asker index :"));
+
+    // RESTART shares the DIE page
+    let die = handle_command(Command::Help(Some("DIE".to_string())), 1, &shared_state).await.unwrap();
+    let restart = handle_command(Command::Help(Some("RESTART".to_string())), 1, &shared_state).await.unwrap();
+    assert_eq!(restart, die.into_iter().map(|(id, line)| (id, line.replace(" die :", " restart :"))).collect::<Vec<_>>());
+
+    for topic in ["nosuchtopic", "../Cargo.toml"] {
+        let messages = handle_command(Command::Help(Some(topic.to_string())), 1, &shared_state).await.unwrap();
+        assert_eq!(messages, vec![(1, format!(":server 524 asker {} :No help available on this topic", topic.to_lowercase()))]);
+    }
+
+    // Every topic listed in the index has its own page, and every page is built in
+    let index = shared_state.config().help.get("index").unwrap().to_string();
+    for topic in index.lines().skip(1).flat_map(|line| line.split_whitespace()) {
+        let messages = handle_command(Command::Help(Some(topic.to_string())), 1, &shared_state).await.unwrap();
+        assert!(messages[0].1.contains(" 704 "), "No help page for {}", topic);
+    }
+    for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/help")).unwrap() {
+        let topic = entry.unwrap().file_name().into_string().unwrap();
+        assert!(shared_state.config().help.get(&topic).is_some(), "help/{} is not built in", topic);
+    }
+
+    // A help directory adds topics and replaces pages, read when the config is loaded
+    let dir = std::env::temp_dir().join(format!("rustirc2-help-{}", crate::utils::generate_client_id()));
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("rules"), "Be nice.\n").unwrap();
+    std::fs::write(dir.join("part"), "PART is disabled here.\n").unwrap();
+    let mut config = Config::default();
+    config.server.help_dir = Some(dir.clone());
+    config.load_help().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let shared_state = registered_state(config);
+    let messages = handle_command(Command::Help(Some("rules".to_string())), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 704 asker rules :Be nice.");
+    let messages = handle_command(Command::Help(Some("part".to_string())), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 704 asker part :PART is disabled here.");
+    assert!(handle_command(Command::Help(Some("join".to_string())), 1, &shared_state).await.unwrap()[0].1.contains(" 704 "));
+
+    let mut config = Config::default();
+    config.server.help_dir = Some(dir);
+    assert!(config.load_help().unwrap_err().to_string().contains("server.help_dir"));
+}
+
+#[tokio::test]
+async fn test_auth_blocks_assign_classes() {
+    let config: Config = toml::from_str(r#"
+        [[class]]
+        name = "default"
+        max_channels = 1
+
+        [[class]]
+        name = "bots"
+        max_clients = 1
+        max_channels = 5
+
+        [[auth]]
+        masks = ["bot*@10.0.0.0/8"]
+        class = "bots"
+        spoof = "bots.example.org"
+        exempt = ["kline"]
+
+        [[privset]]
+        name = "stats"
+        privileges = ["stats"]
+
+        [[oper]]
+        name = "admin"
+        password_hash = "$argon2id$v=19$m=19456,t=2,p=1$nNzvFRC1RKLHR1m7x4vUPg$lOAndSSc/YzlOA1GJqaL1sIVKM9W7ZBTwAIxgeaJcC8"
+        privset = "stats"
+    "#).unwrap();
+    let shared_state = registered_state(config);
+    shared_state.bans.lock().unwrap().add(Ban::new(BanKind::UserHost, "*@10.0.0.*", "No clients", "oper", None).unwrap());
+    let register = |id: usize, nick: &str, ip: &str| {
+        let mut user = User::new(id, ip.parse().unwrap());
+        user.set_nickname(nick.to_string()).unwrap();
+        shared_state.users.lock().unwrap().insert(id, user);
+        Command::User(nick.to_string(), "0".to_string(), "Real Name".to_string())
+    };
+
+    // The auth block gets past the K-line and spoofs the host
+    let messages = handle_command(register(2, "bot1", "10.0.0.5"), 2, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 001 bot1 "), "Unexpected response: {:?}", messages);
+    {
+        let users = shared_state.users.lock().unwrap();
+        assert_eq!(users[&2].class, "bots");
+        assert_eq!(users[&2].mask(), "bot1!bot1@bots.example.org");
+    }
+
+    let messages = handle_command(register(3, "bot2", "10.0.0.6"), 3, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(3, "ERROR :Closing Link: 10.0.0.6 (No more connections allowed in your connection class)".to_string())]);
+    assert!(!shared_state.users.lock().unwrap().contains_key(&3));
+
+    let messages = handle_command(register(4, "human", "10.0.0.7"), 4, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 465 human :You are banned from this server- No clients");
+
+    // The default class allows a single channel
+    handle_command(Command::Join("#one".to_string()), 1, &shared_state).await.unwrap();
+    let messages = handle_command(Command::Join("#two".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 405 asker #two :You have joined too many channels".to_string())]);
+    let messages = handle_command(Command::Join("#two".to_string()), 2, &shared_state).await.unwrap();
+    assert!(messages[0].1.contains("JOIN"));
+
+    let messages = handle_command(Command::Stats("l".to_string()), 2, &shared_state).await.unwrap();
+    assert!(messages[0].1.ends_with(" bots"), "Unexpected response: {:?}", messages);
+    let messages = handle_command(Command::Stats("i".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(1, ":server 481 asker :Permission Denied- You're not an IRC operator".to_string())]);
+    shared_state.users.lock().unwrap().get_mut(&1).unwrap().oper = Some("admin".to_string());
+    let messages = handle_command(Command::Stats("i".to_string()), 1, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 215 asker I bot*@10.0.0.0/8 * bots.example.org 0 bots");
+}
+
+#[tokio::test]
+async fn test_handle_pass_command() {
+    let config: Config = toml::from_str(r#"
+        [[listen]]
+        address = "0.0.0.0:6697"
+        # "team"
+        password_hash = "$argon2id$v=19$m=19456,t=2,p=1$+swnMdAtwjXJnbIaGc/3FA$fkNc97BN7ZZmN7CIyQyX9viLPFHUx2tcOfAI62TPIpg"
+
+        [[auth]]
+        masks = ["bot@*"]
+        # "botpass"
+        password_hash = "$argon2id$v=19$m=19456,t=2,p=1$J7GN008I+lrHdCZQUdbICQ$eJHKvlcnUWfdrAhdH9EPLWH8RmZ7DttHFyM92PVTNNs"
+    "#).unwrap();
+    let shared_state = registered_state(config);
+    let register = |id: usize, username: &str, password: Option<&str>, listener: Option<&str>| {
+        let mut user = User::new(id, "192.0.2.1".parse().unwrap());
+        user.set_nickname(format!("user{}", id)).unwrap();
+        user.password = password.map(str::to_string);
+        user.listener = listener.map(str::to_string);
+        shared_state.users.lock().unwrap().insert(id, user);
+        Command::User(username.to_string(), "0".to_string(), "Real Name".to_string())
+    };
+
+    let messages = handle_command(register(2, "guest", None, Some("0.0.0.0:6697")), 2, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![
+        (2, ":server 464 user2 :Password incorrect".to_string()),
+        (2, "ERROR :Closing Link: 192.0.2.1 (Bad Password)".to_string()),
+    ]);
+    assert!(!shared_state.users.lock().unwrap().contains_key(&2));
+
+    let messages = handle_command(register(3, "guest", Some("team"), Some("0.0.0.0:6697")), 3, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 001 user3 "));
+    assert_eq!(shared_state.users.lock().unwrap()[&3].password, None);
+
+    // The auth block's password replaces the server password
+    let messages = handle_command(register(4, "bot", Some("team"), Some("0.0.0.0:6697")), 4, &shared_state).await.unwrap();
+    assert_eq!(messages[0].1, ":server 464 user4 :Password incorrect");
+    let messages = handle_command(register(5, "bot", Some("botpass"), Some("0.0.0.0:6697")), 5, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 001 user5 "));
+
+    // Other listeners need no password
+    let messages = handle_command(register(6, "guest", None, None), 6, &shared_state).await.unwrap();
+    assert!(messages[0].1.starts_with(":server 001 user6 "));
+
+    let messages = handle_command(Command::Pass("team".to_string()), 6, &shared_state).await.unwrap();
+    assert_eq!(messages, vec![(6, ":server 462 user6 :You may not reregister".to_string())]);
+}
@@ -0,0 +1,248 @@
+use crate::config::{Config, ConfigError, ConfigHandle, ListenAddress, Overrides};
+use crate::utils::check_password;
+use log::LevelFilter;
+
+fn parse(contents: &str) -> Config {
+    toml::from_str(contents).unwrap()
+}
+
+fn validation_problems(config: &Config) -> Vec<String> {
+    match config.validate() {
+        Err(ConfigError::Invalid(problems)) => problems,
+        other => panic!("Expected validation problems, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_example_config_is_valid() {
+    let config = Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/doc/rustirc2.example.toml")).unwrap();
+    assert_eq!(config.server.name, "irc.example.org");
+    assert_eq!(config.listeners.len(), 1);
+    assert_eq!(config.classes.len(), 3);
+    assert_eq!(config.auth_blocks[0].class, "trusted");
+    assert_eq!(config.log_level(), LevelFilter::Info);
+
+    let oper = &config.opers[0];
+    assert!(check_password("secret", &oper.password_hash));
+    assert_eq!(oper.class.as_deref(), Some("opers"));
+}
+
+#[test]
+fn test_config_defaults() {
+    let config = parse("[server]\nname = \"irc.test\"\n");
+    assert!(config.validate().is_ok());
+    assert_eq!(config.server.name, "irc.test");
+    assert_eq!(config.listeners[0].address, "0.0.0.0:6667");
+    assert_eq!(config.limits.nick_length, 20);
+    assert_eq!(config.retention().max_messages, 1000);
+
+    let config = parse("[history]\nmax_age_days = 0\n");
+    assert_eq!(config.retention().max_age, None);
+
+    assert_eq!(ListenAddress::parse("[::1]:6667"), Some(ListenAddress::Tcp("[::1]:6667".parse().unwrap())));
+    assert_eq!(ListenAddress::parse("unix:/run/irc.sock"), Some(ListenAddress::Unix("/run/irc.sock".into())));
+    assert_eq!(ListenAddress::parse("systemd:1"), Some(ListenAddress::Systemd(1)));
+    assert_eq!(ListenAddress::parse("unix:"), None);
+    assert_eq!(ListenAddress::parse("systemd:first"), None);
+}
+
+#[test]
+fn test_config_rejects_unknown_fields() {
+    let error = toml::from_str::<Config>("[limits]\nnick_lenght = 30\n").unwrap_err();
+    assert!(error.to_string().contains("nick_lenght"), "Unexpected error: {}", error);
+
+    let path = std::env::temp_dir().join(format!("rustirc2-config-{}.toml", crate::utils::generate_client_id()));
+    std::fs::write(&path, "[server\nname = 1\n").unwrap();
+    let error = Config::load(&path).unwrap_err();
+    assert!(matches!(error, ConfigError::Parse(..)));
+    assert!(error.to_string().starts_with(&format!("Cannot parse {}", path.display())));
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(Config::load("/nonexistent/rustirc2.toml"), Err(ConfigError::Io(..))));
+}
+
+#[test]
+fn test_config_validation_reports_every_problem() {
+    let config = parse(r#"
+        [server]
+        name = "bad name"
+
+        [[listen]]
+        address = "127.0.0.1:6667"
+
+        [[listen]]
+        address = "127.0.0.1:6667"
+
+        [[listen]]
+        address = "localhost"
+        allow = ["nowhere"]
+        client_host = "bad host"
+        proxy = true
+        trusted_proxies = ["balancer"]
+
+        [[listen]]
+        address = "127.0.0.1:6697"
+        password_hash = "c5310e3d1e5823ef77ce3a5804988a72fc60c307a0c8959f3461e202e4a8d814"
+        proxy = true
+        origins = ["https://example.org"]
+        tls = { cert = "/nonexistent/cert.pem", key = "/nonexistent/key.pem" }
+
+        [limits]
+        nick_length = 0
+        line_length = 100
+        encoding_fallback = "ebcdic"
+        flood_exempt = ["10.0.0.0/8", "example.org"]
+        max_clients = 0
+
+        [[class]]
+        name = "default"
+        flood_rate = 0.0
+        sendq = 100
+
+        [[oper]]
+        name = "admin"
+        password_hash = "not-a-hash"
+        privset = "missing"
+        class = "missing"
+
+        [[privset]]
+        name = "helpers"
+        privileges = ["stats", "fly"]
+
+        [[auth]]
+        masks = ["10.0.0.1"]
+        password_hash = "not-a-hash"
+        class = "missing"
+        exempt = ["everything"]
+
+        [history]
+        max_age_days = 1000000000000000
+
+        [dns]
+        timeout = 0
+
+        [cloak]
+        key = "short"
+
+        [logging]
+        level = "loud"
+    "#);
+
+    let problems = validation_problems(&config);
+    let expected = [
+        "server.name",
+        "127.0.0.1:6667 is configured twice",
+        "\"localhost\" must be IP:PORT, unix:PATH or systemd:N",
+        "allow entry \"nowhere\"",
+        "client_host \"bad host\" must be a hostname",
+        "trusted_proxies entry \"balancer\"",
+        "127.0.0.1:6697 uses proxy but has no trusted_proxies",
+        "127.0.0.1:6697 has origins but is not a websocket listener",
+        "listen 127.0.0.1:6697 password_hash is not an Argon2 hash; make one with --hash-password",
+        "/nonexistent/cert.pem",
+        "/nonexistent/key.pem",
+        "limits.nick_length",
+        "limits.line_length must be at least 512",
+        "limits.encoding_fallback \"ebcdic\" must be one of latin1, replace",
+        "flood_exempt entry \"example.org\"",
+        "class \"default\" flood_rate must be at least 0.01",
+        "class \"default\" sendq must be at least 1024 bytes",
+        "limits.max_clients",
+        "oper \"admin\" password_hash is not an Argon2 hash",
+        "undefined privset \"missing\"",
+        "unknown privilege \"fly\"",
+        "undefined class \"missing\"",
+        "auth block 1 mask \"10.0.0.1\" must be user@host",
+        "auth block 1 uses undefined class \"missing\"",
+        "unknown exemption \"everything\"",
+        "auth block 1 password_hash is not an Argon2 hash",
+        "history.max_age_days must be between 0 and 36500",
+        "dns.timeout",
+        "cloak.key",
+        "logging.level",
+    ];
+    for expected in expected {
+        assert!(problems.iter().any(|p| p.contains(expected)), "Missing {:?} in {:?}", expected, problems);
+    }
+    assert_eq!(problems.len(), expected.len());
+
+    let config = parse("listen = []\n");
+    assert_eq!(validation_problems(&config), vec!["at least one [[listen]] block is required".to_string()]);
+}
+
+#[test]
+fn test_overrides_take_precedence_over_the_file() {
+    let mut config = parse(r#"
+        [[listen]]
+        address = "10.0.0.1:6667"
+
+        [[listen]]
+        address = "10.0.0.1:6697"
+        tls = { cert = "old.pem", key = "old.key" }
+
+        [logging]
+        level = "warn"
+    "#);
+    let overrides = Overrides {
+        port: Some("7000".to_string()),
+        tls: Some(("7001".to_string(), "new.pem".into(), "new.key".into())),
+        log_level: Some("debug".to_string()),
+        history_limit: Some(5),
+        ..Default::default()
+    };
+    overrides.apply(&mut config);
+
+    let listeners: Vec<(&str, Option<&str>)> = config.listeners.iter()
+        .map(|l| (l.address.as_str(), l.tls.as_ref().and_then(|tls| tls.cert.to_str())))
+        .collect();
+    assert_eq!(listeners, vec![("10.0.0.1:7000", None), ("10.0.0.1:7001", Some("new.pem"))]);
+    assert_eq!(config.log_level(), LevelFilter::Debug);
+    assert_eq!(config.history.limit, 5);
+}
+
+#[tokio::test]
+async fn test_config_handle_reload() {
+    let path = std::env::temp_dir().join(format!("rustirc2-reload-{}.toml", crate::utils::generate_client_id()));
+    std::fs::write(&path, "[server]\nname = \"one.test\"\n").unwrap();
+    let overrides = Overrides { port: Some("7000".to_string()), ..Default::default() };
+    let handle = ConfigHandle::open(Some(path.clone()), overrides).unwrap();
+    let mut updates = handle.subscribe();
+    assert_eq!(handle.get().server.name, "one.test");
+
+    std::fs::write(&path, "[server]\nname = \"two.test\"\n").unwrap();
+    handle.reload().unwrap();
+    assert!(updates.has_changed().unwrap());
+    let config = updates.borrow_and_update().clone();
+    assert_eq!(config.server.name, "two.test");
+    // Overrides are applied again
+    assert_eq!(config.listeners[0].address, "0.0.0.0:7000");
+
+    std::fs::write(&path, "[server]\nname = \"bad name\"\n").unwrap();
+    assert!(matches!(handle.reload(), Err(ConfigError::Invalid(_))));
+    assert!(!updates.has_changed().unwrap());
+    assert_eq!(handle.get().server.name, "two.test");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_motd_is_read_with_the_config() {
+    let dir = std::env::temp_dir().join(format!("rustirc2-motd-{}", crate::utils::generate_client_id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (config_path, motd_path) = (dir.join("config.toml"), dir.join("motd.txt"));
+    std::fs::write(&config_path, format!("[server]\nmotd = {:?}\n", motd_path.display().to_string())).unwrap();
+
+    let error = Config::load(&config_path).unwrap_err();
+    assert!(error.to_string().contains("server.motd"), "Unexpected error: {}", error);
+
+    std::fs::write(&motd_path, "First\nSecond\n").unwrap();
+    let handle = ConfigHandle::open(Some(config_path), Overrides::default()).unwrap();
+    assert_eq!(handle.get().motd.as_ref().unwrap().lines, vec!["First", "Second"]);
+
+    // Rehashing picks up an edited MOTD
+    std::fs::write(&motd_path, "Changed\n").unwrap();
+    handle.reload().unwrap();
+    assert_eq!(handle.get().motd.as_ref().unwrap().lines, vec!["Changed"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -3,3 +3,4 @@ mod model_tests;
 mod command_tests;
 mod server_tests;
 mod client_tests;
+mod config_tests;
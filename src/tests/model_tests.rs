@@ -1,13 +1,19 @@
 
-use crate::utils::generate_client_id;
+use crate::utils::{check_password, cloak_host, generate_client_id, hash_password, is_password_hash, wildcard_match};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use std::net::IpAddr;
 use std::str::FromStr;
 use crate::models::user::{User, UserStatus};
 use crate::models::channel::Channel;
-use crate::models::message::{Message, Recipient};
-use chrono::Utc;
+use crate::models::message::{format_tags, Message, Recipient};
+use crate::models::account::{parse_plain_payload, AccountStore, FileAccountStore, SaslSession};
+use crate::models::history::{
+    channel_buffer, select_history, FileHistoryStore, HistoryEntry, HistoryQuery, HistoryRef, HistoryStore, Retention,
+};
+use crate::models::ban::{Ban, BanKind, BanList};
+use crate::models::motd::{wrap_line, Motd, MOTD_WIDTH};
+use chrono::{Duration, Utc};
 
 #[test]
 fn test_generate_client_id_uniqueness() {
@@ -16,6 +22,16 @@ fn test_generate_client_id_uniqueness() {
     assert_ne!(id1, id2, "Generated IDs should be unique");
 }
 
+#[test]
+fn test_wildcard_match() {
+    assert!(wildcard_match("*@127.0.0.1", "alice@127.0.0.1"));
+    assert!(wildcard_match("*@*", "@"));
+    assert!(wildcard_match("a?ice@*.EXAMPLE.org", "Alice@host.example.org"));
+    assert!(wildcard_match("*a*b*", "xxaxxbxxb"));
+    assert!(!wildcard_match("*@127.0.0.1", "alice@127.0.0.10"));
+    assert!(!wildcard_match("a?ice@*", "aice@host"));
+}
+
 #[test]
 fn test_generate_client_id_thread_safety() {
     let thread_count = 100;
@@ -117,3 +133,216 @@ fn test_message_creation() {
     assert!(user_message.timestamp <= Utc::now());
     assert!(channel_message.timestamp <= Utc::now());
 }
+
+#[test]
+fn test_file_account_store_roundtrip() {
+    let path = std::env::temp_dir().join(format!("rustirc2-accounts-{}", generate_client_id()));
+    let mut store = FileAccountStore::load(&path).unwrap();
+    store.register("Alice", "s3cret");
+    store.add_certfp("alice", "AA:BB:CC").unwrap();
+    store.save().unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(!contents.contains("s3cret"), "Passwords must not be stored in clear text");
+
+    let store = FileAccountStore::load(&path).unwrap();
+    assert!(store.verify_password("alice", "s3cret"));
+    assert!(!store.verify_password("alice", "wrong"));
+    assert!(!store.verify_password("nobody", "s3cret"));
+    assert_eq!(store.account_for_certfp("aabbcc"), Some("Alice".to_string()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_password_hashes() {
+    let hash = hash_password("s3cret");
+    assert!(hash.starts_with("$argon2id$"), "Unexpected hash: {}", hash);
+    assert_ne!(hash, hash_password("s3cret"), "Each hash should get its own salt");
+    assert!(is_password_hash(&hash));
+    assert!(check_password("s3cret", &hash));
+    assert!(!check_password("wrong", &hash));
+
+    // Unsalted or fast hashes from before are refused rather than trusted
+    let sha256 = "c5310e3d1e5823ef77ce3a5804988a72fc60c307a0c8959f3461e202e4a8d814";
+    assert!(!is_password_hash(sha256));
+    assert!(!check_password("secret", sha256));
+    assert!(!is_password_hash("$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ"));
+}
+
+#[test]
+fn test_sasl_session_chunking() {
+    let mut session = SaslSession::new("PLAIN".to_string());
+    assert_eq!(session.push_chunk(&"a".repeat(400)), Ok(None));
+    assert_eq!(session.push_chunk("+"), Ok(Some("a".repeat(400))));
+    assert_eq!(session.push_chunk("abc"), Ok(Some("abc".to_string())));
+    assert!(session.push_chunk(&"a".repeat(401)).is_err());
+
+    assert_eq!(
+        parse_plain_payload(b"\0alice\0pw"),
+        Some(("".to_string(), "alice".to_string(), "pw".to_string()))
+    );
+    assert_eq!(parse_plain_payload(b"alice"), None);
+}
+
+#[test]
+fn test_message_tags_rendering() {
+    let message = Message::new(1, Recipient::Channel("#test".to_string()), "Hello all".to_string());
+    let other = Message::new(1, Recipient::Channel("#test".to_string()), "Hello again".to_string());
+    assert_ne!(message.msgid, other.msgid, "Message ids should be unique");
+
+    assert_eq!(message.to_line("nick", "#test", &[]), ":nick PRIVMSG #test :Hello all");
+    assert_eq!(
+        message.to_line("nick", "#test", &[("account", "a b;c\\".to_string())]),
+        "@account=a\\sb\\:c\\\\ :nick PRIVMSG #test :Hello all"
+    );
+    assert!(message.server_time().ends_with('Z'));
+    assert_eq!(format_tags(&[]), "");
+}
+
+fn history_entry(content: &str, minutes_ago: i64) -> HistoryEntry {
+    let mut message = Message::new(1, Recipient::Channel("#test".to_string()), content.to_string());
+    message.timestamp = Utc::now() - Duration::minutes(minutes_ago);
+    HistoryEntry::from_message(&message, "nick", None, "#test")
+}
+
+#[test]
+fn test_history_selection() {
+    let entries: Vec<HistoryEntry> = (0..10).map(|i| history_entry(&format!("m{}", i), 10 - i)).collect();
+    let contents = |selected: Vec<HistoryEntry>| selected.into_iter().map(|e| e.content).collect::<Vec<_>>();
+
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Latest(HistoryRef::Any), 2)), vec!["m8", "m9"]);
+    let m5 = HistoryRef::MsgId(entries[5].msgid.clone());
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Before(m5.clone()), 2)), vec!["m3", "m4"]);
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::After(m5.clone()), 2)), vec!["m6", "m7"]);
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Around(m5.clone()), 3)), vec!["m4", "m5", "m6"]);
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Latest(m5.clone()), 2)), vec!["m8", "m9"]);
+
+    let m2 = HistoryRef::MsgId(entries[2].msgid.clone());
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Between(m2.clone(), m5.clone()), 10)), vec!["m3", "m4"]);
+    assert_eq!(contents(select_history(&entries, &HistoryQuery::Between(m5, m2), 1)), vec!["m4"]);
+
+    let unknown = HistoryRef::MsgId("unknown".to_string());
+    assert!(select_history(&entries, &HistoryQuery::Before(unknown), 10).is_empty());
+}
+
+#[test]
+fn test_file_history_store_persists_and_applies_retention() {
+    let path = std::env::temp_dir().join(format!("rustirc2-history-{}", generate_client_id()));
+    let retention = Retention { max_messages: 3, max_age: Some(Duration::hours(1)) };
+
+    let store = FileHistoryStore::open(&path, retention).unwrap();
+    store.append(&channel_buffer("#test"), history_entry("too old", 120));
+    for i in 0..4 {
+        store.append(&channel_buffer("#Test"), history_entry(&format!("tab\tand\nnewline {}", i), 4 - i));
+    }
+    assert_eq!(store.entries("#test").len(), 3);
+    drop(store);
+
+    let store = FileHistoryStore::open(&path, retention).unwrap();
+    let entries = store.entries("#test");
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].content, "tab\tand\nnewline 1");
+    assert_eq!(store.buffers().len(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_motd_line_wrapping() {
+    assert_eq!(wrap_line("", MOTD_WIDTH), vec![String::new()]);
+    assert_eq!(wrap_line("short line", MOTD_WIDTH), vec!["short line".to_string()]);
+
+    let long = format!("{} {}", "a".repeat(50), "b".repeat(50));
+    assert_eq!(wrap_line(&long, MOTD_WIDTH), vec!["a".repeat(50), "b".repeat(50)]);
+
+    let word = "é".repeat(170);
+    assert_eq!(wrap_line(&format!("{} end", word), MOTD_WIDTH), vec!["é".repeat(80), "é".repeat(80), format!("{} end", "é".repeat(10))]);
+
+    let motd = Motd::from_text("Welcome!\r\n\nRules:   \n");
+    assert_eq!(motd.lines, vec!["Welcome!", "", "Rules:"]);
+}
+
+#[test]
+fn test_ban_matching() {
+    let dline = Ban::new(BanKind::Ip, "192.0.2.0/25", "", "oper", None).unwrap();
+    assert!(dline.matches_ip(IpAddr::from_str("192.0.2.127").unwrap()));
+    assert!(!dline.matches_ip(IpAddr::from_str("192.0.2.128").unwrap()));
+    assert!(!dline.matches_ip(IpAddr::from_str("2001:db8::1").unwrap()));
+    let dline = Ban::new(BanKind::Ip, "2001:db8::/32", "", "oper", None).unwrap();
+    assert!(dline.matches_ip(IpAddr::from_str("2001:db8:ffff::1").unwrap()));
+    let dline = Ban::new(BanKind::Ip, "10.1.*", "", "oper", None).unwrap();
+    assert!(dline.matches_ip(IpAddr::from_str("10.1.2.3").unwrap()));
+    assert!(Ban::new(BanKind::Ip, "10.0.0.0/33", "", "oper", None).is_err());
+
+    let kline = Ban::new(BanKind::UserHost, "10.0.0.1", "", "oper", None).unwrap();
+    assert_eq!(kline.mask, "*@10.0.0.1");
+    assert!(kline.matches_user("anyone", IpAddr::from_str("10.0.0.1").unwrap(), None));
+
+    // K-lines also match the resolved host name, and CIDR ranges
+    let kline = Ban::new(BanKind::UserHost, "*@*.example.org", "", "oper", None).unwrap();
+    assert!(kline.matches_user("anyone", IpAddr::from_str("10.0.0.1").unwrap(), Some("dsl-1.example.org")));
+    assert!(!kline.matches_user("anyone", IpAddr::from_str("10.0.0.1").unwrap(), None));
+    let kline = Ban::new(BanKind::UserHost, "bob@10.0.0.0/8", "", "oper", None).unwrap();
+    assert!(kline.matches_user("bob", IpAddr::from_str("10.1.2.3").unwrap(), Some("host.example.org")));
+    assert!(!kline.matches_ip(IpAddr::from_str("10.0.0.1").unwrap()));
+}
+
+#[test]
+fn test_host_cloaking() {
+    let key = "0123456789abcdef";
+    let ip = IpAddr::from_str("192.0.2.10").unwrap();
+    let cloak = cloak_host(key, ip, None);
+    let parts: Vec<&str> = cloak.split('.').collect();
+    assert_eq!(parts.len(), 4);
+    assert_eq!(parts[3], "IP");
+    assert!(parts[..3].iter().all(|part| part.len() == 8));
+    assert_eq!(cloak, cloak_host(key, ip, None));
+    assert_ne!(cloak, cloak_host("fedcba9876543210", ip, None));
+
+    // Neighbours share the outer parts of the cloak, so bans can cover a range
+    let neighbour = cloak_host(key, IpAddr::from_str("192.0.2.11").unwrap(), None);
+    assert_ne!(neighbour, cloak);
+    assert_eq!(neighbour.split_once('.').unwrap().1, cloak.split_once('.').unwrap().1);
+    assert!(cloak_host(key, IpAddr::from_str("2001:db8::1").unwrap(), None).ends_with(".IP"));
+
+    let cloak = cloak_host(key, ip, Some("dsl-10.example.org"));
+    assert!(cloak.ends_with(".example.org"));
+    assert!(!cloak.contains("dsl-10"));
+
+    let mut user = User::new(1, ip);
+    user.hostname = Some("dsl-10.example.org".to_string());
+    user.cloaked_host = Some(cloak.clone());
+    assert_eq!(user.display_host(), "dsl-10.example.org");
+    user.cloaked = true;
+    assert_eq!(user.display_host(), cloak);
+    assert!(user.matches_host_mask("*@*.example.org"));
+    user.vhost = Some("staff.example.org".to_string());
+    assert_eq!(user.display_host(), "staff.example.org");
+}
+
+#[tokio::test]
+async fn test_ban_list_persistence_and_expiry() {
+    let path = std::env::temp_dir().join(format!("rustirc2-bans-{}.txt", generate_client_id()));
+    let mut bans = BanList::load(&path).unwrap();
+    bans.add(Ban::new(BanKind::Nick, "Chan*", "Reserved\tfor services", "oper", None).unwrap()).wait().await.unwrap();
+    bans.add(Ban::new(BanKind::UserHost, "*@192.0.2.1", "Temporary", "oper", Some(Duration::minutes(5))).unwrap()).wait().await.unwrap();
+    let mut expired = Ban::new(BanKind::Ip, "192.0.2.2", "Old", "oper", None).unwrap();
+    expired.expires = Some(Utc::now() - Duration::minutes(1));
+    let line = format!("D\t192.0.2.2\t{}\t{}\toper\tOld\n", expired.set_at.timestamp(), expired.expires.unwrap().timestamp());
+    std::fs::write(&path, std::fs::read_to_string(&path).unwrap() + &line).unwrap();
+
+    // Lookups skip the expired ban without rewriting the file
+    let mut reloaded = BanList::load(&path).unwrap();
+    assert_eq!(reloaded.find_nick("chanserv").unwrap().reason, "Reserved for services");
+    assert!(reloaded.find_user("bob", IpAddr::from_str("192.0.2.1").unwrap(), None).unwrap().expires.is_some());
+    assert_eq!(reloaded.find_ip(IpAddr::from_str("192.0.2.2").unwrap()), None);
+    assert_eq!(reloaded.active().len(), 2);
+    assert!(std::fs::read_to_string(&path).unwrap().ends_with(&line));
+
+    // The next write leaves it out
+    reloaded.remove(BanKind::UserHost, "192.0.2.1").unwrap().wait().await.unwrap();
+    assert!(reloaded.remove(BanKind::UserHost, "192.0.2.1").is_none());
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    assert_eq!(BanList::load(&path).unwrap().active().len(), 1);
+
+    std::fs::remove_file(&path).unwrap();
+}
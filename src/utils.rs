@@ -1,8 +1,194 @@
-
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use rand::Rng;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 static CLIENT_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
 pub fn generate_client_id() -> usize {
     CLIENT_ID_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
+
+/// Generates a unique identifier for the `msgid` message tag.
+pub fn generate_msgid() -> String {
+    let bytes: [u8; 12] = rand::thread_rng().gen();
+    to_hex(&bytes)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes a password with Argon2id and a random salt. The result is a PHC string such
+/// as `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`, which records the algorithm and
+/// cost parameters along with the salt, so they can be raised without breaking old hashes.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .expect("the default Argon2 parameters are valid")
+        .to_string()
+}
+
+/// Folds a nickname or channel name for comparison under CASEMAPPING=ascii, as
+/// advertised in ISUPPORT: only A-Z and a-z are equivalent.
+pub fn casefold(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Checks `password` against a hash made by `hash_password`, as stored in the config and
+/// the account file, using the parameters the hash records.
+pub fn check_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `hash` is an Argon2 PHC string with valid parameters, a salt and a hash.
+pub fn is_password_hash(hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => argon2::Algorithm::try_from(hash.algorithm).is_ok()
+            && Params::try_from(&hash).is_ok()
+            && hash.salt.is_some()
+            && hash.hash.is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Matches `text` against an IRC mask where `*` matches any run of characters and `?`
+/// matches exactly one, ignoring ASCII case.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+    let text: Vec<char> = text.to_ascii_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `mask` can match an IP address: an address, a CIDR range or a wildcard
+/// made of address characters.
+pub fn is_ip_mask(mask: &str) -> bool {
+    match mask.split_once('/') {
+        Some(_) => cidr_contains(mask, IpAddr::from([0, 0, 0, 0])).is_some(),
+        None => mask.chars().all(|c| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '*' | '?')),
+    }
+}
+
+/// Matches a `user@host` mask against a client's real address. The host part is an
+/// address, CIDR range or wildcard matched against `ip`, or a wildcard matched against
+/// the resolved `hostname`.
+pub fn user_host_matches(mask: &str, username: &str, ip: IpAddr, hostname: Option<&str>) -> bool {
+    match mask.split_once('@') {
+        Some((user, host)) => {
+            wildcard_match(user, username)
+                && (ip_mask_matches(host, ip) || hostname.map(|hostname| wildcard_match(host, hostname)).unwrap_or(false))
+        }
+        None => false,
+    }
+}
+
+/// Whether `host` is usable as a host name: letters, digits, dots and dashes.
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+/// Whether `mask` can match a host: an IP mask or a host name with wildcards.
+pub fn is_host_mask(mask: &str) -> bool {
+    is_ip_mask(mask) || is_valid_host(&mask.replace(['*', '?'], "a"))
+}
+
+/// Cloaked host shown with user mode +x. A host name keeps its domain, as in
+/// `1a2b3c4d.example.org`; an address becomes three keyed hashes of the address and
+/// of ever larger networks around it, as in `1a2b3c4d.5e6f7a8b.9c0d1e2f.IP`, so bans on
+/// the outer parts still catch a whole range.
+pub fn cloak_host(key: &str, ip: IpAddr, hostname: Option<&str>) -> String {
+    let hash = |data: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(data.as_bytes());
+        to_hex(&mac.finalize().into_bytes()[..4])
+    };
+    if let Some((hostname, (_, domain))) = hostname.and_then(|hostname| Some((hostname, hostname.split_once('.')?))) {
+        return format!("{}.{}", hash(hostname), domain);
+    }
+    let (network, wider) = match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            (format!("{}.{}.{}", octets[0], octets[1], octets[2]), format!("{}.{}", octets[0], octets[1]))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let prefix = |count: usize| segments[..count].iter().map(|segment| format!("{:x}", segment)).collect::<Vec<_>>().join(":");
+            (prefix(4), prefix(3))
+        }
+    };
+    format!("{}.{}.{}.IP", hash(&ip.to_string()), hash(&network), hash(&wider))
+}
+
+/// Matches `ip` against an address, CIDR range or wildcard mask.
+pub fn ip_mask_matches(mask: &str, ip: IpAddr) -> bool {
+    if mask.contains('/') {
+        return cidr_contains(mask, ip).unwrap_or(false);
+    }
+    match mask.parse::<IpAddr>() {
+        Ok(address) => address == ip,
+        Err(_) => wildcard_match(mask, &ip.to_string()),
+    }
+}
+
+/// Whether `a` and `b` fall in the same IPv4 `/v4_prefix` or IPv6 `/v6_prefix` block.
+pub fn same_network(a: IpAddr, b: IpAddr, v4_prefix: u8, v6_prefix: u8) -> bool {
+    let prefix = if a.is_ipv4() { v4_prefix } else { v6_prefix };
+    cidr_contains(&format!("{}/{}", a, prefix), b).unwrap_or(false)
+}
+
+/// First address of the IPv4 `/v4_prefix` or IPv6 `/v6_prefix` block holding `ip`.
+pub fn network(ip: IpAddr, v4_prefix: u8, v6_prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & u32::MAX.checked_shl(32 - u32::from(v4_prefix.min(32))).unwrap_or(0)).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & u128::MAX.checked_shl(128 - u32::from(v6_prefix.min(128))).unwrap_or(0)).into()),
+    }
+}
+
+/// Whether `ip` lies in `cidr` (such as `192.0.2.0/24`), or `None` if the range is malformed.
+fn cidr_contains(cidr: &str, ip: IpAddr) -> Option<bool> {
+    let (network, prefix) = cidr.split_once('/')?;
+    let network: IpAddr = network.parse().ok()?;
+    let prefix: u32 = prefix.parse().ok()?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    if prefix > bits {
+        return None;
+    }
+    let (network, ip) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip)),
+        _ => return Some(false),
+    };
+    let host_bits = bits - prefix;
+    Some(network.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0))
+}